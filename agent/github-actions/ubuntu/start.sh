@@ -0,0 +1,63 @@
+#!/bin/bash
+set -e
+
+if [ -z "${RUNNER_URL}" ]; then
+  echo 1>&2 "error: missing RUNNER_URL environment variable"
+  exit 1
+fi
+
+if [ -z "${RUNNER_TOKEN}" ]; then
+  echo 1>&2 "error: missing RUNNER_TOKEN environment variable"
+  exit 1
+fi
+
+RUNNER_TOKEN_FILE="/runner/.token"
+echo -n "${RUNNER_TOKEN}" > "${RUNNER_TOKEN_FILE}"
+unset RUNNER_TOKEN
+
+cleanup() {
+  trap "" EXIT
+
+  if [ -e ./config.sh ]; then
+    print_header "Cleanup. Removing GitHub Actions runner..."
+    ./config.sh remove --token $(cat "${RUNNER_TOKEN_FILE}") || true
+  fi
+}
+
+print_header() {
+  lightcyan="\033[1;36m"
+  nocolor="\033[0m"
+  echo -e "\n${lightcyan}$1${nocolor}\n"
+}
+
+print_header "1. Determining latest GitHub Actions runner..."
+
+RUNNER_VERSION=$(curl -LsS https://api.github.com/repos/actions/runner/releases/latest | jq -r ".tag_name" | sed 's/^v//')
+
+if [ -z "${RUNNER_VERSION}" -o "${RUNNER_VERSION}" == "null" ]; then
+  echo 1>&2 "error: could not determine the latest GitHub Actions runner"
+  exit 1
+fi
+
+print_header "2. Downloading and extracting GitHub Actions runner ${RUNNER_VERSION}..."
+
+curl -LsS "https://github.com/actions/runner/releases/download/v${RUNNER_VERSION}/actions-runner-linux-${TARGETARCH}-${RUNNER_VERSION}.tar.gz" | tar -xz & wait $!
+
+trap "cleanup; exit 0" EXIT
+trap "cleanup; exit 130" INT
+trap "cleanup; exit 143" TERM
+
+print_header "3. Configuring GitHub Actions runner..."
+
+./config.sh --unattended \
+  --url "${RUNNER_URL}" \
+  --token $(cat "${RUNNER_TOKEN_FILE}") \
+  --name "${RUNNER_NAME:-$(hostname)}" \
+  --labels "${RUNNER_LABELS}" \
+  --runnergroup "${RUNNER_GROUP:-Default}" \
+  --work "${RUNNER_WORK:-_work}" \
+  --replace & wait $!
+
+print_header "4. Running GitHub Actions runner..."
+
+./run.sh "$@" & wait $!
@@ -0,0 +1,275 @@
+use k8s_openapi::api::apps::v1::Deployment;
+use k8s_openapi::chrono::{DateTime, Duration, SecondsFormat, Utc};
+use reqwest::Client;
+use serde::Deserialize;
+use tracing::*;
+
+use crate::crd::{AutoscalingSpec, AutoscalingStatus, CDBootstrap};
+use crate::error::Error;
+
+/// Azure DevOps REST API version used for the distributed task endpoints.
+const API_VERSION: &str = "7.0";
+
+/// Cooldown applied when `cooldownSeconds` is not set.
+pub const DEFAULT_COOLDOWN_SECONDS: u64 = 300;
+
+/// Number of jobs of an agent pool, as reported by the Azure DevOps job request API.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JobCounts {
+    pub queued: u32,
+    pub running: u32,
+}
+
+/// Minimal client of the Azure DevOps distributed task API, authenticating with the personal
+/// access token of the agents. Failed requests are reported as `Error::JobRequests`.
+pub struct AzureDevOpsClient {
+    http: Client,
+    url: String,
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct List<T> {
+    value: Vec<T>,
+}
+
+#[derive(Deserialize)]
+struct Pool {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JobRequest {
+    assign_time: Option<String>,
+    finish_time: Option<String>,
+}
+
+impl AzureDevOpsClient {
+    /// # Arguments
+    /// - `url` - Azure DevOps organization URL, e.g. `https://dev.azure.com/<organization>`.
+    /// - `token` - Personal access token with the `Agent Pools (read)` scope.
+    pub fn new(url: &str, token: &str) -> Result<Self, Error> {
+        let http = Client::builder()
+            .user_agent("cdbootstrap-operator")
+            .build()
+            .map_err(request_error)?;
+        Ok(AzureDevOpsClient {
+            http,
+            url: url.trim_end_matches('/').to_owned(),
+            token: token.to_owned(),
+        })
+    }
+
+    async fn get<T: for<'de> Deserialize<'de>>(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<T, Error> {
+        let response = self
+            .http
+            .get(format!("{}/_apis/distributedtask/{}", self.url, path))
+            .query(query)
+            .query(&[("api-version", API_VERSION)])
+            .basic_auth("", Some(&self.token))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(request_error)?;
+        response.json().await.map_err(request_error)
+    }
+
+    /// Returns the ID of the agent pool with the given name.
+    pub async fn pool_id(&self, pool: &str) -> Result<u64, Error> {
+        let pools: List<Pool> = self.get("pools", &[("poolName", pool)]).await?;
+        pools
+            .value
+            .first()
+            .map(|pool| pool.id)
+            .ok_or_else(|| Error::JobRequests(format!("agent pool {} not found", pool)))
+    }
+
+    /// Counts the queued and running jobs of the agent pool. A job is queued until it is
+    /// assigned to an agent, and running until it finishes. Only unfinished jobs are requested,
+    /// the history of finished jobs is left on the server.
+    pub async fn job_counts(&self, pool_id: u64) -> Result<JobCounts, Error> {
+        let requests: List<JobRequest> = self
+            .get(
+                &format!("pools/{}/jobrequests", pool_id),
+                &[("completedRequestCount", "0")],
+            )
+            .await?;
+
+        let mut counts = JobCounts::default();
+        for request in requests.value.iter().filter(|r| r.finish_time.is_none()) {
+            match request.assign_time {
+                Some(_) => counts.running += 1,
+                None => counts.queued += 1,
+            }
+        }
+        Ok(counts)
+    }
+}
+
+fn request_error(error: reqwest::Error) -> Error {
+    Error::JobRequests(error.to_string())
+}
+
+/// Outcome of a single autoscaling pass.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScaleDecision {
+    /// Number of agents the Deployment should run.
+    pub replicas: i32,
+    /// True if `replicas` differs from the current number of agents.
+    pub scaled: bool,
+    /// True if scaling down is held back by the cooldown.
+    pub delayed: bool,
+    pub message: String,
+}
+
+/// Decides on the number of agents: one agent per queued or running job, bounded by
+/// `minReplicas` and `maxReplicas`. Scaling up happens right away, scaling down only once the
+/// cooldown since the last scale has passed, so agents are not removed between the jobs of a
+/// pipeline.
+///
+/// # Arguments
+/// - `current` - Number of agents the Deployment currently runs.
+/// - `counts` - Jobs of the agent pool.
+/// - `spec` - Autoscaling settings of the resource.
+/// - `last_scale_time` - Time of the previous scale, if any.
+/// - `now` - Current time.
+pub fn decide(
+    current: i32,
+    counts: &JobCounts,
+    spec: &AutoscalingSpec,
+    last_scale_time: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> ScaleDecision {
+    let jobs = (counts.queued + counts.running).min(i32::MAX as u32) as i32;
+    let desired = jobs.clamp(spec.min_replicas, spec.max_replicas.max(spec.min_replicas));
+    let cooldown =
+        Duration::seconds(spec.cooldown_seconds.unwrap_or(DEFAULT_COOLDOWN_SECONDS) as i64);
+    let jobs_message = format!(
+        "{} queued and {} running jobs",
+        counts.queued, counts.running
+    );
+
+    if desired > current {
+        ScaleDecision {
+            replicas: desired,
+            scaled: true,
+            delayed: false,
+            message: format!(
+                "Scaled up from {} to {}: {}",
+                current, desired, jobs_message
+            ),
+        }
+    } else if desired < current {
+        match last_scale_time.map(|last| last + cooldown) {
+            Some(until) if until > now => ScaleDecision {
+                replicas: current,
+                scaled: false,
+                delayed: true,
+                message: format!(
+                    "Scale down from {} to {} delayed by the cooldown until {}: {}",
+                    current,
+                    desired,
+                    until.to_rfc3339_opts(SecondsFormat::Secs, true),
+                    jobs_message
+                ),
+            },
+            _ => ScaleDecision {
+                replicas: desired,
+                scaled: true,
+                delayed: false,
+                message: format!(
+                    "Scaled down from {} to {}: {}",
+                    current, desired, jobs_message
+                ),
+            },
+        }
+    } else {
+        ScaleDecision {
+            replicas: current,
+            scaled: false,
+            delayed: false,
+            message: format!("Keeping {} agents: {}", current, jobs_message),
+        }
+    }
+}
+
+/// Returns the number of agents the Deployment of the resource should run. With autoscaling
+/// this is the last scale decision recorded in the status, otherwise `spec.agent.replicas`.
+pub fn replicas(cr: &CDBootstrap) -> i32 {
+    match &cr.spec.agent.autoscaling {
+        Some(autoscaling) => cr
+            .status
+            .as_ref()
+            .and_then(|status| status.autoscaling.as_ref())
+            .map(|status| status.desired_replicas)
+            .unwrap_or(autoscaling.min_replicas)
+            .clamp(
+                autoscaling.min_replicas,
+                autoscaling.max_replicas.max(autoscaling.min_replicas),
+            ),
+        None => cr.spec.agent.replicas,
+    }
+}
+
+/// Returns the number of agents the Deployment of the resource is applied with. With autoscaling
+/// the replicas of the live Deployment, as last scaled by the autoscaler, are kept within the
+/// bounds. The decision recorded in the status is written after the scale and may not have been
+/// observed yet, applying it would scale the agents back.
+pub fn applied_replicas(cr: &CDBootstrap, live: Option<&Deployment>) -> i32 {
+    let live = live
+        .and_then(|deployment| deployment.spec.as_ref())
+        .and_then(|spec| spec.replicas);
+    match (&cr.spec.agent.autoscaling, live) {
+        (Some(autoscaling), Some(live)) => live.clamp(
+            autoscaling.min_replicas,
+            autoscaling.max_replicas.max(autoscaling.min_replicas),
+        ),
+        _ => replicas(cr),
+    }
+}
+
+/// Polls the job requests of the agent pool and records the scale decision in `status`.
+/// Returns the decision, the caller scales the Deployment when `scaled` is set.
+///
+/// # Arguments
+/// - `client` - Client of the Azure DevOps organization.
+/// - `pool` - Name of the agent pool.
+/// - `spec` - Autoscaling settings of the resource.
+/// - `current` - Number of agents the Deployment currently runs.
+/// - `status` - Autoscaling status of the previous pass, updated in place.
+pub async fn poll(
+    client: &AzureDevOpsClient,
+    pool: &str,
+    spec: &AutoscalingSpec,
+    current: i32,
+    status: &mut AutoscalingStatus,
+) -> Result<ScaleDecision, Error> {
+    let pool_id = client.pool_id(pool).await?;
+    let counts = client.job_counts(pool_id).await?;
+
+    let now = Utc::now();
+    let last_scale_time = status
+        .last_scale_time
+        .as_deref()
+        .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+        .map(|time| time.with_timezone(&Utc));
+    let decision = decide(current, &counts, spec, last_scale_time, now);
+
+    if decision.scaled {
+        info!("Agent pool {}: {}", pool, decision.message);
+        status.last_scale_time = Some(now.to_rfc3339_opts(SecondsFormat::Secs, true));
+    }
+    if decision.scaled || decision.delayed {
+        status.last_decision = Some(decision.message.clone());
+    }
+    status.queued_jobs = counts.queued;
+    status.running_jobs = counts.running;
+    status.desired_replicas = decision.replicas;
+
+    Ok(decision)
+}
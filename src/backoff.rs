@@ -0,0 +1,133 @@
+use k8s_openapi::chrono::{self, DateTime, SecondsFormat, Utc};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::crd::{CDBootstrap, RetryStatus};
+
+/// Delay before the first retry of a failed reconciliation.
+const DEFAULT_BASE: Duration = Duration::from_secs(5);
+/// Longest delay between two retries.
+const DEFAULT_MAX: Duration = Duration::from_secs(300);
+
+/// The next retry of a failed reconciliation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Retry {
+    /// Number of consecutive failed reconciliations.
+    pub attempts: u32,
+    /// Delay before the next reconciliation.
+    pub delay: Duration,
+}
+
+impl Retry {
+    /// Returns the retry as recorded in the resource status.
+    pub fn status(&self) -> RetryStatus {
+        let next = Utc::now() + chrono::Duration::from_std(self.delay).unwrap_or_default();
+        RetryStatus {
+            attempts: self.attempts,
+            next_retry_time: Some(next.to_rfc3339_opts(SecondsFormat::Secs, true)),
+        }
+    }
+}
+
+/// Exponential backoff of the failed reconciliations of each `CDBootstrap` resource. The delay
+/// doubles with every consecutive failure up to a maximum, and is reset once the resource
+/// reconciles successfully. Half of the delay is random, so resources failing on the same
+/// cause, e.g. an Azure AD outage, do not retry in lockstep.
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    /// Retry of each failing resource, by `<namespace>/<name>`.
+    retries: Mutex<HashMap<String, Retry>>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(DEFAULT_BASE, DEFAULT_MAX)
+    }
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Backoff {
+            base,
+            max,
+            retries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the delay before retry `attempt`, starting at 1.
+    ///
+    /// # Arguments
+    /// - `attempt` - Number of consecutive failures.
+    /// - `jitter` - Random value in `[0, 1)`, selecting a delay between half and the full
+    ///   exponential delay.
+    pub fn delay(&self, attempt: u32, jitter: f64) -> Duration {
+        let exponential = self
+            .base
+            .checked_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .unwrap_or(self.max)
+            .min(self.max);
+        exponential.div_f64(2.0) + exponential.mul_f64(jitter.clamp(0.0, 1.0) / 2.0)
+    }
+
+    /// Records a failed reconciliation of the resource and returns its next retry.
+    pub fn failed(&self, key: &str) -> Retry {
+        let Ok(mut retries) = self.retries.lock() else {
+            return Retry {
+                attempts: 1,
+                delay: self.base,
+            };
+        };
+        let attempts = retries.get(key).map(|retry| retry.attempts).unwrap_or(0) + 1;
+        let retry = Retry {
+            attempts,
+            delay: self.delay(attempts, jitter()),
+        };
+        retries.insert(key.to_owned(), retry);
+        retry
+    }
+
+    /// Returns the delay before the next retry of the resource, the base delay if it has not
+    /// failed.
+    pub fn next_delay(&self, key: &str) -> Duration {
+        self.retries
+            .lock()
+            .ok()
+            .and_then(|retries| retries.get(key).map(|retry| retry.delay))
+            .unwrap_or(self.base)
+    }
+
+    /// Forgets the failures of the resource after a successful reconciliation.
+    pub fn reset(&self, key: &str) {
+        if let Ok(mut retries) = self.retries.lock() {
+            retries.remove(key);
+        }
+    }
+}
+
+/// Returns the time left until the next retry recorded in the status of a failing resource, or
+/// `None` if it is due. Events of the subresources, or of the status written by the failed
+/// reconciliation, would otherwise reconcile the resource again right away and skip the backoff.
+/// A change of the specification is reconciled right away, it may well fix the failure.
+pub fn pending(cr: &CDBootstrap, now: DateTime<Utc>) -> Option<Duration> {
+    let status = cr.status.as_ref()?;
+    if status.observed_generation != cr.metadata.generation {
+        return None;
+    }
+    let next_retry_time = status.retry.as_ref()?.next_retry_time.as_deref()?;
+    let next_retry_time = DateTime::parse_from_rfc3339(next_retry_time).ok()?;
+    (next_retry_time.with_timezone(&Utc) - now)
+        .to_std()
+        .ok()
+        .filter(|remaining| !remaining.is_zero())
+}
+
+/// Returns a random value in `[0, 1)`. The hasher of every `RandomState` is seeded differently,
+/// which is random enough to spread retries.
+fn jitter() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
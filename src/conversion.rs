@@ -0,0 +1,161 @@
+use kube::core::conversion::{ConversionRequest, ConversionResponse, ConversionReview};
+use kube::core::Status;
+use serde_json::Value;
+use std::net::SocketAddr;
+use tracing::*;
+use warp::Filter;
+
+use crate::crd::{self, v1beta1, AzureDevOpsSpec};
+
+pub const V1_API_VERSION: &str = "cndev.nl/v1";
+pub const V1BETA1_API_VERSION: &str = "cndev.nl/v1beta1";
+
+/// Annotation used to carry the full `v1` specification on a `v1beta1` object. Fields that do not
+/// exist in `v1beta1` would otherwise be lost when a `v1` object is read as `v1beta1` and
+/// written back, e.g. by `kubectl apply` with an old manifest.
+pub const V1_SPEC_ANNOTATION: &str = "cndev.nl/v1-spec";
+
+/// Converts a `v1beta1` specification to `v1`. Fields that only exist in `v1` are taken from
+/// `preserved`, the specification stored in the `V1_SPEC_ANNOTATION`, when present.
+pub fn to_v1(
+    spec: &v1beta1::CDBootstrapSpec,
+    preserved: Option<crd::CDBootstrapSpec>,
+) -> crd::CDBootstrapSpec {
+    let mut converted = preserved.unwrap_or_default();
+    // v1beta1 can only describe Azure Pipelines, keep the section absent for other providers
+    if converted.azure_devops.is_some() || !spec.url.is_empty() || !spec.pool.is_empty() {
+        converted.azure_devops = Some(AzureDevOpsSpec {
+            url: spec.url.clone(),
+            pool: spec.pool.clone(),
+        });
+    }
+    converted.vault.url = spec.keyvault.clone();
+    converted.vault.secret_name = spec.oid.clone();
+    converted.vault.client_id = spec.spn.clone();
+    converted.vault.tenant_id = spec.tenant.clone();
+    converted.agent.replicas = spec.replicas;
+    converted
+}
+
+/// Converts a `v1` specification to the flat `v1beta1` specification.
+pub fn to_v1beta1(spec: &crd::CDBootstrapSpec) -> v1beta1::CDBootstrapSpec {
+    let azure_devops = spec.azure_devops.clone().unwrap_or_default();
+    v1beta1::CDBootstrapSpec {
+        oid: spec.vault.secret_name.clone(),
+        replicas: spec.agent.replicas,
+        url: azure_devops.url,
+        pool: azure_devops.pool,
+        keyvault: spec.vault.url.clone(),
+        spn: spec.vault.client_id.clone(),
+        tenant: spec.vault.tenant_id.clone(),
+    }
+}
+
+/// Converts a single `CDBootstrap` object, as received in a `ConversionReview`, to the desired
+/// API version. Metadata and status are shared by both versions and are copied unchanged.
+pub fn convert(mut object: Value, desired_api_version: &str) -> Result<Value, String> {
+    let api_version = object
+        .get("apiVersion")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_owned();
+
+    if api_version == desired_api_version {
+        return Ok(object);
+    }
+
+    let spec = object.get("spec").cloned().unwrap_or(Value::Null);
+    let converted_spec = match (api_version.as_str(), desired_api_version) {
+        (V1BETA1_API_VERSION, V1_API_VERSION) => {
+            let old: v1beta1::CDBootstrapSpec =
+                serde_json::from_value(spec).map_err(|e| format!("invalid v1beta1 spec: {}", e))?;
+            let preserved = take_annotation(&mut object)
+                .and_then(|value| serde_json::from_str::<crd::CDBootstrapSpec>(&value).ok());
+            serde_json::to_value(to_v1(&old, preserved))
+        }
+        (V1_API_VERSION, V1BETA1_API_VERSION) => {
+            let new: crd::CDBootstrapSpec =
+                serde_json::from_value(spec).map_err(|e| format!("invalid v1 spec: {}", e))?;
+            let preserved = serde_json::to_string(&new).map_err(|e| e.to_string())?;
+            set_annotation(&mut object, preserved);
+            serde_json::to_value(to_v1beta1(&new))
+        }
+        (from, to) => return Err(format!("unsupported conversion from {} to {}", from, to)),
+    }
+    .map_err(|e| e.to_string())?;
+
+    object["spec"] = converted_spec;
+    object["apiVersion"] = Value::String(desired_api_version.to_owned());
+    Ok(object)
+}
+
+/// Handles a `ConversionReview` sent by the API server and returns the review with the response
+/// filled in. Either all objects are converted, or the whole review fails.
+pub fn review(review: ConversionReview) -> ConversionReview {
+    let request = match ConversionRequest::from_review(review) {
+        Ok(request) => request,
+        Err(err) => {
+            return ConversionResponse::invalid(Status::failure(&err.to_string(), "InvalidRequest"))
+                .into_review()
+        }
+    };
+
+    let desired_api_version = request.desired_api_version.clone();
+    let converted: Result<Vec<Value>, String> = request
+        .objects
+        .iter()
+        .cloned()
+        .map(|object| convert(object, &desired_api_version))
+        .collect();
+
+    let response = ConversionResponse::for_request(request);
+    match converted {
+        Ok(objects) => response.success(objects),
+        Err(message) => {
+            warn!("Conversion to {} failed: {}", desired_api_version, message);
+            response.failure(Status::failure(&message, "ConversionFailed"))
+        }
+    }
+    .into_review()
+}
+
+/// Serves the conversion webhook on `POST /convert` over TLS. The API server only calls
+/// webhooks over HTTPS, the certificate must be valid for the Service in the CRD `clientConfig`.
+///
+/// # Arguments:
+/// - `addr` - Address to listen on.
+/// - `cert_path` - Path to the PEM encoded TLS certificate.
+/// - `key_path` - Path to the PEM encoded TLS private key.
+pub async fn serve(addr: SocketAddr, cert_path: String, key_path: String) {
+    let routes = warp::path("convert")
+        .and(warp::post())
+        .and(warp::body::json())
+        .map(|body: ConversionReview| warp::reply::json(&review(body)));
+
+    info!("Serving the conversion webhook on {}", addr);
+    warp::serve(routes)
+        .tls()
+        .cert_path(cert_path)
+        .key_path(key_path)
+        .run(addr)
+        .await;
+}
+
+fn take_annotation(object: &mut Value) -> Option<String> {
+    object
+        .pointer_mut("/metadata/annotations")
+        .and_then(Value::as_object_mut)
+        .and_then(|annotations| annotations.remove(V1_SPEC_ANNOTATION))
+        .and_then(|value| value.as_str().map(String::from))
+}
+
+fn set_annotation(object: &mut Value, value: String) {
+    if !object["metadata"].is_object() {
+        object["metadata"] = Value::Object(Default::default());
+    }
+    let metadata = &mut object["metadata"];
+    if !metadata["annotations"].is_object() {
+        metadata["annotations"] = Value::Object(Default::default());
+    }
+    metadata["annotations"][V1_SPEC_ANNOTATION] = Value::String(value);
+}
@@ -1,38 +1,536 @@
 use garde::Validate;
+use k8s_openapi::api::core::v1::{Affinity, Toleration, TopologySpreadConstraint};
+use k8s_openapi::api::networking::v1::NetworkPolicyEgressRule;
 use kube::CustomResource;
 use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+use crate::quantity;
+
+pub mod v1beta1;
 
 /// Struct corresponding to the Specification (`spec`) part of the `CDBootstrap` resource, directly
-/// reflects context of the `cdbootstraps.example.com.yaml` file to be found in this repository.
+/// reflects context of the `cdbootstraps.cndev.nl.yaml` file to be found in this repository.
 /// The `CDBootstrap` struct will be generated by the `CustomResource` derive macro.
-#[derive(CustomResource, Serialize, Deserialize, Debug, Validate, Clone, JsonSchema)]
+///
+/// `v1` is the storage version. Resources created as `v1beta1` are converted by the conversion
+/// webhook served by the operator, see the `conversion` module.
+#[derive(
+    CustomResource, Serialize, Deserialize, Debug, Default, Validate, Clone, PartialEq, JsonSchema,
+)]
 #[kube(
     group = "cndev.nl",
-    version = "v1beta1",
+    version = "v1",
     kind = "CDBootstrap",
     plural = "cdbootstraps",
     namespaced
 )]
 #[kube(status = "CDBootstrapStatus")]
 pub struct CDBootstrapSpec {
+    /// CI system the agents register with. The matching provider section must be set.
+    #[serde(default)]
     #[garde(skip)]
-    pub oid: String,
+    pub provider: Provider,
+    /// Azure DevOps organization and agent pool the agents register with.
+    #[serde(rename = "azureDevOps", skip_serializing_if = "Option::is_none")]
+    #[garde(dive)]
+    pub azure_devops: Option<AzureDevOpsSpec>,
+    /// GitHub organization or repository the self-hosted runners register with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[garde(dive)]
+    pub github: Option<GitHubSpec>,
+    /// GitLab instance the runners register with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[garde(dive)]
+    pub gitlab: Option<GitLabSpec>,
+    /// Key Vault the agent credential is collected from.
+    #[garde(dive)]
+    pub vault: VaultSpec,
+    /// Agent Deployment settings.
+    #[garde(dive)]
+    pub agent: AgentSpec,
+    /// Egress NetworkPolicy of the agent pods, defaults to the endpoints of the provider.
+    #[serde(rename = "networkPolicy", skip_serializing_if = "Option::is_none")]
+    #[garde(dive)]
+    pub network_policy: Option<NetworkPolicySpec>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Validate, Clone, PartialEq, JsonSchema)]
+pub struct AzureDevOpsSpec {
+    /// Azure DevOps organization URL, e.g. `https://dev.azure.com/<organization>`.
+    #[garde(url)]
+    pub url: String,
+    /// Name of the Azure Pipelines agent pool.
+    #[garde(length(min = 1))]
+    pub pool: String,
+}
+
+/// CI system the agents of a `CDBootstrap` resource register with.
+#[derive(
+    Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, JsonSchema,
+)]
+pub enum Provider {
+    /// Azure Pipelines agents, configured by the `azureDevOps` section.
+    #[default]
+    AzurePipelines,
+    /// GitHub Actions self-hosted runners, configured by the `github` section.
+    GitHubActions,
+    /// GitLab runners, configured by the `gitlab` section.
+    GitLabRunner,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Validate, Clone, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GitHubSpec {
+    /// URL of the organization or repository, e.g. `https://github.com/<org>/<repo>`.
+    #[garde(url)]
+    pub url: String,
+    /// REST API endpoint, defaults to `https://api.github.com`. Set for GitHub Enterprise Server.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[garde(url)]
+    pub api_url: Option<String>,
+    /// Labels the runners are registered with, in addition to the default labels.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     #[garde(skip)]
-    pub replicas: i32,
+    pub labels: Vec<String>,
+    /// Runner group of the organization the runners are added to.
+    #[serde(skip_serializing_if = "Option::is_none")]
     #[garde(skip)]
+    pub runner_group: Option<String>,
+    /// Authenticate as a GitHub App. The vault secret then holds the PEM encoded private key
+    /// of the App instead of a personal access token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[garde(dive)]
+    pub app: Option<GitHubAppSpec>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Validate, Clone, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GitHubAppSpec {
+    /// ID of the GitHub App.
+    #[garde(range(min = 1))]
+    pub app_id: u64,
+    /// ID of the installation of the App in the organization or repository.
+    #[garde(range(min = 1))]
+    pub installation_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Validate, Clone, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GitLabSpec {
+    /// URL of the GitLab instance, e.g. `https://gitlab.com/`.
+    #[garde(url)]
     pub url: String,
+    /// Executor the runners run jobs with, defaults to `shell`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[garde(length(min = 1))]
+    pub executor: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Validate, Clone, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VaultSpec {
+    /// Azure Key Vault URL, e.g. `https://<vault>.vault.azure.net/`.
+    #[garde(url)]
+    pub url: String,
+    /// Name of the secret in the Key Vault holding the token, formerly known as `oid`.
+    #[garde(length(min = 1))]
+    pub secret_name: String,
+    /// Client ID of the service principal used to access the Key Vault, or of the managed
+    /// identity with `auth: ManagedIdentity`.
+    #[garde(pattern(
+        r"^[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}$"
+    ))]
+    pub client_id: String,
+    /// Azure AD tenant ID of the service principal.
+    #[garde(pattern(
+        r"^[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}$"
+    ))]
+    pub tenant_id: String,
+    /// How the operator authenticates as the service principal, defaults to `ClientSecret`.
+    #[serde(default)]
     #[garde(skip)]
-    pub pool: String,
+    pub auth: VaultAuth,
+    /// Client certificate of the service principal, required with `auth: Certificate`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[garde(dive)]
+    pub certificate: Option<VaultCertificateSpec>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Validate, Clone, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VaultCertificateSpec {
+    /// Name of the Secret in the namespace of the resource holding the certificate and its
+    /// private key: PEM encoded in `tls.crt` and `tls.key`, as in a `kubernetes.io/tls` Secret,
+    /// or a PFX in `certificate.pfx` with its password in `password`.
+    #[garde(length(min = 1))]
+    pub secret_name: String,
+}
+
+/// Credential the operator authenticates against the Key Vault with.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub enum VaultAuth {
+    /// Client secret of the service principal, injected as `SPN_SECRET` in the agent Secret.
+    #[default]
+    ClientSecret,
+    /// Azure AD workload identity federation: the projected service account token of the
+    /// operator is exchanged for an access token of the service principal, so no client secret
+    /// is stored in the cluster.
+    WorkloadIdentity,
+    /// User-assigned managed identity of the node pool, with tokens from the instance metadata
+    /// service. `clientId` is the client ID of the identity.
+    ManagedIdentity,
+    /// Client certificate of the service principal, read from the Secret in `certificate`. The
+    /// certificate signs the client assertion the operator authenticates with.
+    Certificate,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Validate, Clone, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AgentSpec {
+    /// Number of agent pods, bounded to keep a typo from flooding the cluster.
+    #[garde(range(min = 0, max = 50))]
+    pub replicas: i32,
+    /// Agent container image, e.g. a hardened internal image or one pinned by digest. Defaults
+    /// to the operator-level default of the provider, see the `defaults` module.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[garde(length(min = 1))]
+    pub image: Option<String>,
+    /// Pull policy of the agent image, defaults to the operator-level default.
+    #[serde(skip_serializing_if = "Option::is_none")]
     #[garde(skip)]
-    pub keyvault: String,
+    pub image_pull_policy: Option<ImagePullPolicy>,
+    /// Names of the Secrets in the namespace used to pull the agent image from a private
+    /// registry. Replaces the operator-level default when set.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[garde(inner(length(min = 1)))]
+    pub image_pull_secrets: Vec<String>,
+    /// Scheduling and resource settings of the agent pods.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[garde(dive)]
+    pub pod: Option<AgentPodSpec>,
+    /// Scale the agents on the jobs of the agent pool instead of running a fixed number of
+    /// `replicas`. Only supported by the `AzurePipelines` provider.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[garde(dive)]
+    pub autoscaling: Option<AutoscalingSpec>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Validate, Clone, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AutoscalingSpec {
+    /// Number of agents kept running when no jobs are queued.
+    #[garde(range(min = 0, max = 50))]
+    pub min_replicas: i32,
+    /// Upper bound of the number of agents, regardless of the number of queued jobs.
+    #[garde(range(min = 1, max = 50))]
+    pub max_replicas: i32,
+    /// Seconds to wait after scaling before the agents are scaled down, defaults to 300.
+    /// Scaling up is never delayed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[garde(range(max = 3600))]
+    pub cooldown_seconds: Option<u64>,
+}
+
+/// Settings passed into the `PodSpec` of the agent Deployment. The fields follow the Kubernetes
+/// `PodSpec`; `tolerations`, `affinity` and `topologySpreadConstraints` are validated against the
+/// Kubernetes types and `resources` as quantities, so a malformed value is reported instead of
+/// producing a broken Deployment.
+#[derive(Serialize, Deserialize, Debug, Default, Validate, Clone, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AgentPodSpec {
+    /// Compute resources of the agent container.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[garde(custom(quantities))]
+    pub resources: Option<ResourceRequirements>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     #[garde(skip)]
-    pub spn: String,
+    pub node_selector: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[garde(custom(kubernetes_list::<Toleration>))]
+    pub tolerations: Vec<Value>,
+    /// Replaces the default anti-affinity, which prefers to spread the agents across nodes.
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    #[garde(custom(kubernetes::<Affinity>))]
+    pub affinity: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[garde(length(min = 1))]
+    pub priority_class_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[garde(custom(kubernetes_list::<TopologySpreadConstraint>))]
+    pub topology_spread_constraints: Vec<Value>,
+}
+
+/// Requests and limits of the agent container, quantities as in the Kubernetes
+/// `ResourceRequirements`, e.g. `cpu: 500m` or `memory: 1Gi`.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, JsonSchema)]
+pub struct ResourceRequirements {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub requests: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub limits: BTreeMap<String, String>,
+}
+
+/// Checks that `value` deserializes into the Kubernetes type `T`.
+fn kubernetes<T: DeserializeOwned>(value: &Value, _context: &()) -> garde::Result {
+    if value.is_null() {
+        return Ok(());
+    }
+    serde_json::from_value::<T>(value.clone())
+        .map(|_| ())
+        .map_err(garde::Error::new)
+}
+
+/// Checks that the requests and limits are Kubernetes quantities, see `quantity::nanos`.
+fn quantities(resources: &Option<ResourceRequirements>, _context: &()) -> garde::Result {
+    let Some(resources) = resources else {
+        return Ok(());
+    };
+    resources
+        .requests
+        .iter()
+        .chain(&resources.limits)
+        .try_for_each(|(resource, value)| {
+            quantity::nanos(value)
+                .map(|_| ())
+                .map_err(|e| garde::Error::new(format!("{}: {}", resource, e)))
+        })
+}
+
+/// Checks that every item of `values` deserializes into the Kubernetes type `T`.
+fn kubernetes_list<T: DeserializeOwned>(values: &[Value], context: &()) -> garde::Result {
+    values
+        .iter()
+        .try_for_each(|value| kubernetes::<T>(value, context))
+}
+
+/// Egress allowed to the agent pods. All other egress is denied while the policy is enabled.
+#[derive(Serialize, Deserialize, Debug, Validate, Clone, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkPolicySpec {
+    /// Set to false to not restrict the egress of the agent pods at all.
+    #[serde(default = "enabled")]
+    #[garde(skip)]
+    pub enabled: bool,
+    /// Destinations the agents may connect to, defaults to the endpoints of the provider,
+    /// e.g. the `dev.azure.com` ranges for Azure Pipelines.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[garde(custom(cidrs))]
+    pub cidrs: Vec<String>,
+    /// Ports the agents may connect to on the `cidrs`, defaults to 443/TCP.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[garde(dive)]
+    pub ports: Vec<PortSpec>,
+    /// Allow DNS lookups against kube-dns in the `kube-system` namespace.
+    #[serde(default = "enabled")]
+    #[garde(skip)]
+    pub dns: bool,
+    /// Additional egress rules, as in the Kubernetes `NetworkPolicyEgressRule`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[garde(custom(kubernetes_list::<NetworkPolicyEgressRule>))]
+    pub extra_egress: Vec<Value>,
+}
+
+impl Default for NetworkPolicySpec {
+    fn default() -> Self {
+        NetworkPolicySpec {
+            enabled: true,
+            cidrs: Vec::new(),
+            ports: Vec::new(),
+            dns: true,
+            extra_egress: Vec::new(),
+        }
+    }
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Validate, Clone, PartialEq, JsonSchema)]
+pub struct PortSpec {
+    #[garde(range(min = 1))]
+    pub port: u16,
+    #[serde(default)]
     #[garde(skip)]
-    pub tenant: String,
+    pub protocol: Protocol,
+}
+
+/// Network protocol of a `PortSpec`.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub enum Protocol {
+    #[default]
+    #[serde(rename = "TCP")]
+    Tcp,
+    #[serde(rename = "UDP")]
+    Udp,
+    #[serde(rename = "SCTP")]
+    Sctp,
+}
+
+/// Checks that every value is an IPv4 or IPv6 network in CIDR notation, e.g. `13.107.6.0/24`.
+fn cidrs(values: &[String], _context: &()) -> garde::Result {
+    for value in values {
+        let valid = value.split_once('/').is_some_and(|(address, prefix)| {
+            match (address.parse::<IpAddr>(), prefix.parse::<u8>()) {
+                (Ok(IpAddr::V4(_)), Ok(prefix)) => prefix <= 32,
+                (Ok(IpAddr::V6(_)), Ok(prefix)) => prefix <= 128,
+                _ => false,
+            }
+        });
+        if !valid {
+            return Err(garde::Error::new(format!("{} is not a valid CIDR", value)));
+        }
+    }
+    Ok(())
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+/// Pull policy of a container image, as in the Kubernetes `Container` specification.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub enum ImagePullPolicy {
+    Always,
+    IfNotPresent,
+    Never,
+}
+
+impl ImagePullPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImagePullPolicy::Always => "Always",
+            ImagePullPolicy::IfNotPresent => "IfNotPresent",
+            ImagePullPolicy::Never => "Never",
+        }
+    }
+}
+
+/// Observed state of the `CDBootstrap` resource, modelled after the status of the Kubernetes
+/// built-in resources: a coarse `phase` plus a list of fine grained `conditions`.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct CDBootstrapStatus {
-    pub succeeded: bool,
+    /// High level summary of the lifecycle of the resource.
+    #[serde(default)]
+    pub phase: Phase,
+    /// The `metadata.generation` of the resource this status was computed for.
+    pub observed_generation: Option<i64>,
+    /// Number of agent pods reported ready by the Deployment.
+    pub ready_replicas: Option<i32>,
+    /// RFC 3339 timestamp of the last reconciliation that updated this status.
+    pub last_reconcile_time: Option<String>,
+    /// Latest observations of the state of the resource and its subresources.
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+    /// Last observed job counts and scale decision, when autoscaling is enabled.
+    #[serde(default)]
+    pub autoscaling: Option<AutoscalingStatus>,
+    /// Azure service tag the egress NetworkPolicy was last rendered from.
+    #[serde(default)]
+    pub service_tag: Option<ServiceTagStatus>,
+    /// Subresources that drifted from their desired state in the last reconciliation and were
+    /// applied again, e.g. `ConfigMap` or `Deployment`.
+    #[serde(default)]
+    pub drifted: Vec<String>,
+    /// Backoff of the failing reconciliations, cleared once the resource reconciles.
+    #[serde(default)]
+    pub retry: Option<RetryStatus>,
+    /// Version of the vault secret the agent token in the agent Secret was collected from.
+    #[serde(default)]
+    pub token: Option<TokenStatus>,
+}
+
+/// Version of the vault secret applied to the agent Secret.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenStatus {
+    /// Version of the secret in the Key Vault, the last segment of its identifier.
+    pub vault_version: String,
+    /// RFC 3339 timestamp of the last time a new version of the vault secret was applied.
+    pub rotation_time: Option<String>,
+    /// RFC 3339 timestamp of the last time the version of the vault secret was read.
+    pub check_time: Option<String>,
+}
+
+/// Retry of a failing reconciliation of a `CDBootstrap` resource.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RetryStatus {
+    /// Number of consecutive failed reconciliations.
+    pub attempts: u32,
+    /// RFC 3339 timestamp of the next reconciliation.
+    pub next_retry_time: Option<String>,
+}
+
+/// Version of the Azure service tag applied to the egress NetworkPolicy.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceTagStatus {
+    /// Name of the service tag, e.g. `AzureDevOps`.
+    pub name: String,
+    /// Region the service tag is filtered on, if any.
+    pub region: Option<String>,
+    /// The `changeNumber` of the service tag in the Azure Service Tags document.
+    pub change_number: u64,
+}
+
+/// Observed state of the autoscaler of a `CDBootstrap` resource.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AutoscalingStatus {
+    /// Number of jobs waiting for an agent in the agent pool.
+    pub queued_jobs: u32,
+    /// Number of jobs running on an agent of the agent pool.
+    pub running_jobs: u32,
+    /// Number of agents the Deployment is scaled to.
+    pub desired_replicas: i32,
+    /// RFC 3339 timestamp of the last time the number of agents was changed.
+    pub last_scale_time: Option<String>,
+    /// Human readable description of the last scale decision.
+    pub last_decision: Option<String>,
+}
+
+/// Lifecycle phase of a `CDBootstrap` resource.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub enum Phase {
+    /// The resource has not been reconciled yet.
+    #[default]
+    Pending,
+    /// Subresources are created, but not all agents are available yet.
+    Provisioning,
+    /// All conditions are met and the agents are available.
+    Running,
+    /// One of the subresources or the Key Vault is failing.
+    Degraded,
+    /// The specification is invalid and will not be retried until it is changed.
+    Failed,
+    /// The resource is being deleted.
+    Terminating,
+}
+
+/// A single observation of the state of the resource, compatible with the `metav1.Condition`
+/// type used by the Kubernetes built-in resources.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Condition {
+    /// Type of the condition, e.g. `Ready` or `VaultReachable`.
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// Status of the condition, one of `True`, `False` or `Unknown`.
+    pub status: ConditionStatus,
+    /// Machine readable, CamelCase reason for the last transition.
+    pub reason: String,
+    /// Human readable details about the last transition.
+    pub message: String,
+    /// RFC 3339 timestamp of the last time the status of the condition changed.
+    pub last_transition_time: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+pub enum ConditionStatus {
+    True,
+    False,
+    Unknown,
 }
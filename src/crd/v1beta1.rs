@@ -0,0 +1,27 @@
+use kube::CustomResource;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::crd::CDBootstrapStatus;
+
+/// The original, flat `v1beta1` specification of the `CDBootstrap` resource. It is still served
+/// by the API server, objects are converted from and to the `v1` storage version by the
+/// conversion webhook. The operator itself only reconciles `v1` objects.
+#[derive(CustomResource, Serialize, Deserialize, Debug, Default, Clone, PartialEq, JsonSchema)]
+#[kube(
+    group = "cndev.nl",
+    version = "v1beta1",
+    kind = "CDBootstrap",
+    plural = "cdbootstraps",
+    namespaced
+)]
+#[kube(status = "CDBootstrapStatus")]
+pub struct CDBootstrapSpec {
+    pub oid: String,
+    pub replicas: i32,
+    pub url: String,
+    pub pool: String,
+    pub keyvault: String,
+    pub spn: String,
+    pub tenant: String,
+}
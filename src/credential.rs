@@ -0,0 +1,515 @@
+use async_trait::async_trait;
+use azure_core::auth::{AccessToken, TokenCredential, TokenResponse};
+use azure_core::error::ErrorKind;
+use azure_core::Error;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use k8s_openapi::chrono::{self, DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use simple_asn1::ASN1Block;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use time::{Duration, OffsetDateTime};
+
+/// Azure AD host tokens are requested from, unless set in `AZURE_AUTHORITY_HOST`.
+pub const DEFAULT_AUTHORITY_HOST: &str = "https://login.microsoftonline.com";
+/// Service account token projected by the Azure AD workload identity webhook, unless set in
+/// `AZURE_FEDERATED_TOKEN_FILE`.
+pub const DEFAULT_FEDERATED_TOKEN_FILE: &str = "/var/run/secrets/azure/tokens/azure-identity-token";
+
+/// Instance metadata service of the Azure VM, unless set in `IMDS_ENDPOINT`.
+pub const DEFAULT_IMDS_ENDPOINT: &str = "http://169.254.169.254";
+const IMDS_API_VERSION: &str = "2018-02-01";
+
+const CLIENT_ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+/// Cached access tokens are renewed this long before they expire.
+const TOKEN_RENEWAL_MARGIN: Duration = Duration::minutes(5);
+
+/// Successful response of the Azure AD token endpoint.
+#[derive(Deserialize)]
+struct TokenEndpointResponse {
+    access_token: String,
+    /// Lifetime of the access token in seconds.
+    expires_in: i64,
+}
+
+/// Successful response of the managed identity endpoint of the instance metadata service.
+#[derive(Deserialize)]
+struct ImdsResponse {
+    access_token: String,
+    /// Lifetime of the access token in seconds, as a string.
+    expires_in: String,
+}
+
+/// Claims of the client assertion a service principal authenticates with its certificate.
+#[derive(Serialize)]
+struct AssertionClaims<'a> {
+    aud: &'a str,
+    iss: &'a str,
+    sub: &'a str,
+    jti: String,
+    nbf: i64,
+    iat: i64,
+    exp: i64,
+}
+
+/// Returns the token endpoint of the tenant.
+fn token_url(authority_host: &str, tenant_id: &str) -> String {
+    format!(
+        "{}/{}/oauth2/v2.0/token",
+        authority_host.trim_end_matches('/'),
+        tenant_id
+    )
+}
+
+/// Returns the OAuth 2.0 scope of a resource, e.g. `https://vault.azure.net/.default`.
+pub fn scope(resource: &str) -> String {
+    if resource.ends_with("/.default") {
+        resource.to_owned()
+    } else {
+        format!("{}/.default", resource.trim_end_matches('/'))
+    }
+}
+
+/// Requests an access token for `resource` with the client credentials flow, authenticating
+/// the service principal with a signed client assertion instead of a client secret. Every
+/// failure is reported as `ErrorKind::Credential`, so it surfaces as a failed authentication.
+async fn client_assertion_token(
+    http: &reqwest::Client,
+    authority_host: &str,
+    tenant_id: &str,
+    client_id: &str,
+    assertion: &str,
+    resource: &str,
+) -> azure_core::Result<TokenResponse> {
+    let url = token_url(authority_host, tenant_id);
+    let scope = scope(resource);
+    let form = [
+        ("grant_type", "client_credentials"),
+        ("client_id", client_id),
+        ("scope", scope.as_str()),
+        ("client_assertion_type", CLIENT_ASSERTION_TYPE),
+        ("client_assertion", assertion),
+    ];
+    let credential_error = |message: String| Error::message(ErrorKind::Credential, message);
+
+    let response = http
+        .post(&url)
+        .form(&form)
+        .send()
+        .await
+        .map_err(|e| credential_error(format!("token request to {} failed: {}", url, e)))?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(credential_error(format!(
+            "Azure AD rejected the client assertion of {} with {}: {}",
+            client_id, status, body
+        )));
+    }
+    let token: TokenEndpointResponse = response
+        .json()
+        .await
+        .map_err(|e| credential_error(format!("invalid token response of {}: {}", url, e)))?;
+
+    Ok(TokenResponse::new(
+        AccessToken::new(token.access_token),
+        OffsetDateTime::now_utc() + Duration::seconds(token.expires_in),
+    ))
+}
+
+/// Azure AD workload identity federation. The service account token projected into the operator
+/// pod is sent as client assertion of the service principal, which trusts the service account
+/// of the operator through a federated identity credential. No client secret is involved.
+pub struct WorkloadIdentityCredential {
+    http: reqwest::Client,
+    authority_host: String,
+    tenant_id: String,
+    client_id: String,
+    token_file: PathBuf,
+}
+
+impl WorkloadIdentityCredential {
+    pub fn new(
+        authority_host: &str,
+        tenant_id: &str,
+        client_id: &str,
+        token_file: PathBuf,
+    ) -> Self {
+        WorkloadIdentityCredential {
+            http: reqwest::Client::new(),
+            authority_host: authority_host.to_owned(),
+            tenant_id: tenant_id.to_owned(),
+            client_id: client_id.to_owned(),
+            token_file,
+        }
+    }
+
+    /// Returns the credential of the service principal, with the authority host and token file
+    /// read from the environment of the operator as set by the workload identity webhook:
+    /// - `AZURE_AUTHORITY_HOST` - Azure AD host, defaults to `https://login.microsoftonline.com`
+    /// - `AZURE_FEDERATED_TOKEN_FILE` - Projected service account token, defaults to
+    ///   `/var/run/secrets/azure/tokens/azure-identity-token`
+    pub fn from_env(tenant_id: &str, client_id: &str) -> Self {
+        let var = |key: &str| env::var(key).ok().filter(|value| !value.trim().is_empty());
+        WorkloadIdentityCredential::new(
+            &var("AZURE_AUTHORITY_HOST").unwrap_or(String::from(DEFAULT_AUTHORITY_HOST)),
+            tenant_id,
+            client_id,
+            PathBuf::from(
+                var("AZURE_FEDERATED_TOKEN_FILE")
+                    .unwrap_or(String::from(DEFAULT_FEDERATED_TOKEN_FILE)),
+            ),
+        )
+    }
+}
+
+#[async_trait]
+impl TokenCredential for WorkloadIdentityCredential {
+    /// Exchanges the service account token for an access token. The file is read on every
+    /// request, as the kubelet rotates the projected token before it expires.
+    async fn get_token(&self, resource: &str) -> azure_core::Result<TokenResponse> {
+        let assertion = fs::read_to_string(&self.token_file).map_err(|e| {
+            Error::message(
+                ErrorKind::Credential,
+                format!(
+                    "unable to read the federated token file {}: {}",
+                    self.token_file.display(),
+                    e
+                ),
+            )
+        })?;
+        client_assertion_token(
+            &self.http,
+            &self.authority_host,
+            &self.tenant_id,
+            &self.client_id,
+            assertion.trim(),
+            resource,
+        )
+        .await
+    }
+}
+
+/// User-assigned managed identity of the node pool, e.g. the kubelet identity of an AKS node
+/// pool. Access tokens are requested from the instance metadata service (IMDS) of the node, so
+/// no credential is stored at all. The client ID selects one of the identities assigned to the
+/// node.
+pub struct ManagedIdentityCredential {
+    http: reqwest::Client,
+    endpoint: String,
+    client_id: String,
+}
+
+impl ManagedIdentityCredential {
+    pub fn new(endpoint: &str, client_id: &str) -> Self {
+        ManagedIdentityCredential {
+            http: reqwest::Client::new(),
+            endpoint: endpoint.to_owned(),
+            client_id: client_id.to_owned(),
+        }
+    }
+
+    /// Returns the credential of the identity, with the endpoint read from `IMDS_ENDPOINT`,
+    /// defaults to `http://169.254.169.254`.
+    pub fn from_env(client_id: &str) -> Self {
+        let endpoint = env::var("IMDS_ENDPOINT")
+            .ok()
+            .filter(|value| !value.trim().is_empty())
+            .unwrap_or(String::from(DEFAULT_IMDS_ENDPOINT));
+        ManagedIdentityCredential::new(&endpoint, client_id)
+    }
+}
+
+#[async_trait]
+impl TokenCredential for ManagedIdentityCredential {
+    /// Requests an access token for the identity from IMDS. Every failure is reported as
+    /// `ErrorKind::Credential`, e.g. a client ID that is not assigned to the node.
+    async fn get_token(&self, resource: &str) -> azure_core::Result<TokenResponse> {
+        let url = format!(
+            "{}/metadata/identity/oauth2/token",
+            self.endpoint.trim_end_matches('/')
+        );
+        let resource = resource.trim_end_matches("/.default");
+        let query = [
+            ("api-version", IMDS_API_VERSION),
+            ("resource", resource),
+            ("client_id", self.client_id.as_str()),
+        ];
+        let credential_error = |message: String| Error::message(ErrorKind::Credential, message);
+
+        let response = self
+            .http
+            .get(&url)
+            .header("Metadata", "true")
+            .query(&query)
+            .send()
+            .await
+            .map_err(|e| credential_error(format!("IMDS request to {} failed: {}", url, e)))?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(credential_error(format!(
+                "IMDS refused a token for managed identity {} with {}: {}",
+                self.client_id, status, body
+            )));
+        }
+        let token: ImdsResponse = response
+            .json()
+            .await
+            .map_err(|e| credential_error(format!("invalid IMDS response of {}: {}", url, e)))?;
+        let expires_in = token.expires_in.parse().map_err(|e| {
+            credential_error(format!("invalid expires_in {}: {}", token.expires_in, e))
+        })?;
+
+        Ok(TokenResponse::new(
+            AccessToken::new(token.access_token),
+            OffsetDateTime::now_utc() + Duration::seconds(expires_in),
+        ))
+    }
+}
+
+/// Client certificate of a service principal with its RSA private key.
+#[derive(Clone)]
+pub struct ClientCertificate {
+    key: EncodingKey,
+    /// Base64url encoded SHA-1 thumbprint of the certificate, by which Azure AD finds the
+    /// certificate to verify the client assertion with.
+    pub thumbprint: String,
+    /// End of the validity period of the certificate.
+    pub not_after: DateTime<Utc>,
+}
+
+impl ClientCertificate {
+    /// Reads the certificate from PEM, e.g. the `tls.crt` and `tls.key` of a `kubernetes.io/tls`
+    /// Secret. The first certificate is used, so `certificate` may hold the whole chain. The key
+    /// is either PKCS#1 or PKCS#8 encoded.
+    pub fn from_pem(certificate: &[u8], key: &[u8]) -> Result<Self, String> {
+        let der = pem::parse_many(certificate)
+            .map_err(|e| format!("invalid PEM certificate: {}", e))?
+            .into_iter()
+            .find(|block| block.tag == "CERTIFICATE")
+            .ok_or(String::from("no CERTIFICATE found in the PEM certificate"))?
+            .contents;
+        let key = EncodingKey::from_rsa_pem(key)
+            .map_err(|e| format!("invalid RSA private key: {}", e))?;
+        ClientCertificate::new(&der, key)
+    }
+
+    /// Reads the certificate and its private key from a PKCS#12 archive, a PFX.
+    pub fn from_pfx(pfx: &[u8], password: &str) -> Result<Self, String> {
+        let pfx = p12::PFX::parse(pfx).map_err(|e| format!("invalid PFX: {:?}", e))?;
+        let der = pfx
+            .cert_x509_bags(password)
+            .map_err(|e| format!("unable to read the certificate of the PFX: {:?}", e))?
+            .into_iter()
+            .next()
+            .ok_or(String::from("no certificate found in the PFX"))?;
+        let key = pfx
+            .key_bags(password)
+            .map_err(|e| format!("unable to read the private key of the PFX: {:?}", e))?
+            .into_iter()
+            .next()
+            .ok_or(String::from("no private key found in the PFX"))?;
+        // The key bags hold PKCS#8 keys, which are read as PEM
+        let key = pem::encode(&pem::Pem {
+            tag: String::from("PRIVATE KEY"),
+            contents: key,
+        });
+        let key = EncodingKey::from_rsa_pem(key.as_bytes())
+            .map_err(|e| format!("invalid RSA private key in the PFX: {}", e))?;
+        ClientCertificate::new(&der, key)
+    }
+
+    /// Returns the certificate of DER encoded X.509 certificate `der` with its private key.
+    fn new(der: &[u8], key: EncodingKey) -> Result<Self, String> {
+        // The key is only parsed when signing, sign once so an invalid key fails here and not at
+        // the token endpoint
+        jsonwebtoken::crypto::sign(der, &key, Algorithm::RS256)
+            .map_err(|e| format!("invalid RSA private key: {}", e))?;
+        Ok(ClientCertificate {
+            key,
+            thumbprint: URL_SAFE_NO_PAD.encode(Sha1::digest(der)),
+            not_after: not_after(der)?,
+        })
+    }
+
+    /// Returns true if the certificate is no longer valid at `now`, or will be within `window`.
+    pub fn expires_within(&self, now: DateTime<Utc>, window: chrono::Duration) -> bool {
+        self.not_after <= now + window
+    }
+
+    /// Returns the client assertion for the token endpoint `audience`: a JWT issued by the
+    /// service principal, valid for 10 minutes and signed with the private key of the
+    /// certificate.
+    pub fn assertion(
+        &self,
+        audience: &str,
+        client_id: &str,
+        now: DateTime<Utc>,
+    ) -> Result<String, String> {
+        let mut header = Header::new(Algorithm::RS256);
+        header.x5t = Some(self.thumbprint.clone());
+        let claims = AssertionClaims {
+            aud: audience,
+            iss: client_id,
+            sub: client_id,
+            jti: jti(),
+            nbf: now.timestamp(),
+            iat: now.timestamp(),
+            exp: now.timestamp() + 600,
+        };
+        jsonwebtoken::encode(&header, &claims, &self.key)
+            .map_err(|e| format!("unable to sign the client assertion: {}", e))
+    }
+}
+
+/// Returns the end of the validity period of a DER encoded X.509 certificate: the second time
+/// of the validity sequence of the `tbsCertificate`.
+fn not_after(der: &[u8]) -> Result<DateTime<Utc>, String> {
+    let blocks =
+        simple_asn1::from_der(der).map_err(|e| format!("invalid X.509 certificate: {}", e))?;
+    let tbs_certificate = match blocks.first() {
+        Some(ASN1Block::Sequence(_, certificate)) => match certificate.first() {
+            Some(ASN1Block::Sequence(_, tbs_certificate)) => tbs_certificate,
+            _ => return Err(String::from("X.509 certificate without tbsCertificate")),
+        },
+        _ => return Err(String::from("invalid X.509 certificate")),
+    };
+    tbs_certificate
+        .iter()
+        .find_map(|block| match block {
+            ASN1Block::Sequence(_, validity) => match validity.as_slice() {
+                [_, ASN1Block::UTCTime(_, not_after) | ASN1Block::GeneralizedTime(_, not_after)] => {
+                    Utc.timestamp_opt(not_after.assume_utc().unix_timestamp(), 0)
+                        .single()
+                }
+                _ => None,
+            },
+            _ => None,
+        })
+        .ok_or(String::from("X.509 certificate without validity"))
+}
+
+/// Returns a random identifier of a client assertion, so Azure AD can reject a replayed one.
+fn jti() -> String {
+    let random = || RandomState::new().build_hasher().finish();
+    format!("{:016x}{:016x}", random(), random())
+}
+
+/// Service principal authenticating with a client certificate. Every token request is made with
+/// a new client assertion signed by the certificate, so no secret is sent to Azure AD.
+pub struct ClientCertificateCredential {
+    http: reqwest::Client,
+    authority_host: String,
+    tenant_id: String,
+    client_id: String,
+    certificate: ClientCertificate,
+}
+
+impl ClientCertificateCredential {
+    pub fn new(
+        authority_host: &str,
+        tenant_id: &str,
+        client_id: &str,
+        certificate: ClientCertificate,
+    ) -> Self {
+        ClientCertificateCredential {
+            http: reqwest::Client::new(),
+            authority_host: authority_host.to_owned(),
+            tenant_id: tenant_id.to_owned(),
+            client_id: client_id.to_owned(),
+            certificate,
+        }
+    }
+
+    /// Returns the credential of the service principal, with the Azure AD host read from
+    /// `AZURE_AUTHORITY_HOST`, defaults to `https://login.microsoftonline.com`.
+    pub fn from_env(tenant_id: &str, client_id: &str, certificate: ClientCertificate) -> Self {
+        let authority_host = env::var("AZURE_AUTHORITY_HOST")
+            .ok()
+            .filter(|value| !value.trim().is_empty())
+            .unwrap_or(String::from(DEFAULT_AUTHORITY_HOST));
+        ClientCertificateCredential::new(&authority_host, tenant_id, client_id, certificate)
+    }
+}
+
+#[async_trait]
+impl TokenCredential for ClientCertificateCredential {
+    /// Requests an access token with a client assertion signed by the certificate.
+    async fn get_token(&self, resource: &str) -> azure_core::Result<TokenResponse> {
+        let audience = token_url(&self.authority_host, &self.tenant_id);
+        let assertion = self
+            .certificate
+            .assertion(&audience, &self.client_id, Utc::now())
+            .map_err(|e| Error::message(ErrorKind::Credential, e))?;
+        client_assertion_token(
+            &self.http,
+            &self.authority_host,
+            &self.tenant_id,
+            &self.client_id,
+            &assertion,
+            resource,
+        )
+        .await
+    }
+}
+
+/// Access tokens of all `CachedCredential`s with their expiry, by credential key and resource.
+fn token_cache() -> &'static Mutex<HashMap<String, (String, OffsetDateTime)>> {
+    static TOKENS: OnceLock<Mutex<HashMap<String, (String, OffsetDateTime)>>> = OnceLock::new();
+    TOKENS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// A credential that reuses its access tokens until shortly before they expire. The Key Vault
+/// credentials are built anew on every reconciliation, so the tokens are cached for the whole
+/// operator, under a key identifying the service principal and what it authenticates with.
+pub struct CachedCredential {
+    key: String,
+    credential: Arc<dyn TokenCredential>,
+}
+
+impl CachedCredential {
+    /// # Arguments
+    /// - `key` - Identifies the credential, credentials with the same key share their tokens.
+    /// - `credential` - Requests the tokens that are not cached or expire soon.
+    pub fn new(key: &str, credential: Arc<dyn TokenCredential>) -> Self {
+        CachedCredential {
+            key: key.to_owned(),
+            credential,
+        }
+    }
+}
+
+#[async_trait]
+impl TokenCredential for CachedCredential {
+    async fn get_token(&self, resource: &str) -> azure_core::Result<TokenResponse> {
+        let key = format!("{} {}", self.key, scope(resource));
+        let cached = token_cache()
+            .lock()
+            .ok()
+            .and_then(|tokens| tokens.get(&key).cloned());
+        if let Some((token, expires_on)) = cached {
+            if expires_on > OffsetDateTime::now_utc() + TOKEN_RENEWAL_MARGIN {
+                return Ok(TokenResponse::new(AccessToken::new(token), expires_on));
+            }
+        }
+
+        let response = self.credential.get_token(resource).await?;
+        if let Ok(mut tokens) = token_cache().lock() {
+            tokens.insert(
+                key,
+                (response.token.secret().to_owned(), response.expires_on),
+            );
+        }
+        Ok(response)
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+use std::env;
+
+use crate::crd::{CDBootstrapSpec, ImagePullPolicy, Provider};
+use crate::provider::CiProvider;
+
+/// Environment variables of the operator holding the default agent image per provider.
+const IMAGE_VARS: [(Provider, &str); 3] = [
+    (Provider::AzurePipelines, "AGENT_IMAGE_AZURE_PIPELINES"),
+    (Provider::GitHubActions, "AGENT_IMAGE_GITHUB_ACTIONS"),
+    (Provider::GitLabRunner, "AGENT_IMAGE_GITLAB_RUNNER"),
+];
+const PULL_POLICY_VAR: &str = "AGENT_IMAGE_PULL_POLICY";
+const PULL_SECRETS_VAR: &str = "AGENT_IMAGE_PULL_SECRETS";
+
+/// Operator-level defaults of the agent Deployment, applied when the `spec.agent` section of a
+/// `CDBootstrap` resource leaves them out. Lets a platform team point all agents to an internal
+/// registry without touching every resource.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AgentDefaults {
+    /// Agent image per provider, replacing the image built into the provider.
+    pub images: BTreeMap<Provider, String>,
+    pub image_pull_policy: Option<ImagePullPolicy>,
+    pub image_pull_secrets: Vec<String>,
+}
+
+/// The image settings of the agent container, after applying the defaults.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AgentImage {
+    pub image: String,
+    pub pull_policy: Option<ImagePullPolicy>,
+    pub pull_secrets: Vec<String>,
+}
+
+impl AgentDefaults {
+    /// Reads the defaults from the environment of the operator:
+    /// - `AGENT_IMAGE_AZURE_PIPELINES`, `AGENT_IMAGE_GITHUB_ACTIONS`, `AGENT_IMAGE_GITLAB_RUNNER`
+    /// - `AGENT_IMAGE_PULL_POLICY` - `Always`, `IfNotPresent` or `Never`
+    /// - `AGENT_IMAGE_PULL_SECRETS` - Comma separated names of Secrets
+    pub fn from_env() -> Self {
+        AgentDefaults::from_vars(|key| env::var(key).ok())
+    }
+
+    /// Reads the defaults with the given variable lookup, empty values are ignored.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let var = |key: &str| var(key).filter(|value| !value.trim().is_empty());
+
+        let images = IMAGE_VARS
+            .iter()
+            .filter_map(|(provider, key)| var(key).map(|image| (*provider, image)))
+            .collect();
+        let image_pull_policy = var(PULL_POLICY_VAR).and_then(|policy| {
+            serde_json::from_value(serde_json::Value::String(policy.trim().to_owned())).ok()
+        });
+        let image_pull_secrets = var(PULL_SECRETS_VAR)
+            .map(|secrets| {
+                secrets
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|secret| !secret.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        AgentDefaults {
+            images,
+            image_pull_policy,
+            image_pull_secrets,
+        }
+    }
+
+    /// Returns the image settings of the agent container. Settings in the specification take
+    /// precedence over the operator-level defaults, which take precedence over the provider.
+    pub fn resolve(&self, spec: &CDBootstrapSpec, provider: &dyn CiProvider) -> AgentImage {
+        let image = spec
+            .agent
+            .image
+            .clone()
+            .or_else(|| self.images.get(&spec.provider).cloned())
+            .unwrap_or_else(|| provider.image().to_owned());
+        let pull_secrets = if spec.agent.image_pull_secrets.is_empty() {
+            self.image_pull_secrets.clone()
+        } else {
+            spec.agent.image_pull_secrets.clone()
+        };
+
+        AgentImage {
+            image,
+            pull_policy: spec.agent.image_pull_policy.or(self.image_pull_policy),
+            pull_secrets,
+        }
+    }
+}
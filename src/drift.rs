@@ -0,0 +1,130 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+use crate::quantity;
+
+/// The subresources a `CDBootstrap` resource owns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Subresource {
+    Secret,
+    ConfigMap,
+    NetworkPolicy,
+    Deployment,
+}
+
+impl Subresource {
+    pub const ALL: [Subresource; 4] = [
+        Subresource::Secret,
+        Subresource::ConfigMap,
+        Subresource::NetworkPolicy,
+        Subresource::Deployment,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Subresource::Secret => "Secret",
+            Subresource::ConfigMap => "ConfigMap",
+            Subresource::NetworkPolicy => "NetworkPolicy",
+            Subresource::Deployment => "Deployment",
+        }
+    }
+}
+
+impl fmt::Display for Subresource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Returns the hex encoded SHA-256 hash of a rendered object. Keys are hashed in sorted order, so
+/// the hash only depends on the content of the object. The hash is stored in the pod template of
+/// the agents, so it has to be the same for every build of the operator.
+pub fn spec_hash(value: &Value) -> String {
+    format!("{:x}", Sha256::digest(value.to_string()))
+}
+
+/// Returns the fields of the live object that the operator renders in `desired`, so both can be
+/// hashed and compared. Fields the API server defaults or other controllers add are left out.
+/// A field that is empty in `desired` and missing from the live object counts as equal, as the
+/// API server drops empty lists and maps. Resource `requests` and `limits` are compared by value,
+/// see `rendered_quantities`.
+pub fn rendered_fields(desired: &Value, live: &Value) -> Value {
+    match (desired, live) {
+        (Value::Object(desired), Value::Object(live)) => Value::Object(
+            desired
+                .iter()
+                .map(|(key, value)| {
+                    let live = live.get(key).unwrap_or(&Value::Null);
+                    let fields = match key.as_str() {
+                        "requests" | "limits" => rendered_quantities(value, live),
+                        _ => rendered_fields(value, live),
+                    };
+                    (key.clone(), fields)
+                })
+                .collect::<Map<String, Value>>(),
+        ),
+        // Entries added to a list count as drift, entries are matched by position
+        (Value::Array(desired), Value::Array(live)) => Value::Array(
+            live.iter()
+                .enumerate()
+                .map(|(i, live)| match desired.get(i) {
+                    Some(desired) => rendered_fields(desired, live),
+                    None => live.clone(),
+                })
+                .collect(),
+        ),
+        (desired, Value::Null) if is_empty(desired) => desired.clone(),
+        (_, live) => live.clone(),
+    }
+}
+
+/// Returns the live quantities of the `requests` or `limits` in `desired`. The API server stores
+/// quantities in canonical form, e.g. `1Gi` for `1024Mi` and `500m` for `0.5`, so a live quantity
+/// with the same value as the desired one counts as equal.
+fn rendered_quantities(desired: &Value, live: &Value) -> Value {
+    let (Value::Object(desired), Value::Object(live)) = (desired, live) else {
+        return rendered_fields(desired, live);
+    };
+    Value::Object(
+        desired
+            .iter()
+            .map(|(resource, value)| {
+                let live = live.get(resource).unwrap_or(&Value::Null);
+                let fields = match (quantity_value(value), quantity_value(live)) {
+                    (Some(desired), Some(live)) if desired == live => value.clone(),
+                    _ => rendered_fields(value, live),
+                };
+                (resource.clone(), fields)
+            })
+            .collect::<Map<String, Value>>(),
+    )
+}
+
+fn quantity_value(value: &Value) -> Option<i128> {
+    match value {
+        Value::String(value) => quantity::nanos(value).ok(),
+        Value::Number(value) => quantity::nanos(&value.to_string()).ok(),
+        _ => None,
+    }
+}
+
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Array(values) => values.is_empty(),
+        Value::Object(values) => values.is_empty(),
+        Value::String(value) => value.is_empty(),
+        _ => false,
+    }
+}
+
+/// Returns true if the live object differs from the desired object in any field the operator
+/// renders, by comparing the hash of the desired object with the hash of those fields of the
+/// live object.
+pub fn drifted<K: Serialize>(desired: &K, live: &K) -> bool {
+    let desired = serde_json::to_value(desired).unwrap_or_default();
+    let live = serde_json::to_value(live).unwrap_or_default();
+    spec_hash(&desired) != spec_hash(&rendered_fields(&desired, &live))
+}
@@ -0,0 +1,106 @@
+/// How a failed reconciliation is retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryPolicy {
+    /// Retried with exponential backoff, see `Backoff`.
+    Backoff,
+    /// Not retried until the `CDBootstrap` resource changes, retrying would fail the same way.
+    AwaitChange,
+}
+
+/// All errors possible to occur during reconciliation
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Any error originating from the `kube-rs` crate
+    #[error("Kubernetes reported error: {source}")]
+    KubeError {
+        #[from]
+        source: kube::Error,
+    },
+    /// Error in user input or CDBootstrap resource definition, typically missing fields.
+    #[error("Invalid CDBootstrap CRD: {0}")]
+    UserInputError(String),
+    /// The Key Vault rejected the credentials, e.g. a wrong or expired `SPN_SECRET`.
+    #[error("Authentication against the Key Vault failed: {0}")]
+    VaultAuthentication(String),
+    /// The Key Vault does not exist or can not be reached, e.g. a malformed or unknown URL.
+    #[error("Key Vault not found: {0}")]
+    VaultNotFound(String),
+    /// The Key Vault request failed for another reason, e.g. throttling or an outage.
+    #[error("Key Vault request failed: {0}")]
+    Vault(String),
+    /// A secret the agents need is missing from the Key Vault or the agent Secret.
+    #[error("Secret missing: {0}")]
+    SecretMissing(String),
+    /// The client certificate of the service principal can not be read or has expired.
+    #[error("Invalid client certificate: {0}")]
+    InvalidCertificate(String),
+    /// The resource selects an identity of the operator it is not allowed to use, see
+    /// `IdentityAllowList`.
+    #[error("Identity not allowed: {0}")]
+    IdentityNotAllowed(String),
+    /// The CI provider did not exchange the vault secret for an agent token.
+    #[error("Token exchange failed: {0}")]
+    TokenExchange(String),
+    /// Any other request to the CI provider failed, e.g. removing the agents.
+    #[error("CI provider request failed: {0}")]
+    Provider(String),
+    /// The job requests of the agent pool could not be read from Azure DevOps.
+    #[error("Job requests unavailable: {0}")]
+    JobRequests(String),
+    /// The Azure Service Tags document can not be read or lacks the service tag.
+    #[error("Service tags unavailable: {0}")]
+    ServiceTags(String),
+    /// A subresource could not be rendered from the specification.
+    #[error("Unable to serialize the {kind} manifest: {source}")]
+    ManifestSerialization {
+        kind: &'static str,
+        source: serde_json::Error,
+    },
+    /// Server-side apply of a subresource failed on fields owned by another field manager.
+    #[error("Field conflict applying the {kind}: {message}")]
+    SubresourceConflict { kind: String, message: String },
+}
+
+impl Error {
+    /// Returns the CamelCase reason the error is reported with in the `Ready` condition.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Error::KubeError { .. } => "ReconcileError",
+            Error::UserInputError(_) => "InvalidSpec",
+            Error::VaultAuthentication(_) => "VaultAuthenticationFailed",
+            Error::VaultNotFound(_) => "VaultNotFound",
+            Error::Vault(_) => "VaultRequestFailed",
+            Error::SecretMissing(_) => "SecretMissing",
+            Error::InvalidCertificate(_) => "InvalidCertificate",
+            Error::IdentityNotAllowed(_) => "IdentityNotAllowed",
+            Error::TokenExchange(_) => "TokenExchangeFailed",
+            Error::Provider(_) => "ProviderRequestFailed",
+            Error::JobRequests(_) => "JobRequestsUnavailable",
+            Error::ServiceTags(_) => "ServiceTagsUnavailable",
+            Error::ManifestSerialization { .. } => "ManifestSerializationFailed",
+            Error::SubresourceConflict { .. } => "FieldConflict",
+        }
+    }
+
+    /// Returns how the failed reconciliation is retried. Errors that only a change of the
+    /// resource can resolve are not retried. Credentials, secrets, certificates and conflicting
+    /// field managers live outside the resource, so those errors are retried with backoff.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        match self {
+            Error::UserInputError(_)
+            | Error::ManifestSerialization { .. }
+            | Error::IdentityNotAllowed(_) => RetryPolicy::AwaitChange,
+            Error::KubeError { .. }
+            | Error::VaultAuthentication(_)
+            | Error::VaultNotFound(_)
+            | Error::Vault(_)
+            | Error::SecretMissing(_)
+            | Error::InvalidCertificate(_)
+            | Error::TokenExchange(_)
+            | Error::Provider(_)
+            | Error::JobRequests(_)
+            | Error::ServiceTags(_)
+            | Error::SubresourceConflict { .. } => RetryPolicy::Backoff,
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use kube::runtime::events::{Event, EventType, Recorder, Reporter};
+use kube::{Client, Resource};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::*;
+
+use crate::crd::CDBootstrap;
+
+/// The subresources of the resource have been created.
+pub const CREATED: &str = "Created";
+/// Drifted subresources of the resource have been applied again.
+pub const UPDATED: &str = "Updated";
+/// One of the subresources could not be applied.
+pub const APPLY_FAILED: &str = "ApplyFailed";
+/// The agent token has been collected from the vault.
+pub const TOKEN_FETCHED: &str = "TokenFetched";
+/// A new version of the vault secret has been collected.
+pub const TOKEN_ROTATED: &str = "TokenRotated";
+/// Authentication against, or the connection to, the vault failed.
+pub const VAULT_AUTHENTICATION_FAILED: &str = "VaultAuthenticationFailed";
+/// Neither the agent token nor the `SPN_SECRET` has been injected in the agent Secret.
+pub const MISSING_SPN_SECRET: &str = "MissingSpnSecret";
+/// The client certificate of the Key Vault service principal expires soon, or has expired.
+pub const CERTIFICATE_EXPIRING: &str = "CertificateExpiring";
+/// A subresource could not be deleted, so the finalizer is kept.
+pub const DELETION_BLOCKED: &str = "DeletionBlocked";
+
+/// An Event equal to one published on the same resource within this period is not published
+/// again, so a failure retried every few seconds does not flood the namespace with Events.
+const REPEAT_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Publishes Kubernetes Events on `CDBootstrap` resources, so the users owning a resource can
+/// follow the operator with `kubectl describe cdbootstrap`.
+pub struct Events {
+    client: Client,
+    reporter: Reporter,
+    /// Time each Event was last published, by resource UID, reason and note.
+    published: Mutex<HashMap<(String, String, String), Instant>>,
+}
+
+impl Events {
+    /// The Events are reported by `cdbootstrap-operator`, with the name of the operator pod from
+    /// the `POD_NAME` environment variable as the instance, if set.
+    pub fn new(client: Client) -> Self {
+        Events {
+            client,
+            reporter: Reporter {
+                controller: String::from("cdbootstrap-operator"),
+                instance: env::var("POD_NAME").ok(),
+            },
+            published: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Publishes an Event of type `Normal`.
+    ///
+    /// # Arguments:
+    /// - `cr` - The resource the Event is about
+    /// - `reason` - CamelCase reason, one of the constants of this module
+    /// - `action` - What the operator did, or failed to do, e.g. `Create`
+    /// - `note` - Human readable description
+    pub async fn normal(&self, cr: &CDBootstrap, reason: &str, action: &str, note: &str) {
+        self.publish(cr, EventType::Normal, reason, action, note)
+            .await
+    }
+
+    /// Publishes an Event of type `Warning`, see `normal`.
+    pub async fn warning(&self, cr: &CDBootstrap, reason: &str, action: &str, note: &str) {
+        self.publish(cr, EventType::Warning, reason, action, note)
+            .await
+    }
+
+    /// Returns true if the Event has not been published on the resource within the
+    /// `REPEAT_INTERVAL`, and records it as published.
+    fn first_within_interval(&self, cr: &CDBootstrap, reason: &str, note: &str) -> bool {
+        let Ok(mut published) = self.published.lock() else {
+            return true;
+        };
+        let now = Instant::now();
+        published.retain(|_, time| now.duration_since(*time) < REPEAT_INTERVAL);
+
+        let key = (
+            cr.meta().uid.clone().unwrap_or_default(),
+            reason.to_owned(),
+            note.to_owned(),
+        );
+        if published.contains_key(&key) {
+            return false;
+        }
+        published.insert(key, now);
+        true
+    }
+
+    /// Events are informational, a failure to publish one is logged and otherwise ignored.
+    async fn publish(
+        &self,
+        cr: &CDBootstrap,
+        type_: EventType,
+        reason: &str,
+        action: &str,
+        note: &str,
+    ) {
+        if !self.first_within_interval(cr, reason, note) {
+            return;
+        }
+        let reference = cr.object_ref(&());
+        let recorder = Recorder::new(self.client.clone(), self.reporter.clone(), reference);
+        let event = Event {
+            type_,
+            reason: reason.to_owned(),
+            note: Some(note.to_owned()),
+            action: action.to_owned(),
+            secondary: None,
+        };
+        if let Err(e) = recorder.publish(event).await {
+            warn!(
+                "Unable to publish {} Event on {}: {}",
+                reason,
+                cr.meta().name.as_deref().unwrap_or_default(),
+                e
+            );
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+use kube::{Api, Client, CustomResourceExt};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+use tracing::*;
+use warp::http::StatusCode;
+use warp::Filter;
+
+use crate::crd::CDBootstrap;
+
+/// Interval the operator checks for the `CDBootstrap` CRD until it is installed.
+const CRD_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Health of the operator, reported on `/healthz` and `/readyz`.
+#[derive(Default)]
+pub struct Health {
+    crd_installed: AtomicBool,
+    synced: AtomicBool,
+    standby: AtomicBool,
+    shutting_down: AtomicBool,
+}
+
+impl Health {
+    /// Records that the `CDBootstrap` CRD is installed in the cluster.
+    pub fn set_crd_installed(&self) {
+        self.crd_installed.store(true, Ordering::SeqCst);
+    }
+
+    /// Records that the watcher of the `CDBootstrap` resources has listed them all once.
+    pub fn set_synced(&self) {
+        self.synced.store(true, Ordering::SeqCst);
+    }
+
+    /// Records whether the replica waits as standby for the leader election Lease. A standby
+    /// does not run the controller, so it is ready without a synced watcher.
+    pub fn set_standby(&self, standby: bool) {
+        self.standby.store(standby, Ordering::SeqCst);
+    }
+
+    /// Records that the operator received a shutdown signal and no longer takes new work.
+    pub fn set_shutting_down(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns `Ok` when the operator is ready to reconcile, else the reason it is not.
+    pub fn readiness(&self) -> Result<(), &'static str> {
+        if self.shutting_down.load(Ordering::SeqCst) {
+            Err("shutting down")
+        } else if !self.crd_installed.load(Ordering::SeqCst) {
+            Err("CRD not installed")
+        } else if !self.standby.load(Ordering::SeqCst) && !self.synced.load(Ordering::SeqCst) {
+            Err("watcher not synced")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Returns the `GET /healthz` and `GET /readyz` routes. The operator is live as long as it
+/// serves requests, it is ready once `Health::readiness` returns `Ok`.
+pub fn routes(
+    health: Arc<Health>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let healthz = warp::path("healthz")
+        .and(warp::get())
+        .map(|| warp::reply::with_status("ok", StatusCode::OK));
+    let readyz = warp::path("readyz")
+        .and(warp::get())
+        .map(move || match health.readiness() {
+            Ok(()) => warp::reply::with_status("ok", StatusCode::OK),
+            Err(reason) => warp::reply::with_status(reason, StatusCode::SERVICE_UNAVAILABLE),
+        });
+    healthz.or(readyz)
+}
+
+/// Serves `/healthz` and `/readyz`, over plain HTTP.
+pub async fn serve(addr: SocketAddr, health: Arc<Health>) {
+    info!("Serving the health endpoints on {}", addr);
+    warp::serve(routes(health)).run(addr).await;
+}
+
+/// Checks for the `CDBootstrap` CRD every `CRD_POLL_INTERVAL` until it is installed, then
+/// records it in `health`.
+pub async fn wait_for_crd(client: Client, health: Arc<Health>) {
+    let api: Api<CustomResourceDefinition> = Api::all(client);
+    let name = CDBootstrap::crd_name();
+    loop {
+        match api.get_opt(name).await {
+            Ok(Some(_)) => {
+                info!("CRD {} is installed", name);
+                health.set_crd_installed();
+                return;
+            }
+            Ok(None) => warn!("CRD {} is not installed", name),
+            Err(e) => warn!("Unable to get CRD {}: {}", name, e),
+        }
+        sleep(CRD_POLL_INTERVAL).await;
+    }
+}
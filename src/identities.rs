@@ -0,0 +1,97 @@
+use std::env;
+use tracing::*;
+
+use crate::error::Error;
+
+const ALLOW_LIST_VAR: &str = "VAULT_IDENTITY_ALLOW_LIST";
+
+/// A namespace allowed to read from a Key Vault as an identity of the operator.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AllowedIdentity {
+    pub namespace: String,
+    /// Client ID of the service principal or managed identity.
+    pub client_id: String,
+    /// URL of the Key Vault, without a trailing `/`.
+    pub vault_url: String,
+}
+
+/// Operator-level allow-list of the identities of the operator that `CDBootstrap` resources may
+/// authenticate against a Key Vault as: its federated service account token, and the managed
+/// identities assigned to the node it runs on. Neither is owned by the namespace of the resource,
+/// so without the allow-list anyone allowed to create a `CDBootstrap` resource could have the
+/// operator read any secret those identities can read, and write it into their own namespace.
+/// Nothing is allowed unless listed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IdentityAllowList {
+    pub allowed: Vec<AllowedIdentity>,
+}
+
+impl IdentityAllowList {
+    /// Reads the allow-list from `VAULT_IDENTITY_ALLOW_LIST` in the environment of the operator:
+    /// comma separated `<namespace>/<clientId>@<vaultUrl>` entries, e.g.
+    /// `team-a/11111111-2222-3333-4444-555555555555@https://kv-team-a.vault.azure.net`.
+    pub fn from_env() -> Self {
+        IdentityAllowList::from_vars(|key| env::var(key).ok())
+    }
+
+    /// Reads the allow-list with the given variable lookup. Malformed entries are logged and
+    /// left out.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let allowed = var(ALLOW_LIST_VAR)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| {
+                let allowed = parse_entry(entry);
+                if allowed.is_none() {
+                    warn!(
+                        "Ignoring {} entry {:?}, expected <namespace>/<clientId>@<vaultUrl>",
+                        ALLOW_LIST_VAR, entry
+                    );
+                }
+                allowed
+            })
+            .collect();
+        IdentityAllowList { allowed }
+    }
+
+    /// Returns `Error::IdentityNotAllowed` unless the namespace may read from the Key Vault as
+    /// the identity with the client ID. Checked before a token is requested for the identity.
+    pub fn check(&self, namespace: &str, client_id: &str, vault_url: &str) -> Result<(), Error> {
+        let vault_url = normalize_url(vault_url);
+        let allowed = self.allowed.iter().any(|allowed| {
+            allowed.namespace == namespace
+                && allowed.client_id.eq_ignore_ascii_case(client_id.trim())
+                && allowed.vault_url == vault_url
+        });
+        if allowed {
+            Ok(())
+        } else {
+            Err(Error::IdentityNotAllowed(format!(
+                "namespace {} is not allowed to read Key Vault {} as client ID {}, see {}",
+                namespace, vault_url, client_id, ALLOW_LIST_VAR
+            )))
+        }
+    }
+}
+
+/// Parses a `<namespace>/<clientId>@<vaultUrl>` entry of the allow-list.
+fn parse_entry(entry: &str) -> Option<AllowedIdentity> {
+    let (namespace, rest) = entry.split_once('/')?;
+    let (client_id, vault_url) = rest.split_once('@')?;
+    let (namespace, client_id, vault_url) = (namespace.trim(), client_id.trim(), vault_url.trim());
+    if namespace.is_empty() || client_id.is_empty() || vault_url.is_empty() {
+        return None;
+    }
+    Some(AllowedIdentity {
+        namespace: namespace.to_owned(),
+        client_id: client_id.to_owned(),
+        vault_url: normalize_url(vault_url),
+    })
+}
+
+/// Key Vault URLs are compared without case and trailing `/`.
+fn normalize_url(url: &str) -> String {
+    url.trim().trim_end_matches('/').to_ascii_lowercase()
+}
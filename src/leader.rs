@@ -0,0 +1,323 @@
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::MicroTime;
+use k8s_openapi::chrono::{DateTime, Utc};
+use kube::api::{ObjectMeta, PostParams};
+use kube::{Api, Client, Error};
+use std::env;
+use std::sync::Mutex;
+use tokio::time::{sleep, timeout_at, Duration, Instant};
+use tracing::*;
+
+use crate::metrics::metrics;
+use crate::subresources::is_conflict;
+
+const DEFAULT_LEASE_NAME: &str = "cdbootstrap-operator";
+const DEFAULT_LEASE_DURATION_SECONDS: u64 = 15;
+
+/// Operator configuration of the leader election, read from the environment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeaderConfig {
+    /// Name of the `coordination.k8s.io/v1` Lease the replicas compete for.
+    pub lease_name: String,
+    pub namespace: String,
+    /// Identity of this replica, recorded as the holder of the Lease.
+    pub identity: String,
+    /// Time a standby waits for the leader to renew the Lease before taking it over.
+    pub lease_duration: Duration,
+    /// Time the leader keeps trying to renew the Lease before it gives up leadership. Shorter
+    /// than the lease duration, so the leader has stopped before a standby takes over.
+    pub renew_deadline: Duration,
+    /// Interval the leader renews the Lease at, and standbys try to acquire it at.
+    pub retry_interval: Duration,
+}
+
+impl LeaderConfig {
+    /// Reads the configuration from the environment of the operator, `None` when leader election
+    /// is disabled:
+    /// - `LEADER_ELECTION` - `true` to enable leader election
+    /// - `LEASE_NAME` - Name of the Lease, defaults to `cdbootstrap-operator`
+    /// - `LEASE_NAMESPACE` - Namespace of the Lease, defaults to `POD_NAMESPACE` or `default`
+    /// - `LEASE_DURATION_SECONDS` - Lease duration, defaults to 15. The Lease is renewed every
+    ///   fifth of it, and leadership is given up when it was not renewed within two thirds of it.
+    /// - `POD_NAME` - Identity of the replica, defaults to `HOSTNAME`
+    pub fn from_env() -> Option<Self> {
+        LeaderConfig::from_vars(|key| env::var(key).ok())
+    }
+
+    /// Reads the configuration with the given variable lookup, empty values are ignored.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        let var = |key: &str| {
+            var(key)
+                .map(|value| value.trim().to_owned())
+                .filter(|value| !value.is_empty())
+        };
+
+        if !var("LEADER_ELECTION").is_some_and(|enabled| enabled.eq_ignore_ascii_case("true")) {
+            return None;
+        }
+        let lease_duration = var("LEASE_DURATION_SECONDS")
+            .and_then(|seconds| seconds.parse().ok())
+            .filter(|seconds| *seconds > 0)
+            .unwrap_or(DEFAULT_LEASE_DURATION_SECONDS);
+
+        Some(LeaderConfig {
+            lease_name: var("LEASE_NAME").unwrap_or(String::from(DEFAULT_LEASE_NAME)),
+            namespace: var("LEASE_NAMESPACE")
+                .or_else(|| var("POD_NAMESPACE"))
+                .unwrap_or(String::from("default")),
+            identity: var("POD_NAME")
+                .or_else(|| var("HOSTNAME"))
+                .unwrap_or(String::from(DEFAULT_LEASE_NAME)),
+            lease_duration: Duration::from_secs(lease_duration),
+            renew_deadline: Duration::from_millis(lease_duration * 1000 * 2 / 3),
+            retry_interval: Duration::from_millis(lease_duration * 1000 / 5),
+        })
+    }
+}
+
+/// The Lease as this replica last saw it change, and when by its own clock. The `renewTime`
+/// written by the holder is never compared against the local clock, as the clocks of the replicas
+/// may differ: the Lease expires once it did not change for the lease duration, as measured by
+/// the replica watching it.
+#[derive(Debug, Clone)]
+pub struct Observation {
+    spec: Option<LeaseSpec>,
+    at: Instant,
+}
+
+impl Observation {
+    /// Records the Lease as read at `now`, keeping the time of the previous observation while the
+    /// Lease did not change since.
+    pub fn new(previous: Option<&Observation>, lease: Option<&Lease>, now: Instant) -> Self {
+        let spec = lease.and_then(|lease| lease.spec.clone());
+        match previous {
+            Some(previous) if previous.spec == spec => previous.clone(),
+            _ => Observation { spec, at: now },
+        }
+    }
+
+    /// Returns how long the Lease has been seen unchanged.
+    pub fn unchanged_for(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.at)
+    }
+}
+
+/// Returns true if the Lease has no holder, or it was not renewed within the lease duration:
+/// it did not change for longer than that since this replica saw it change.
+pub fn expired(lease: &Lease, unchanged_for: Duration) -> bool {
+    let Some(spec) = &lease.spec else {
+        return true;
+    };
+    if spec
+        .holder_identity
+        .as_deref()
+        .unwrap_or_default()
+        .is_empty()
+    {
+        return true;
+    }
+    match spec.lease_duration_seconds {
+        Some(seconds) => unchanged_for > Duration::from_secs(seconds.max(0) as u64),
+        None => true,
+    }
+}
+
+/// Returns the Lease claimed for `config.identity`, or `None` when another replica holds it.
+/// Taking over the Lease from another holder sets the acquire time and counts a transition.
+/// `unchanged_for` is how long this replica has seen the Lease unchanged, see `Observation`.
+pub fn claim(
+    lease: Option<&Lease>,
+    unchanged_for: Duration,
+    config: &LeaderConfig,
+    now: DateTime<Utc>,
+) -> Option<Lease> {
+    let mut spec = lease
+        .and_then(|lease| lease.spec.clone())
+        .unwrap_or_default();
+    let held = spec.holder_identity.as_deref() == Some(config.identity.as_str());
+    if !held && lease.is_some_and(|lease| !expired(lease, unchanged_for)) {
+        return None;
+    }
+
+    if !held {
+        spec.acquire_time = Some(MicroTime(now));
+        if lease.is_some() {
+            spec.lease_transitions = Some(spec.lease_transitions.unwrap_or_default() + 1);
+        }
+    }
+    spec.holder_identity = Some(config.identity.clone());
+    spec.lease_duration_seconds = Some(config.lease_duration.as_secs() as i32);
+    spec.renew_time = Some(MicroTime(now));
+    spec.lease_transitions = Some(spec.lease_transitions.unwrap_or_default());
+
+    Some(Lease {
+        metadata: match lease {
+            Some(lease) => lease.metadata.clone(),
+            None => ObjectMeta {
+                name: Some(config.lease_name.clone()),
+                namespace: Some(config.namespace.clone()),
+                ..ObjectMeta::default()
+            },
+        },
+        spec: Some(spec),
+    })
+}
+
+/// Returns true if a create failed because the resource already exists.
+fn already_exists(error: &kube::Error) -> bool {
+    matches!(error, kube::Error::Api(response) if response.reason == "AlreadyExists")
+}
+
+/// Leader election of the operator replicas on a `coordination.k8s.io/v1` Lease. Only the
+/// replica holding the Lease runs the controller, the others wait as standby and take over once
+/// the leader stops renewing it.
+pub struct LeaderElection {
+    api: Api<Lease>,
+    config: LeaderConfig,
+    observed: Mutex<Option<Observation>>,
+}
+
+impl LeaderElection {
+    pub fn new(client: Client, config: LeaderConfig) -> Self {
+        LeaderElection {
+            api: Api::namespaced(client, &config.namespace),
+            config,
+            observed: Mutex::new(None),
+        }
+    }
+
+    pub fn identity(&self) -> &str {
+        &self.config.identity
+    }
+
+    /// Acquires or renews the Lease, returns false when another replica holds it. The Lease is
+    /// replaced at the read `resourceVersion`, so of two replicas racing for it only one wins.
+    pub async fn try_acquire(&self) -> Result<bool, Error> {
+        let lease = self.api.get_opt(&self.config.lease_name).await?;
+        let unchanged_for = self.observe(lease.as_ref());
+        let Some(claimed) = claim(lease.as_ref(), unchanged_for, &self.config, Utc::now()) else {
+            return Ok(false);
+        };
+        let written = match lease {
+            Some(_) => {
+                self.api
+                    .replace(&self.config.lease_name, &PostParams::default(), &claimed)
+                    .await
+            }
+            None => self.api.create(&PostParams::default(), &claimed).await,
+        };
+        match written {
+            Ok(written) => {
+                self.observe(Some(&written));
+                Ok(true)
+            }
+            // Another replica wrote or created the Lease first
+            Err(e) if is_conflict(&e) || already_exists(&e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Records the read Lease, returns how long this replica has seen it unchanged.
+    fn observe(&self, lease: Option<&Lease>) -> Duration {
+        let now = Instant::now();
+        let mut observed = self.observed.lock().unwrap();
+        let observation = Observation::new(observed.as_ref(), lease, now);
+        let unchanged_for = observation.unchanged_for(now);
+        *observed = Some(observation);
+        unchanged_for
+    }
+
+    /// Waits as standby until this replica holds the Lease.
+    pub async fn acquire(&self) {
+        metrics().leader(self.identity(), false);
+        info!(
+            "Waiting for Lease {}/{} as {}",
+            self.config.namespace,
+            self.config.lease_name,
+            self.identity()
+        );
+        loop {
+            match self.try_acquire().await {
+                Ok(true) => break,
+                Ok(false) => debug!("Lease {} held by another replica", self.config.lease_name),
+                Err(e) => warn!("Unable to acquire Lease {}: {}", self.config.lease_name, e),
+            }
+            sleep(self.config.retry_interval).await;
+        }
+        metrics().leader(self.identity(), true);
+        info!(
+            "Acquired Lease {}/{}, {} is the leader",
+            self.config.namespace,
+            self.config.lease_name,
+            self.identity()
+        );
+    }
+
+    /// Renews the Lease every retry interval, returns once leadership is lost: another replica
+    /// took the Lease, or it could not be renewed within the renew deadline. A slow renewal is
+    /// cut off at the deadline as well, so the leader returns before a standby can take over.
+    pub async fn hold(&self) {
+        let mut deadline = Instant::now() + self.config.renew_deadline;
+        loop {
+            let renewal = async {
+                sleep(self.config.retry_interval).await;
+                // The Lease is renewed as of the start of the request
+                let attempt = Instant::now();
+                (attempt, self.try_acquire().await)
+            };
+            match timeout_at(deadline, renewal).await {
+                Ok((attempt, Ok(true))) => deadline = attempt + self.config.renew_deadline,
+                Ok((_, Ok(false))) => break,
+                Ok((_, Err(e))) => warn!("Unable to renew Lease {}: {}", self.config.lease_name, e),
+                Err(_) => {
+                    warn!(
+                        "Lease {} not renewed within {:?}",
+                        self.config.lease_name, self.config.renew_deadline
+                    );
+                    break;
+                }
+            }
+        }
+        metrics().leader(self.identity(), false);
+        warn!(
+            "Lost Lease {}/{}, {} is no longer the leader",
+            self.config.namespace,
+            self.config.lease_name,
+            self.identity()
+        );
+    }
+
+    /// Gives up the Lease if this replica holds it, so a standby takes over right away instead
+    /// of after the lease duration.
+    pub async fn release(&self) {
+        let lease = match self.api.get_opt(&self.config.lease_name).await {
+            Ok(Some(lease)) => lease,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Unable to release Lease {}: {}", self.config.lease_name, e);
+                return;
+            }
+        };
+        let mut released = lease.clone();
+        match released.spec.as_mut() {
+            Some(spec) if spec.holder_identity.as_deref() == Some(self.identity()) => {
+                spec.holder_identity = None;
+            }
+            _ => return,
+        }
+        match self
+            .api
+            .replace(&self.config.lease_name, &PostParams::default(), &released)
+            .await
+        {
+            Ok(_) => {
+                metrics().leader(self.identity(), false);
+                info!(
+                    "Released Lease {}/{}",
+                    self.config.namespace, self.config.lease_name
+                );
+            }
+            Err(e) => warn!("Unable to release Lease {}: {}", self.config.lease_name, e),
+        }
+    }
+}
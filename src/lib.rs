@@ -1,5 +1,20 @@
+pub mod autoscaler;
+pub mod backoff;
+pub mod conversion;
 pub mod crd;
+pub mod credential;
+pub mod defaults;
+pub mod drift;
+pub mod error;
+pub mod events;
 pub mod finalizer;
+pub mod health;
+pub mod identities;
+pub mod leader;
+pub mod metrics;
+pub mod provider;
+pub mod quantity;
+pub mod service_tags;
 pub mod status;
 pub mod subresources;
 pub mod vault;
@@ -1,19 +1,56 @@
-use cdbootstrap::crd::CDBootstrap;
+use cdbootstrap::autoscaler::{self, AzureDevOpsClient};
+use cdbootstrap::backoff::{self, Backoff};
+use cdbootstrap::conversion;
+use cdbootstrap::crd::{
+    AutoscalingStatus, CDBootstrap, CDBootstrapStatus, ConditionStatus, Phase, Provider, VaultAuth,
+};
+use cdbootstrap::defaults::{AgentDefaults, AgentImage};
+use cdbootstrap::drift::Subresource;
+use cdbootstrap::error::{Error, RetryPolicy};
+use cdbootstrap::events::{self, Events};
 use cdbootstrap::finalizer;
+use cdbootstrap::health::{self, Health};
+use cdbootstrap::identities::IdentityAllowList;
+use cdbootstrap::leader::{LeaderConfig, LeaderElection};
+use cdbootstrap::metrics::{self, metrics};
+use cdbootstrap::provider::{self, CiProvider};
+use cdbootstrap::service_tags::{self, ServiceTag, ServiceTagConfig, ServiceTags};
 use cdbootstrap::status;
 use cdbootstrap::subresources::{Agent, AgentConfig, AgentPolicy, AgentSecret};
 use cdbootstrap::vault::*;
 
 use anyhow::Result;
+use futures::future::{self, FutureExt};
 use futures::join;
 use futures::stream::StreamExt;
-use kube::runtime::watcher::Config;
+use garde::Validate;
+use k8s_openapi::api::apps::v1::Deployment;
+use k8s_openapi::api::core::v1::{ConfigMap, Secret};
+use k8s_openapi::api::networking::v1::NetworkPolicy;
+use k8s_openapi::chrono::{self, SecondsFormat, Utc};
+use kube::runtime::watcher::{watcher, Config};
+use kube::runtime::{predicates, reflector, WatchStreamExt};
 use kube::{client::Client, runtime::controller::Action, runtime::Controller, Api};
 use kube::{Resource, ResourceExt};
+use std::env;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Instant;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::oneshot;
 use tokio::time::Duration;
 use tracing::*;
 
+/// Label selector of the subresources of all `CDBootstrap` resources, see `agent_labels`.
+const OWNED_LABELS: &str = "app.kubernetes.io/managed-by=cdbootstrap-operator";
+
+/// Days before the client certificate of the Key Vault service principal expires that it is
+/// reported as expiring, see `observe_certificate`.
+const CERTIFICATE_RENEWAL_DAYS: i64 = 30;
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
@@ -23,9 +60,95 @@ async fn main() {
         .await
         .expect("Expected a valid KUBECONFIG environment variable.");
 
+    // Serve the conversion webhook for `v1beta1` resources when a TLS certificate is mounted.
+    // Without it the API server can not convert between the versions: `v1beta1` requests fail, as
+    // does reading resources still stored as `v1beta1`, also by the watcher of this operator.
+    let cert_path = env::var("WEBHOOK_TLS_CERT").unwrap_or(String::from("/certs/tls.crt"));
+    let key_path = env::var("WEBHOOK_TLS_KEY").unwrap_or(String::from("/certs/tls.key"));
+    if Path::new(&cert_path).exists() && Path::new(&key_path).exists() {
+        let port: u16 = env::var("WEBHOOK_PORT")
+            .ok()
+            .and_then(|port| port.parse().ok())
+            .unwrap_or(8443);
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        tokio::spawn(conversion::serve(addr, cert_path, key_path));
+    } else {
+        warn!(
+            "No TLS certificate found at {}, the conversion webhook is not served and v1beta1 resources can not be converted",
+            cert_path
+        );
+    }
+
+    // Serve the Prometheus metrics of the operator
+    let metrics_port: u16 = env::var("METRICS_PORT")
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(8080);
+    tokio::spawn(metrics::serve(SocketAddr::from((
+        [0, 0, 0, 0],
+        metrics_port,
+    ))));
+
+    // Serve the liveness and readiness probes. The operator is ready once the CRD is installed
+    // and the watcher synced.
+    let health = Arc::new(Health::default());
+    let health_port: u16 = env::var("HEALTH_PORT")
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(8081);
+    tokio::spawn(health::serve(
+        SocketAddr::from(([0, 0, 0, 0], health_port)),
+        health.clone(),
+    ));
+    tokio::spawn(health::wait_for_crd(kubeconfig.clone(), health.clone()));
+
+    // Keep the `AzureDevOps` service tag up to date when an Azure Service Tags document is
+    // configured. Without it the built-in IP ranges of Azure DevOps are allowed.
+    let service_tags = Arc::new(ServiceTags::default());
+    match ServiceTagConfig::from_env() {
+        Some(config) => {
+            tokio::spawn(service_tags::refresh(
+                kubeconfig.clone(),
+                config,
+                service_tags.clone(),
+            ));
+        }
+        None => info!("No Azure Service Tags document configured, using the built-in IP ranges"),
+    }
+
+    // With leader election enabled, only the replica holding the Lease runs the controller. The
+    // others wait as standby, until the leader stops renewing the Lease.
+    let shutdown = on_task(shutdown_signal(health.clone())).shared();
+    let election = LeaderConfig::from_env()
+        .map(|config| Arc::new(LeaderElection::new(kubeconfig.clone(), config)));
+    if let Some(election) = &election {
+        health.set_standby(true);
+        tokio::select! {
+            _ = election.acquire() => health.set_standby(false),
+            _ = shutdown.clone() => return,
+        }
+    }
+    let leader = election
+        .as_ref()
+        .map(|election| election.identity().to_owned());
+    let lost_leadership = on_task({
+        let election = election.clone();
+        async move {
+            match election {
+                Some(election) => election.hold().await,
+                None => future::pending().await,
+            }
+        }
+    });
+
     // Preparation of resources used by the `kube_runtime::Controller`
     let crd_api: Api<CDBootstrap> = Api::all(kubeconfig.clone());
-    let context: Arc<ContextData> = Arc::new(ContextData::new(kubeconfig.clone()));
+    let context: Arc<ContextData> = Arc::new(ContextData::new(
+        kubeconfig.clone(),
+        AgentDefaults::from_env(),
+        IdentityAllowList::from_env(),
+        service_tags,
+    ));
 
     // The controller comes from the `kube_runtime` crate and manages the reconciliation process.
     // It requires the following information:
@@ -33,7 +156,40 @@ async fn main() {
     // - `kube::runtime::watcher::Config` can be adjusted for precise filtering of `CDBootstrap` resources before the actual reconciliation, e.g. by label,
     // - `reconcile` function with reconciliation logic to be called each time a resource of `CDBootstrap` kind is created/updated/deleted,
     // - `on_error` function to call whenever reconciliation fails.
-    Controller::new(crd_api.clone(), Config::default())
+    // Only a change of the specification, or the deletion, of a `CDBootstrap` resource triggers a
+    // reconciliation. The status written by each reconciliation does not, otherwise a failing
+    // resource would be reconciled again right away instead of after its backoff.
+    // The subresources are watched as well, so a change to one of them reconciles the owning
+    // `CDBootstrap` resource right away. Only the subresources labelled by this operator are
+    // watched, to not cache every Secret in the cluster.
+    let (reader, writer) = reflector::store();
+    let specs = watcher(crd_api.clone(), Config::default())
+        .default_backoff()
+        .reflect(writer)
+        .applied_objects()
+        .predicate_filter(predicates::generation);
+    let owned = Config::default().labels(OWNED_LABELS);
+    let controller = Controller::for_stream(specs, reader)
+        .owns(Api::<Deployment>::all(kubeconfig.clone()), owned.clone())
+        .owns(Api::<ConfigMap>::all(kubeconfig.clone()), owned.clone())
+        .owns(Api::<Secret>::all(kubeconfig.clone()), owned.clone())
+        .owns(Api::<NetworkPolicy>::all(kubeconfig.clone()), owned);
+
+    // The operator is ready once the watcher listed all `CDBootstrap` resources
+    let store = controller.store();
+    let synced = health.clone();
+    tokio::spawn(async move {
+        if store.wait_until_ready().await.is_ok() {
+            info!("Watcher of the CDBootstrap resources synced");
+            synced.set_synced();
+        }
+    });
+
+    // On SIGTERM the controller stops taking new work and waits for the running reconciliations
+    // to finish before `main` returns. The logs of the controller carry the identity of the
+    // leader.
+    let controller = controller
+        .graceful_shutdown_on(shutdown)
         .run(reconcile, on_error, context)
         .for_each(|reconciliation_result| async move {
             match reconciliation_result {
@@ -45,13 +201,64 @@ async fn main() {
                 }
             }
         })
-        .await;
+        .instrument(info_span!("controller", leader = leader.as_deref()));
+    // When leadership is lost, a standby may take over right after the renew deadline, so the
+    // running reconciliations are aborted instead of drained
+    tokio::select! {
+        _ = controller => {}
+        _ = lost_leadership => {
+            error!("Lost leadership, aborting the running reconciliations");
+            return;
+        }
+    }
+
+    // Hand the Lease over to a standby right away
+    if let Some(election) = election {
+        election.release().await;
+    }
+}
+
+/// Resolves once the operator receives SIGTERM or SIGINT, and marks the operator as not ready.
+async fn shutdown_signal(health: Arc<Health>) {
+    let mut terminate =
+        signal(SignalKind::terminate()).expect("Expected a SIGTERM signal handler.");
+    tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+    }
+    info!("Shutting down, waiting for the running reconciliations to finish");
+    health.set_shutting_down();
+}
+
+/// Runs the future on a separate task and returns a future that resolves once it finished. The
+/// controller requires a `Sync` shutdown trigger, which the signal and Lease futures are not.
+fn on_task(
+    future: impl Future<Output = ()> + Send + 'static,
+) -> Pin<Box<dyn Future<Output = ()> + Send + Sync>> {
+    let (sender, receiver) = oneshot::channel::<()>();
+    tokio::spawn(async move {
+        future.await;
+        let _ = sender.send(());
+    });
+    Box::pin(async move {
+        let _ = receiver.await;
+    })
 }
 
 /// Context injected with each `reconcile` and `on_error` method invocation.
 struct ContextData {
     /// Kubernetes client to make Kubernetes API requests with. Required for K8S resource management.
     client: Client,
+    /// Operator-level defaults of the agent Deployment.
+    defaults: AgentDefaults,
+    /// Namespaces allowed to read from a Key Vault as an identity of the operator.
+    identities: IdentityAllowList,
+    /// The `AzureDevOps` service tag, refreshed from the Azure Service Tags document.
+    service_tags: Arc<ServiceTags>,
+    /// Publishes Kubernetes Events on the `CDBootstrap` resources.
+    events: Events,
+    /// Backoff of the failing reconciliations of each `CDBootstrap` resource.
+    backoff: Backoff,
 }
 
 impl ContextData {
@@ -60,8 +267,23 @@ impl ContextData {
     /// # Arguments:
     /// - `client`: A Kubernetes client to make Kubernetes REST API requests with. Resources
     /// will be created and deleted with this client.
-    pub fn new(client: Client) -> Self {
-        ContextData { client }
+    /// - `defaults`: Defaults applied to the agent Deployment when omitted in the specification.
+    /// - `identities`: Namespaces allowed to authenticate as an identity of the operator.
+    /// - `service_tags`: Service tag of the Azure DevOps IP ranges allowed by the NetworkPolicy.
+    pub fn new(
+        client: Client,
+        defaults: AgentDefaults,
+        identities: IdentityAllowList,
+        service_tags: Arc<ServiceTags>,
+    ) -> Self {
+        ContextData {
+            events: Events::new(client.clone()),
+            backoff: Backoff::default(),
+            client,
+            defaults,
+            identities,
+            service_tags,
+        }
     }
 }
 
@@ -77,7 +299,84 @@ enum CDBootstrapAction {
     NoOp,
 }
 
+impl CDBootstrapAction {
+    /// Name of the action in the metrics.
+    fn as_str(&self) -> &'static str {
+        match self {
+            CDBootstrapAction::Create => "Create",
+            CDBootstrapAction::Update => "Update",
+            CDBootstrapAction::Delete => "Delete",
+            CDBootstrapAction::NoOp => "NoOp",
+        }
+    }
+}
+
+/// Reconciles the `CDBootstrap` resource and records the action taken, its result and duration
+/// in the metrics. A failure is recorded in the status of the resource, with its next retry.
 async fn reconcile(cr: Arc<CDBootstrap>, context: Arc<ContextData>) -> Result<Action, Error> {
+    // A failing resource triggered by one of its subresources waits for its next retry
+    if cr.meta().deletion_timestamp.is_none() {
+        if let Some(remaining) = backoff::pending(&cr, Utc::now()) {
+            return Ok(Action::requeue(remaining));
+        }
+    }
+
+    let start = Instant::now();
+    // Until an action is decided on, the resource is being validated
+    let mut action = "Validate";
+    let result = reconcile_action(cr.clone(), context.clone(), &mut action).await;
+    metrics().reconciled(action, result.is_ok(), start.elapsed());
+
+    match &result {
+        Ok(_) => context.backoff.reset(&backoff_key(&cr)),
+        Err(error) => report_failure(&cr, error, &context).await,
+    }
+    result
+}
+
+/// Key of the resource in the `Backoff` of the context.
+fn backoff_key(cr: &CDBootstrap) -> String {
+    format!(
+        "{}/{}",
+        cr.namespace().unwrap_or(String::from("default")),
+        cr.name_any()
+    )
+}
+
+/// Marks the status of the resource as not ready after a failed reconciliation, with the reason
+/// of the error. Errors that are retried count as a failed attempt in the backoff, and the next
+/// retry is recorded.
+async fn report_failure(cr: &CDBootstrap, error: &Error, context: &ContextData) {
+    let (phase, retry) = match error.retry_policy() {
+        RetryPolicy::AwaitChange => (Phase::Failed, None),
+        RetryPolicy::Backoff => (
+            Phase::Degraded,
+            Some(context.backoff.failed(&backoff_key(cr))),
+        ),
+    };
+    let name = cr.name_any();
+    let namespace = cr.namespace().unwrap_or(String::from("default"));
+    match status::fail(
+        context.client.clone(),
+        &name,
+        &namespace,
+        phase,
+        error.reason(),
+        &error.to_string(),
+        retry.map(|retry| retry.status()),
+    )
+    .await
+    {
+        Ok(_) => info!("Updated status with reconcile error"),
+        Err(e) => error!("Failed to update status: {:?}", e),
+    }
+}
+
+async fn reconcile_action(
+    cr: Arc<CDBootstrap>,
+    context: Arc<ContextData>,
+    action: &mut &'static str,
+) -> Result<Action, Error> {
     let client: Client = context.client.clone(); // The `Client` is shared -> a clone from the reference is obtained
 
     // The resource of `CDBootstrap` kind is required to have a namespace set. However, it is not guaranteed
@@ -98,10 +397,49 @@ async fn reconcile(cr: Arc<CDBootstrap>, context: Arc<ContextData>) -> Result<Ac
 
     let name = cr.name_any(); // Name of the CDBootstrap resource is used to name the subresources as well.
 
-    let in_desired_state = in_desired_state(client.clone(), &cr, &name, &namespace).await;
+    // Reject an invalid specification before it reaches the subresources or the Key Vault client.
+    // Deletion is always allowed, otherwise an invalid resource could never be removed.
+    if cr.meta().deletion_timestamp.is_none() {
+        if let Err(errors) = cr.spec.validate(&()) {
+            return Err(Error::UserInputError(errors.to_string()));
+        }
+        provider::check(&cr.spec).map_err(Error::UserInputError)?;
+    }
+
+    // The CI system specific parts of the subresources are rendered by the selected provider.
+    let provider = provider::from_spec(&cr.spec);
+    let image = context.defaults.resolve(&cr.spec, provider.as_ref());
+    // The service tag only replaces the built-in IP ranges of Azure Pipelines, `cidrs` in the
+    // specification take precedence.
+    let service_tag = context.service_tags.current().filter(|_| {
+        cr.spec.provider == Provider::AzurePipelines
+            && cr
+                .spec
+                .network_policy
+                .as_ref()
+                .map(|policy| policy.enabled && policy.cidrs.is_empty())
+                .unwrap_or(true)
+    });
+
+    let drifted = drifted_subresources(
+        client.clone(),
+        &cr,
+        &name,
+        &namespace,
+        provider.as_ref(),
+        &image,
+        service_tag.as_deref(),
+    )
+    .await;
+
+    // The status is built up during this reconciliation and written once the action completes.
+    let mut state: CDBootstrapStatus = status::observe(&cr);
+    state.drifted = drifted.iter().map(Subresource::to_string).collect();
 
     // Performs action as decided by the `determine_action` function.
-    return match determine_action(&cr, in_desired_state) {
+    let next = determine_action(&cr, drifted.is_empty());
+    *action = next.as_str();
+    return match next {
         CDBootstrapAction::Create => {
             // Creates a deployment with `n` CDBootstrap service pods, but applies a finalizer first.
             // Finalizer is applied first, as the operator might be shut down and restarted
@@ -116,79 +454,87 @@ async fn reconcile(cr: Arc<CDBootstrap>, context: Arc<ContextData>) -> Result<Ac
                 &name, &namespace
             );
             // Invoke creation of a Kubernetes built-in resource named deployment with `n` CDBootstrap service pods.
-            let (secret_result, config_result, policy_result, agent_result) = join!(
-                AgentSecret::apply(client.clone(), &name, &namespace, &cr),
-                AgentConfig::apply(client.clone(), &name, &namespace, &cr),
-                AgentPolicy::apply(client.clone(), &name, &namespace, &cr),
-                Agent::apply(client.clone(), &name, &namespace, &cr)
-            );
-
-            // Handle the results of each apply operation
-            if let Err(e) = secret_result {
-                eprintln!("Error applying AgentSecret: {:?}", e);
-                status::patch(client.clone(), &name, &namespace, false).await?;
-                return Err(e.into());
-            }
-            if let Err(e) = config_result {
-                eprintln!("Error applying AgentConfig: {:?}", e);
-                status::patch(client.clone(), &name, &namespace, false).await?;
-                return Err(e.into());
-            }
-            if let Err(e) = policy_result {
-                eprintln!("Error applying AgentPolicy: {:?}", e);
-                status::patch(client.clone(), &name, &namespace, false).await?;
-                return Err(e.into());
-            }
-            if let Err(e) = agent_result {
-                eprintln!("Error applying Agent: {:?}", e);
-                status::patch(client.clone(), &name, &namespace, false).await?;
-                return Err(e.into());
+            let result = apply_subresources(
+                client.clone(),
+                &name,
+                &namespace,
+                &cr,
+                provider.as_ref(),
+                &image,
+                service_tag.as_deref(),
+                &Subresource::ALL,
+                &mut state,
+            )
+            .await;
+            if let Err(e) = result {
+                context
+                    .events
+                    .warning(&cr, events::APPLY_FAILED, "Create", &e.to_string())
+                    .await;
+                return Err(e);
             }
 
-            status::patch(client, &name, &namespace, true).await?;
+            state.refresh();
+            status::update(client, &cr, &mut state).await?;
             info!("Created {} subresources in namespace {}", &name, &namespace);
+            context
+                .events
+                .normal(
+                    &cr,
+                    events::CREATED,
+                    "Create",
+                    &format!("Created the {} agent subresources", provider.name()),
+                )
+                .await;
             Ok(Action::requeue(Duration::from_secs(5)))
         }
         CDBootstrapAction::Update => {
             warn!(
-                "{} subresources in namespace {} are not in desired state",
-                &name, &namespace
-            );
-
-            let (secret_result, config_result, policy_result, agent_result) = join!(
-                AgentSecret::apply(client.clone(), &name, &namespace, &cr),
-                AgentConfig::apply(client.clone(), &name, &namespace, &cr),
-                AgentPolicy::apply(client.clone(), &name, &namespace, &cr),
-                Agent::apply(client.clone(), &name, &namespace, &cr)
+                "{} subresources in namespace {} are not in desired state: {}",
+                &name,
+                &namespace,
+                state.drifted.join(", ")
             );
 
-            // Handle the results of each apply operation
-            if let Err(e) = secret_result {
-                eprintln!("Error applying AgentSecret: {:?}", e);
-                status::patch(client.clone(), &name, &namespace, false).await?;
-                return Err(e.into());
-            }
-            if let Err(e) = config_result {
-                eprintln!("Error applying AgentConfig: {:?}", e);
-                status::patch(client.clone(), &name, &namespace, false).await?;
-                return Err(e.into());
-            }
-            if let Err(e) = policy_result {
-                eprintln!("Error applying AgentPolicy: {:?}", e);
-                status::patch(client.clone(), &name, &namespace, false).await?;
-                return Err(e.into());
-            }
-            if let Err(e) = agent_result {
-                eprintln!("Error applying Agent: {:?}", e);
-                status::patch(client.clone(), &name, &namespace, false).await?;
-                return Err(e.into());
+            // Only the drifted subresources are applied again
+            let result = apply_subresources(
+                client.clone(),
+                &name,
+                &namespace,
+                &cr,
+                provider.as_ref(),
+                &image,
+                service_tag.as_deref(),
+                &drifted,
+                &mut state,
+            )
+            .await;
+            if let Err(e) = result {
+                context
+                    .events
+                    .warning(&cr, events::APPLY_FAILED, "Update", &e.to_string())
+                    .await;
+                return Err(e);
             }
 
-            status::patch(client.clone(), &name, &namespace, true).await?;
+            state.refresh();
+            status::update(client.clone(), &cr, &mut state).await?;
             info!(
                 "Updated {} subresources in namespace {} to desired state",
                 &name, &namespace
             );
+            context
+                .events
+                .normal(
+                    &cr,
+                    events::UPDATED,
+                    "Update",
+                    &format!(
+                        "Applied the drifted subresources again: {}",
+                        state.drifted.join(", ")
+                    ),
+                )
+                .await;
             Ok(Action::requeue(Duration::from_secs(10)))
         }
         CDBootstrapAction::Delete => {
@@ -198,11 +544,40 @@ async fn reconcile(cr: Arc<CDBootstrap>, context: Arc<ContextData>) -> Result<Ac
                 "Deleting {} subresources in namespace {}",
                 &name, &namespace
             );
+            state.phase = Phase::Terminating;
             //First, delete the deployment. If there is any error deleting the deployment, it is
             // automatically converted into `Error` defined in this crate and the reconciliation is ended
             // with that error.
             // Note: A more advanced implementation would check for the Deployment's existence.
 
+            // Remove the agents from the CI system while the token is still in the agent Secret.
+            // A failure is only logged, the agents are deleted from the cluster regardless. So is
+            // a token that can not be read, e.g. as the agent Secret is already gone, otherwise
+            // the resource could never be deleted.
+            let token =
+                AgentSecret::get_value(client.clone(), &name, &namespace, provider.token_key())
+                    .await
+                    .unwrap_or_else(|e| {
+                        warn!(
+                            "Unable to read the {} of {}, not unregistering its {} agents: {}",
+                            provider.token_key(),
+                            &name,
+                            provider.name(),
+                            e
+                        );
+                        String::new()
+                    });
+            if !token.is_empty() {
+                if let Err(e) = provider.unregister(&token).await {
+                    warn!(
+                        "Unable to unregister {} agents of {}: {}",
+                        provider.name(),
+                        &name,
+                        e
+                    );
+                }
+            }
+
             let (policy_result, config_result, secret_result, deployment_result) = join!(
                 AgentPolicy::delete(client.clone(), &name, &namespace),
                 AgentConfig::delete(client.clone(), &name, &namespace),
@@ -210,55 +585,653 @@ async fn reconcile(cr: Arc<CDBootstrap>, context: Arc<ContextData>) -> Result<Ac
                 Agent::delete(client.clone(), &name, &namespace),
             );
 
-            // Handle the results of each apply operation
-            if let Err(e) = policy_result {
-                eprintln!("Error deleting AgentPolicy: {:?}", e);
-                status::patch(client.clone(), &name, &namespace, false).await?;
-                return Err(e.into());
-            }
-            if let Err(e) = config_result {
-                eprintln!("Error deleting AgentConfig: {:?}", e);
-                status::patch(client.clone(), &name, &namespace, false).await?;
-                return Err(e.into());
-            }
-            if let Err(e) = secret_result {
-                eprintln!("Error deleting AgentSecret: {:?}", e);
-                status::patch(client.clone(), &name, &namespace, false).await?;
-                return Err(e.into());
-            }
-            if let Err(e) = deployment_result {
-                eprintln!("Error deleting Agent: {:?}", e);
-                status::patch(client.clone(), &name, &namespace, false).await?;
-                return Err(e.into());
+            // Handle the results of each delete operation
+            for (kind, result) in [
+                ("AgentPolicy", policy_result),
+                ("AgentConfig", config_result),
+                ("AgentSecret", secret_result),
+                ("Agent", deployment_result),
+            ] {
+                if let Err(e) = result {
+                    error!(
+                        "Error deleting {} {} in namespace {}: {:?}",
+                        kind, name, namespace, e
+                    );
+                    let message = format!("Error deleting {}: {}", kind, e);
+                    state.set_condition(
+                        status::READY,
+                        ConditionStatus::False,
+                        "DeletionFailed",
+                        &message,
+                    );
+                    context
+                        .events
+                        .warning(&cr, events::DELETION_BLOCKED, "Delete", &message)
+                        .await;
+                    status::update(client.clone(), &cr, &mut state).await?;
+                    return Err(e);
+                }
             }
             // Once the deployment is successfully removed, remove the finalizer to make it possible
             // for Kubernetes to delete the `CDBootstrap` resource.
             finalizer::delete(client, &name, &namespace).await?;
+            metrics().forget(&namespace, &name);
             Ok(Action::await_change()) // Makes no sense to delete after a successful delete, as the resource is gone
         }
         // The resource is already in desired state, do nothing and re-check after 10 seconds
         CDBootstrapAction::NoOp => {
             status::print(client.clone(), &name, &namespace).await?;
-            //temp check azure vault functions
-            run(client, &name, &namespace, &cr).await;
-            Ok(Action::requeue(Duration::from_secs(20)))
+            // Collect the agent token from the vault when it has not been injected yet, or the
+            // vault secret was rotated
+            observe_certificate(client.clone(), &namespace, &cr, &context.events, &mut state).await;
+            let sync = run(
+                client.clone(),
+                &name,
+                &namespace,
+                &cr,
+                provider.as_ref(),
+                &context.identities,
+            )
+            .await;
+            observe_vault(&sync, &mut state);
+            publish_vault_event(&context.events, &cr, &sync).await;
+            autoscale(
+                client.clone(),
+                &name,
+                &namespace,
+                &cr,
+                provider.as_ref(),
+                &image,
+                &mut state,
+            )
+            .await;
+            observe_agents(client.clone(), &name, &namespace, &cr, &mut state).await;
+
+            state.refresh();
+            status::update(client, &cr, &mut state).await?;
+            // A failed vault pass is retried with the backoff of its error, so bad credentials
+            // do not hit Azure AD every 20 seconds
+            match sync.into_error() {
+                Some(error) => Err(error),
+                None => Ok(Action::requeue(Duration::from_secs(20))),
+            }
         }
     };
 }
 
-// check if all objects are in a desired state
-// !!!!! for now only the agent replica number is checked !!!!!!!!
-// !!!!! 2 times to check the iterator construct !!!!!!!!!!!!!!!!!
-async fn in_desired_state(client: Client, cr: &CDBootstrap, name: &str, namespace: &str) -> bool {
-    let results = vec![
-        Agent::desired_state(client.clone(), &cr, &name, &namespace)
-            .await
-            .unwrap_or(false),
-        Agent::desired_state(client.clone(), &cr, &name, &namespace) // example tot test iterator.all
-            .await
-            .unwrap_or(false),
-    ];
-    results.iter().all(|&result| result)
+/// Applies the given subresources of the `CDBootstrap` resource and records the outcome of each
+/// of them as a condition. On failure the status is written before the error is returned, so the
+/// failing subresource can be identified from the resource status.
+#[allow(clippy::too_many_arguments)]
+async fn apply_subresources(
+    client: Client,
+    name: &str,
+    namespace: &str,
+    cr: &CDBootstrap,
+    provider: &dyn CiProvider,
+    image: &AgentImage,
+    service_tag: Option<&ServiceTag>,
+    subresources: &[Subresource],
+    state: &mut CDBootstrapStatus,
+) -> Result<(), Error> {
+    let applies = |subresource: Subresource| subresources.contains(&subresource);
+    let (secret_result, config_result, policy_result, agent_result) = join!(
+        only_if(
+            applies(Subresource::Secret),
+            AgentSecret::apply(client.clone(), name, namespace, cr)
+        ),
+        only_if(
+            applies(Subresource::ConfigMap),
+            AgentConfig::apply(client.clone(), name, namespace, cr, provider)
+        ),
+        only_if(
+            applies(Subresource::NetworkPolicy),
+            AgentPolicy::apply(client.clone(), name, namespace, cr, provider, service_tag)
+        ),
+        only_if(
+            applies(Subresource::Deployment),
+            Agent::apply(client.clone(), name, namespace, cr, provider, image)
+        )
+    );
+
+    // Handle the results of each apply operation
+    let mut failure: Option<Error> = None;
+    match secret_result {
+        None => {}
+        Some(Ok(_)) => state.set_condition(
+            status::SECRETS_RESOLVED,
+            ConditionStatus::False,
+            "TokenPending",
+            &format!(
+                "Waiting for the {} to be injected or collected from the vault",
+                provider.token_key()
+            ),
+        ),
+        Some(Err(e)) => {
+            error!(
+                "Error applying AgentSecret {} in namespace {}: {:?}",
+                name, namespace, e
+            );
+            state.set_condition(
+                status::SECRETS_RESOLVED,
+                ConditionStatus::False,
+                failure_reason(&e, "SecretApplyFailed"),
+                &e.to_string(),
+            );
+            failure = failure.or(Some(e));
+        }
+    }
+    if let Some(Err(e)) = config_result {
+        error!(
+            "Error applying AgentConfig {} in namespace {}: {:?}",
+            name, namespace, e
+        );
+        state.set_condition(
+            status::AGENTS_AVAILABLE,
+            ConditionStatus::False,
+            failure_reason(&e, "ConfigMapApplyFailed"),
+            &e.to_string(),
+        );
+        failure = failure.or(Some(e));
+    }
+    match policy_result {
+        None => {}
+        Some(Ok(Some(_))) => {
+            let message = match service_tag {
+                Some(tag) => format!(
+                    "Egress NetworkPolicy applied to the agent pods, allowing service tag {} with change number {}",
+                    tag.name, tag.change_number
+                ),
+                None => String::from("Egress NetworkPolicy applied to the agent pods"),
+            };
+            state.set_condition(
+                status::NETWORK_POLICY_APPLIED,
+                ConditionStatus::True,
+                "Applied",
+                &message,
+            );
+            state.service_tag = service_tag.map(ServiceTag::status);
+        }
+        Some(Ok(None)) => {
+            state.set_condition(
+                status::NETWORK_POLICY_APPLIED,
+                ConditionStatus::Unknown,
+                "Disabled",
+                "The egress NetworkPolicy is disabled in the specification",
+            );
+            state.service_tag = None;
+        }
+        Some(Err(e)) => {
+            error!(
+                "Error applying AgentPolicy {} in namespace {}: {:?}",
+                name, namespace, e
+            );
+            state.set_condition(
+                status::NETWORK_POLICY_APPLIED,
+                ConditionStatus::False,
+                failure_reason(&e, "NetworkPolicyApplyFailed"),
+                &e.to_string(),
+            );
+            failure = failure.or(Some(e));
+        }
+    }
+    if let Some(Err(e)) = agent_result {
+        error!(
+            "Error applying Agent {} in namespace {}: {:?}",
+            name, namespace, e
+        );
+        state.set_condition(
+            status::AGENTS_AVAILABLE,
+            ConditionStatus::False,
+            failure_reason(&e, "DeploymentApplyFailed"),
+            &e.to_string(),
+        );
+        failure = failure.or(Some(e));
+    }
+
+    if let Some(e) = failure {
+        state.phase = Phase::Degraded;
+        state.refresh();
+        status::update(client, cr, state).await?;
+        return Err(e);
+    }
+
+    observe_agents(client, name, namespace, cr, state).await;
+    Ok(())
+}
+
+/// Awaits `future` only if `enabled`, so the subresources in the desired state are skipped.
+async fn only_if<T>(enabled: bool, future: impl Future<Output = T>) -> Option<T> {
+    if enabled {
+        Some(future.await)
+    } else {
+        None
+    }
+}
+
+/// Returns the reason of the condition of a subresource that could not be applied: the reason of
+/// the typed error, e.g. `FieldConflict` for a field another field manager set to a different
+/// value, or the given reason for errors reported by the API server.
+fn failure_reason(error: &Error, reason: &'static str) -> &'static str {
+    match error {
+        Error::KubeError { .. } => reason,
+        error => error.reason(),
+    }
+}
+
+/// Records the number of ready agent pods and the `AgentsAvailable` condition.
+async fn observe_agents(
+    client: Client,
+    name: &str,
+    namespace: &str,
+    cr: &CDBootstrap,
+    state: &mut CDBootstrapStatus,
+) {
+    // With autoscaling the desired number of agents is the last scale decision
+    let desired = state
+        .autoscaling
+        .as_ref()
+        .filter(|_| cr.spec.agent.autoscaling.is_some())
+        .map(|autoscaling| autoscaling.desired_replicas)
+        .unwrap_or_else(|| autoscaler::replicas(cr));
+    match Agent::ready_replicas(client, name, namespace).await {
+        Ok(ready) => {
+            state.ready_replicas = Some(ready);
+            metrics().agents(namespace, name, desired, ready);
+            let message = format!("{}/{} agents ready", ready, desired);
+            if ready >= desired {
+                state.set_condition(
+                    status::AGENTS_AVAILABLE,
+                    ConditionStatus::True,
+                    "MinimumReplicasAvailable",
+                    &message,
+                );
+            } else {
+                state.set_condition(
+                    status::AGENTS_AVAILABLE,
+                    ConditionStatus::False,
+                    "ReplicasUnavailable",
+                    &message,
+                );
+            }
+        }
+        Err(e) => {
+            state.ready_replicas = None;
+            state.set_condition(
+                status::AGENTS_AVAILABLE,
+                ConditionStatus::Unknown,
+                "DeploymentUnavailable",
+                &e.to_string(),
+            );
+        }
+    }
+}
+
+/// Scales the agents on the queued and running jobs of the agent pool when autoscaling is
+/// enabled, and records the decision in the status. The job requests are read with the personal
+/// access token of the agents, so nothing happens until the token is in the agent Secret.
+async fn autoscale(
+    client: Client,
+    name: &str,
+    namespace: &str,
+    cr: &CDBootstrap,
+    provider: &dyn CiProvider,
+    image: &AgentImage,
+    state: &mut CDBootstrapStatus,
+) {
+    let (Some(spec), Some(azure_devops)) = (&cr.spec.agent.autoscaling, &cr.spec.azure_devops)
+    else {
+        state.autoscaling = None;
+        return;
+    };
+    let token =
+        match AgentSecret::get_value(client.clone(), name, namespace, provider.token_key()).await {
+            Ok(token) if !token.is_empty() => token,
+            _ => return,
+        };
+
+    let current = autoscaler::replicas(cr);
+    let mut autoscaling = state.autoscaling.clone().unwrap_or(AutoscalingStatus {
+        desired_replicas: current,
+        ..AutoscalingStatus::default()
+    });
+    let result = match AzureDevOpsClient::new(&azure_devops.url, &token) {
+        Ok(devops) => {
+            autoscaler::poll(&devops, &azure_devops.pool, spec, current, &mut autoscaling).await
+        }
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(decision) => {
+            if decision.scaled {
+                let scaled = Agent::scale(
+                    client,
+                    name,
+                    namespace,
+                    cr,
+                    provider,
+                    image,
+                    decision.replicas,
+                )
+                .await;
+                if let Err(e) = scaled {
+                    warn!("Error scaling Agent {}: {:?}", name, e);
+                    state.set_condition(
+                        status::AUTOSCALING,
+                        ConditionStatus::False,
+                        "ScaleFailed",
+                        &e.to_string(),
+                    );
+                    return;
+                }
+            }
+            state.set_condition(
+                status::AUTOSCALING,
+                ConditionStatus::True,
+                "JobsObserved",
+                &decision.message,
+            );
+            state.autoscaling = Some(autoscaling);
+        }
+        Err(e) => {
+            warn!(
+                "Error reading the jobs of agent pool {}: {:?}",
+                azure_devops.pool, e
+            );
+            state.set_condition(
+                status::AUTOSCALING,
+                ConditionStatus::False,
+                e.reason(),
+                &e.to_string(),
+            );
+        }
+    }
+}
+
+/// Records the `CertificateValid` condition when the vault is accessed with a client
+/// certificate. The condition turns `False` `CERTIFICATE_RENEWAL_DAYS` before the certificate
+/// expires, and a Warning Event is published, so it can be renewed before authentication fails.
+async fn observe_certificate(
+    client: Client,
+    namespace: &str,
+    cr: &CDBootstrap,
+    events: &Events,
+    state: &mut CDBootstrapStatus,
+) {
+    if cr.spec.vault.auth != VaultAuth::Certificate {
+        state.remove_condition(status::CERTIFICATE_VALID);
+        return;
+    }
+    let certificate = match certificate(client, namespace, cr).await {
+        Ok(certificate) => certificate,
+        Err(error) => {
+            state.set_condition(
+                status::CERTIFICATE_VALID,
+                ConditionStatus::False,
+                error.reason(),
+                &error.to_string(),
+            );
+            return;
+        }
+    };
+
+    let now = Utc::now();
+    let not_after = certificate
+        .not_after
+        .to_rfc3339_opts(SecondsFormat::Secs, true);
+    if certificate.expires_within(now, chrono::Duration::zero()) {
+        let message = format!(
+            "Certificate {} expired at {}",
+            certificate.thumbprint, not_after
+        );
+        state.set_condition(
+            status::CERTIFICATE_VALID,
+            ConditionStatus::False,
+            "Expired",
+            &message,
+        );
+        events
+            .warning(cr, events::CERTIFICATE_EXPIRING, "Authenticate", &message)
+            .await;
+    } else if certificate.expires_within(now, chrono::Duration::days(CERTIFICATE_RENEWAL_DAYS)) {
+        let message = format!(
+            "Certificate {} expires at {}, renew it before then",
+            certificate.thumbprint, not_after
+        );
+        state.set_condition(
+            status::CERTIFICATE_VALID,
+            ConditionStatus::False,
+            "ExpiresSoon",
+            &message,
+        );
+        events
+            .warning(cr, events::CERTIFICATE_EXPIRING, "Authenticate", &message)
+            .await;
+    } else {
+        state.set_condition(
+            status::CERTIFICATE_VALID,
+            ConditionStatus::True,
+            "Valid",
+            &format!(
+                "Certificate {} is valid until {}",
+                certificate.thumbprint, not_after
+            ),
+        );
+    }
+}
+
+/// Publishes an Event for the outcomes of a vault synchronisation pass the owner of the resource
+/// should know about.
+async fn publish_vault_event(events: &Events, cr: &CDBootstrap, sync: &VaultSync) {
+    match sync {
+        VaultSync::TokenCollected(_) => {
+            events
+                .normal(
+                    cr,
+                    events::TOKEN_FETCHED,
+                    "FetchToken",
+                    "The agent token was collected from the vault and set in the agent Secret",
+                )
+                .await
+        }
+        VaultSync::TokenRotated(token) => {
+            events
+                .normal(
+                    cr,
+                    events::TOKEN_ROTATED,
+                    "RotateToken",
+                    &format!(
+                        "Version {} of the vault secret was collected, the agents are rolled out with it",
+                        token.vault_version
+                    ),
+                )
+                .await
+        }
+        VaultSync::VaultUnreachable(error) => {
+            events
+                .warning(
+                    cr,
+                    events::VAULT_AUTHENTICATION_FAILED,
+                    "FetchToken",
+                    &error.to_string(),
+                )
+                .await
+        }
+        VaultSync::MissingCredentials => {
+            events
+                .warning(
+                    cr,
+                    events::MISSING_SPN_SECRET,
+                    "FetchToken",
+                    "SPN_SECRET is not set in the agent Secret, inject the agent token or the SPN_SECRET",
+                )
+                .await
+        }
+        VaultSync::TokenPresent | VaultSync::TokenCurrent(_) | VaultSync::TokenUnresolved(_) => {}
+    }
+}
+
+/// Translates the outcome of a vault synchronisation pass into the `VaultReachable` and
+/// `SecretsResolved` conditions, and records the version of the vault secret that was applied.
+fn observe_vault(sync: &VaultSync, state: &mut CDBootstrapStatus) {
+    if let Some(token) = sync.token() {
+        state.token = Some(token.clone());
+    }
+    match sync {
+        VaultSync::TokenPresent => {
+            state.set_condition(
+                status::SECRETS_RESOLVED,
+                ConditionStatus::True,
+                "TokenPresent",
+                "The agent token is set in the agent Secret",
+            );
+            if state.condition(status::VAULT_REACHABLE).is_none() {
+                state.set_condition(
+                    status::VAULT_REACHABLE,
+                    ConditionStatus::Unknown,
+                    "NotRequired",
+                    "The agent token was injected, the vault has not been contacted",
+                );
+            }
+        }
+        VaultSync::TokenCurrent(token) => {
+            state.set_condition(
+                status::VAULT_REACHABLE,
+                ConditionStatus::True,
+                "Authenticated",
+                "Connection to the vault is successful",
+            );
+            state.set_condition(
+                status::SECRETS_RESOLVED,
+                ConditionStatus::True,
+                "TokenCurrent",
+                &format!(
+                    "The agent token is collected from version {} of the vault secret",
+                    token.vault_version
+                ),
+            );
+        }
+        VaultSync::TokenCollected(_) => {
+            state.set_condition(
+                status::VAULT_REACHABLE,
+                ConditionStatus::True,
+                "Authenticated",
+                "Connection to the vault is successful",
+            );
+            state.set_condition(
+                status::SECRETS_RESOLVED,
+                ConditionStatus::True,
+                "TokenCollected",
+                "The agent token was collected from the vault and set in the agent Secret",
+            );
+        }
+        VaultSync::TokenRotated(token) => {
+            state.set_condition(
+                status::VAULT_REACHABLE,
+                ConditionStatus::True,
+                "Authenticated",
+                "Connection to the vault is successful",
+            );
+            state.set_condition(
+                status::SECRETS_RESOLVED,
+                ConditionStatus::True,
+                "TokenRotated",
+                &format!(
+                    "Version {} of the vault secret was collected, the agents are rolled out with it",
+                    token.vault_version
+                ),
+            );
+        }
+        VaultSync::MissingCredentials => {
+            state.set_condition(
+                status::VAULT_REACHABLE,
+                ConditionStatus::Unknown,
+                "MissingSpnSecret",
+                "SPN_SECRET is not set in the agent Secret",
+            );
+            state.set_condition(
+                status::SECRETS_RESOLVED,
+                ConditionStatus::False,
+                "MissingCredentials",
+                "Inject the agent token, or set the SPN_SECRET to collect it from the vault",
+            );
+        }
+        VaultSync::VaultUnreachable(error) => {
+            state.set_condition(
+                status::VAULT_REACHABLE,
+                ConditionStatus::False,
+                error.reason(),
+                &error.to_string(),
+            );
+            state.set_condition(
+                status::SECRETS_RESOLVED,
+                ConditionStatus::False,
+                "VaultUnreachable",
+                "The agent token can not be collected from the vault",
+            );
+            state.phase = Phase::Degraded;
+        }
+        VaultSync::TokenUnresolved(error) => {
+            state.set_condition(
+                status::VAULT_REACHABLE,
+                ConditionStatus::True,
+                "Authenticated",
+                "Connection to the vault is successful",
+            );
+            let reason = match error {
+                Error::KubeError { .. } => "TokenUnresolved",
+                error => error.reason(),
+            };
+            state.set_condition(
+                status::SECRETS_RESOLVED,
+                ConditionStatus::False,
+                reason,
+                &error.to_string(),
+            );
+            state.phase = Phase::Degraded;
+        }
+    }
+}
+
+/// Returns the subresources that drifted from their rendered state, see `drift::drifted`. A
+/// subresource that can not be read counts as drifted, so it is applied again. The NetworkPolicy
+/// also drifted when the service tag changed since it was applied.
+async fn drifted_subresources(
+    client: Client,
+    cr: &CDBootstrap,
+    name: &str,
+    namespace: &str,
+    provider: &dyn CiProvider,
+    image: &AgentImage,
+    service_tag: Option<&ServiceTag>,
+) -> Vec<Subresource> {
+    let (secret, config, policy, agent) = join!(
+        AgentSecret::drift(client.clone(), name, namespace, cr),
+        AgentConfig::drift(client.clone(), name, namespace, cr, provider),
+        AgentPolicy::drift(client.clone(), name, namespace, cr, provider, service_tag),
+        Agent::drift(client.clone(), name, namespace, cr, provider, image),
+    );
+
+    let applied_tag = cr
+        .status
+        .as_ref()
+        .and_then(|status| status.service_tag.as_ref());
+    let tag_changed = service_tag.is_some_and(|tag| applied_tag != Some(&tag.status()));
+
+    [
+        (Subresource::Secret, secret),
+        (Subresource::ConfigMap, config),
+        (
+            Subresource::NetworkPolicy,
+            policy.map(|drift| drift || tag_changed),
+        ),
+        (Subresource::Deployment, agent),
+    ]
+    .into_iter()
+    .filter(|(_, drift)| *drift.as_ref().unwrap_or(&true))
+    .map(|(subresource, _)| subresource)
+    .collect()
 }
 
 /// Resources arrives into reconciliation queue in a certain state. This function looks at
@@ -285,52 +1258,20 @@ fn determine_action(cr: &CDBootstrap, desired_state: bool) -> CDBootstrapAction
 }
 
 /// Actions to be taken when a reconciliation fails - for whatever reason.
-/// Prints out the error to `stderr` and requeues the resource for another reconciliation after
-/// five seconds.
+/// Prints out the error to `stderr` and requeues the resource according to the retry policy of
+/// the error: with exponential backoff, or not until the resource changes for errors only the
+/// user can fix, such as an invalid specification.
 ///
 /// # Arguments
 /// - `cdbootstrap`: The erroneous resource.
-/// - `error`: A reference to the `kube::Error` that occurred during reconciliation.
-/// - `_context`: Unused argument. Context Data "injected" automatically by kube-rs.
+/// - `error`: A reference to the `Error` that occurred during reconciliation.
+/// - `context`: Context Data "injected" automatically by kube-rs, holding the backoff.
 fn on_error(cr: Arc<CDBootstrap>, error: &Error, context: Arc<ContextData>) -> Action {
-    // Clone the necessary data
-    let client = context.client.clone();
-
-    let name = String::from(&cr.name_any());
-    let namespace = String::from(
-        &cr.metadata
-            .namespace
-            .clone()
-            .unwrap_or(String::from("default")),
-    );
-    // Use the existing Tokio runtime to spawn the async task
-    tokio::spawn(async move {
-        match status::patch(client, &name, &namespace, false).await {
-            Ok(_) => {
-                info!("Updated status with reconcile error")
-            }
-            Err(e) => {
-                // Update status failed, handle the error
-                error!("Failed to update status: {:?}", e);
-            }
-        }
-    });
-
-    // Continue with the rest of your on_error logic
     error!("Reconciliation error:\n{:?}.\n{:?}", error, cr);
-    Action::requeue(Duration::from_secs(5))
-}
 
-/// All errors possible to occur during reconciliation
-#[derive(Debug, thiserror::Error)]
-pub enum Error {
-    /// Any error originating from the `kube-rs` crate
-    #[error("Kubernetes reported error: {source}")]
-    KubeError {
-        #[from]
-        source: kube::Error,
-    },
-    /// Error in user input or CDBootstrap resource definition, typically missing fields.
-    #[error("Invalid CDBootstrap CRD: {0}")]
-    UserInputError(String),
+    // The status has been updated by `reconcile`, which also decided on the next retry
+    match error.retry_policy() {
+        RetryPolicy::AwaitChange => Action::await_change(),
+        RetryPolicy::Backoff => Action::requeue(context.backoff.next_delay(&backoff_key(&cr))),
+    }
 }
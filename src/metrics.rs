@@ -0,0 +1,197 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::*;
+use warp::Filter;
+
+/// Buckets of the reconcile and Key Vault duration histograms, in seconds.
+const DURATION_BUCKETS: [f64; 10] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Prometheus metrics of the operator, served on `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    /// Reconciliations by action (`Create`, `Update`, `Delete`, `NoOp`) and result.
+    pub reconciliations: IntCounterVec,
+    /// Duration of the reconciliations by action.
+    pub reconcile_duration: HistogramVec,
+    /// Duration of the Key Vault requests by operation.
+    pub vault_request_duration: HistogramVec,
+    /// Failed Key Vault requests by operation.
+    pub vault_request_errors: IntCounterVec,
+    /// Number of agents the Deployment of a `CDBootstrap` resource should run.
+    pub desired_replicas: IntGaugeVec,
+    /// Number of agent pods reported ready by the Deployment of a `CDBootstrap` resource.
+    pub ready_replicas: IntGaugeVec,
+    /// 1 while the replica with the identity holds the leader election Lease, else 0.
+    pub leader: IntGaugeVec,
+}
+
+/// Returns the metrics of the operator, registered on first use.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("Expected valid metric definitions."))
+}
+
+impl Metrics {
+    fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new();
+        let duration = |name: &str, help: &str, labels: &[&str]| {
+            HistogramVec::new(
+                HistogramOpts::new(name, help).buckets(DURATION_BUCKETS.to_vec()),
+                labels,
+            )
+        };
+
+        let metrics = Metrics {
+            reconciliations: IntCounterVec::new(
+                Opts::new(
+                    "cdbootstrap_reconciliations_total",
+                    "Reconciliations of CDBootstrap resources by action and result",
+                ),
+                &["action", "result"],
+            )?,
+            reconcile_duration: duration(
+                "cdbootstrap_reconcile_duration_seconds",
+                "Duration of the reconciliations of CDBootstrap resources by action",
+                &["action"],
+            )?,
+            vault_request_duration: duration(
+                "cdbootstrap_vault_request_duration_seconds",
+                "Duration of the Azure Key Vault requests by operation",
+                &["operation"],
+            )?,
+            vault_request_errors: IntCounterVec::new(
+                Opts::new(
+                    "cdbootstrap_vault_request_errors_total",
+                    "Failed Azure Key Vault requests by operation",
+                ),
+                &["operation"],
+            )?,
+            desired_replicas: IntGaugeVec::new(
+                Opts::new(
+                    "cdbootstrap_agent_replicas_desired",
+                    "Number of agents the Deployment of a CDBootstrap resource should run",
+                ),
+                &["namespace", "name"],
+            )?,
+            ready_replicas: IntGaugeVec::new(
+                Opts::new(
+                    "cdbootstrap_agent_replicas_ready",
+                    "Number of ready agent pods of a CDBootstrap resource",
+                ),
+                &["namespace", "name"],
+            )?,
+            leader: IntGaugeVec::new(
+                Opts::new(
+                    "cdbootstrap_leader",
+                    "1 if this replica of the operator is the leader, else 0",
+                ),
+                &["identity"],
+            )?,
+            registry,
+        };
+
+        metrics
+            .registry
+            .register(Box::new(metrics.reconciliations.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.reconcile_duration.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.vault_request_duration.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.vault_request_errors.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.desired_replicas.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.ready_replicas.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.leader.clone()))?;
+        Ok(metrics)
+    }
+
+    /// Records a finished reconciliation.
+    ///
+    /// # Arguments
+    /// - `action` - Action taken, or `Validate` when the resource was rejected before an action
+    ///   was decided on.
+    /// - `success` - False if the reconciliation returned an error.
+    /// - `duration` - Time the reconciliation took.
+    pub fn reconciled(&self, action: &str, success: bool, duration: Duration) {
+        let result = if success { "success" } else { "error" };
+        self.reconciliations
+            .with_label_values(&[action, result])
+            .inc();
+        self.reconcile_duration
+            .with_label_values(&[action])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Records a Key Vault request, e.g. `get_secret`.
+    pub fn vault_request(&self, operation: &str, success: bool, duration: Duration) {
+        self.vault_request_duration
+            .with_label_values(&[operation])
+            .observe(duration.as_secs_f64());
+        if !success {
+            self.vault_request_errors
+                .with_label_values(&[operation])
+                .inc();
+        }
+    }
+
+    /// Records the desired and ready agents of a `CDBootstrap` resource.
+    pub fn agents(&self, namespace: &str, name: &str, desired: i32, ready: i32) {
+        self.desired_replicas
+            .with_label_values(&[namespace, name])
+            .set(desired as i64);
+        self.ready_replicas
+            .with_label_values(&[namespace, name])
+            .set(ready as i64);
+    }
+
+    /// Removes the agent gauges of a deleted `CDBootstrap` resource.
+    pub fn forget(&self, namespace: &str, name: &str) {
+        let _ = self
+            .desired_replicas
+            .remove_label_values(&[namespace, name]);
+        let _ = self.ready_replicas.remove_label_values(&[namespace, name]);
+    }
+
+    /// Records whether the replica with the identity is the leader.
+    pub fn leader(&self, identity: &str, leader: bool) {
+        self.leader
+            .with_label_values(&[identity])
+            .set(leader as i64);
+    }
+
+    /// Renders the metrics in the Prometheus text format.
+    pub fn render(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+/// Serves the metrics on `/metrics`, over plain HTTP.
+pub async fn serve(addr: SocketAddr) {
+    let routes = warp::path("metrics")
+        .and(warp::get())
+        .map(|| match metrics().render() {
+            Ok(body) => warp::reply::with_status(body, warp::http::StatusCode::OK),
+            Err(e) => warp::reply::with_status(
+                e.to_string(),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        });
+
+    info!("Serving the metrics on {}", addr);
+    warp::serve(routes).run(addr).await;
+}
@@ -0,0 +1,177 @@
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use crate::crd::{CDBootstrapSpec, Provider, VaultAuth};
+use crate::error::Error;
+
+pub mod azure;
+pub mod github;
+pub mod gitlab;
+
+pub use azure::AzurePipelines;
+pub use github::GitHubActions;
+pub use gitlab::GitLabRunner;
+
+/// A credential the agents register with, as stored in the agent Secret.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AgentToken {
+    pub value: String,
+    /// RFC 3339 timestamp after which the token can no longer be used to register agents.
+    pub expires_at: Option<String>,
+}
+
+/// The CI system specific parts of a `CDBootstrap` resource. The subresources in the
+/// `subresources` module are rendered from a provider, so the operator itself stays agnostic of
+/// the CI system the agents register with.
+#[async_trait]
+pub trait CiProvider: Send + Sync {
+    /// Human readable name of the CI system, used in logs and status messages.
+    fn name(&self) -> &'static str;
+
+    /// Container image the agents run when no image is set in the specification.
+    fn image(&self) -> &'static str;
+
+    /// Key in the agent Secret holding the credential the agents register with.
+    fn token_key(&self) -> &'static str;
+
+    /// Keys in the agent Secret the running agents depend on. A change of one of them rolls out
+    /// new agent pods, see `subresources::CONFIG_CHECKSUM_ANNOTATION`. By default the token and
+    /// the `SPN_SECRET`.
+    fn restart_keys(&self) -> Vec<&'static str> {
+        vec![self.token_key(), "SPN_SECRET"]
+    }
+
+    /// Non-sensitive agent configuration, rendered as the data of the agent ConfigMap.
+    fn config(&self) -> BTreeMap<String, String>;
+
+    /// Environment of the agent container. By default the token and the `SPN_SECRET` are taken
+    /// from the agent Secret and every key of the agent ConfigMap is exposed as a variable.
+    ///
+    /// # Arguments
+    /// - `name` - Name of the agent Secret and ConfigMap.
+    fn env(&self, name: &str) -> Vec<Value> {
+        let secret_keys = [self.token_key(), "SPN_SECRET"];
+        let secret_env = secret_keys.into_iter().map(|key| {
+            json!({
+                "name": key,
+                "valueFrom": {
+                    "secretKeyRef": {
+                        "name": name,
+                        "key": key,
+                        "optional": true,
+                    },
+                },
+            })
+        });
+        let config_env = self.config().into_keys().map(|key| {
+            json!({
+                "name": key,
+                "valueFrom": {
+                    "configMapKeyRef": {
+                        "name": name,
+                        "key": key,
+                        "optional": true,
+                    },
+                },
+            })
+        });
+        secret_env.chain(config_env).collect()
+    }
+
+    /// Networks the agents connect to, allowed by the egress NetworkPolicy when no `cidrs` are
+    /// set in the specification. Most CI systems do not publish fixed ranges, so any
+    /// destination is allowed on the policy ports by default.
+    fn egress_cidrs(&self) -> Vec<String> {
+        vec!["0.0.0.0/0".to_owned()]
+    }
+
+    /// Command of the agent container, `None` runs the entrypoint of the image.
+    fn command(&self) -> Option<Vec<String>> {
+        None
+    }
+
+    /// Volumes of the agent pod, e.g. to mount the agent ConfigMap as a file.
+    ///
+    /// # Arguments
+    /// - `name` - Name of the agent Secret and ConfigMap.
+    fn volumes(&self, _name: &str) -> Vec<Value> {
+        Vec::new()
+    }
+
+    /// Mounts of the `volumes` in the agent container.
+    fn volume_mounts(&self) -> Vec<Value> {
+        Vec::new()
+    }
+
+    /// Turns the value collected from the vault into the credential stored in the agent Secret.
+    /// A failed request is reported as `Error::TokenExchange`.
+    async fn exchange_token(&self, vault_value: &str) -> Result<AgentToken, Error>;
+
+    /// Removes the agents from the CI system when the `CDBootstrap` resource is deleted. Agents
+    /// of most CI systems deregister themselves when the pod stops, so this does nothing by
+    /// default. A failed request is reported as `Error::Provider`.
+    ///
+    /// # Arguments
+    /// - `token` - The credential stored in the agent Secret under `token_key`.
+    async fn unregister(&self, _token: &str) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Reports a failed token exchange with the CI provider.
+fn exchange_error(error: impl Display) -> Error {
+    Error::TokenExchange(error.to_string())
+}
+
+/// Checks that the provider section matching `spec.provider` is set, that autoscaling is only
+/// used with a provider that supports it, and that a client certificate is referenced when the
+/// vault is accessed with one.
+pub fn check(spec: &CDBootstrapSpec) -> Result<(), String> {
+    if spec.vault.auth == VaultAuth::Certificate && spec.vault.certificate.is_none() {
+        return Err(String::from(
+            "vault.auth Certificate requires the vault.certificate section",
+        ));
+    }
+    if let Some(autoscaling) = &spec.agent.autoscaling {
+        if spec.provider != Provider::AzurePipelines {
+            return Err(String::from(
+                "agent.autoscaling is only supported by provider AzurePipelines",
+            ));
+        }
+        if autoscaling.min_replicas > autoscaling.max_replicas {
+            return Err(String::from(
+                "agent.autoscaling.minReplicas must not exceed maxReplicas",
+            ));
+        }
+    }
+    match spec.provider {
+        Provider::AzurePipelines if spec.azure_devops.is_none() => Err(String::from(
+            "provider AzurePipelines requires the azureDevOps section",
+        )),
+        Provider::GitHubActions if spec.github.is_none() => Err(String::from(
+            "provider GitHubActions requires the github section",
+        )),
+        Provider::GitLabRunner if spec.gitlab.is_none() => Err(String::from(
+            "provider GitLabRunner requires the gitlab section",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the provider selected by `spec.provider`. The specification is expected to have
+/// passed `check`, a missing section results in a provider with empty settings.
+pub fn from_spec(spec: &CDBootstrapSpec) -> Box<dyn CiProvider> {
+    match spec.provider {
+        Provider::AzurePipelines => Box::new(AzurePipelines::new(
+            spec.azure_devops.clone().unwrap_or_default(),
+        )),
+        Provider::GitHubActions => {
+            Box::new(GitHubActions::new(spec.github.clone().unwrap_or_default()))
+        }
+        Provider::GitLabRunner => {
+            Box::new(GitLabRunner::new(spec.gitlab.clone().unwrap_or_default()))
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+
+use crate::crd::AzureDevOpsSpec;
+use crate::error::Error;
+use crate::provider::{AgentToken, CiProvider};
+
+/// Azure Pipelines agents, registering with a personal access token stored in the vault.
+pub struct AzurePipelines {
+    spec: AzureDevOpsSpec,
+}
+
+impl AzurePipelines {
+    pub fn new(spec: AzureDevOpsSpec) -> Self {
+        AzurePipelines { spec }
+    }
+}
+
+#[async_trait]
+impl CiProvider for AzurePipelines {
+    fn name(&self) -> &'static str {
+        "Azure Pipelines"
+    }
+
+    fn image(&self) -> &'static str {
+        "ghcr.io/bartvanbenthem/azp-agent-alpine:latest"
+    }
+
+    fn token_key(&self) -> &'static str {
+        "AZP_TOKEN"
+    }
+
+    fn config(&self) -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("AZP_POOL".to_owned(), self.spec.pool.clone()),
+            ("AZP_URL".to_owned(), self.spec.url.clone()),
+            //("AZP_WORK".to_owned(), "placeholder".to_owned()),
+            //("AZP_AGENT_NAME".to_owned(), "placeholder".to_owned()),
+            //("AGENT_MTU_VALUE".to_owned(), "placeholder".to_owned()),
+        ])
+    }
+
+    /// The IP ranges of `dev.azure.com`, as published in the Azure DevOps documentation on
+    /// allowed address lists.
+    fn egress_cidrs(&self) -> Vec<String> {
+        [
+            "13.107.6.0/24",
+            "13.107.9.0/24",
+            "13.107.42.0/24",
+            "13.107.43.0/24",
+            "150.171.22.0/24",
+            "150.171.23.0/24",
+            "150.171.73.0/24",
+            "150.171.74.0/24",
+            "150.171.75.0/24",
+            "150.171.76.0/24",
+        ]
+        .map(String::from)
+        .to_vec()
+    }
+
+    /// The personal access token in the vault is used by the agents as is.
+    async fn exchange_token(&self, vault_value: &str) -> Result<AgentToken, Error> {
+        Ok(AgentToken {
+            value: vault_value.to_owned(),
+            expires_at: None,
+        })
+    }
+}
@@ -0,0 +1,190 @@
+use async_trait::async_trait;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::*;
+
+use crate::crd::{GitHubAppSpec, GitHubSpec};
+use crate::error::Error;
+use crate::provider::{exchange_error, AgentToken, CiProvider};
+
+const DEFAULT_API_URL: &str = "https://api.github.com";
+
+/// GitHub Actions self-hosted runners. The vault holds either a personal access token or the
+/// private key of a GitHub App, which is exchanged for a short lived runner registration token.
+pub struct GitHubActions {
+    spec: GitHubSpec,
+}
+
+/// Response of both the installation access token and the registration token endpoints.
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: String,
+    expires_at: Option<String>,
+}
+
+/// Claims of the JWT a GitHub App authenticates with.
+#[derive(Serialize)]
+struct AppClaims {
+    iat: u64,
+    exp: u64,
+    iss: String,
+}
+
+impl GitHubActions {
+    pub fn new(spec: GitHubSpec) -> Self {
+        GitHubActions { spec }
+    }
+
+    fn api_url(&self) -> &str {
+        self.spec
+            .api_url
+            .as_deref()
+            .unwrap_or(DEFAULT_API_URL)
+            .trim_end_matches('/')
+    }
+
+    /// Returns the REST endpoint to create a runner registration token with, for either the
+    /// organization or the repository in `spec.url`. A URL of neither is reported as
+    /// `Error::UserInputError`, as only a change of the specification resolves it.
+    pub fn registration_endpoint(&self) -> Result<String, Error> {
+        let api_url = self.api_url();
+        let url = Url::parse(&self.spec.url).map_err(|e| {
+            Error::UserInputError(format!("invalid github.url {}: {}", self.spec.url, e))
+        })?;
+        let segments: Vec<&str> = url
+            .path_segments()
+            .map(|segments| segments.filter(|s| !s.is_empty()).collect())
+            .unwrap_or_default();
+
+        match segments.as_slice() {
+            [org] => Ok(format!(
+                "{}/orgs/{}/actions/runners/registration-token",
+                api_url, org
+            )),
+            [owner, repo] => Ok(format!(
+                "{}/repos/{}/{}/actions/runners/registration-token",
+                api_url, owner, repo
+            )),
+            _ => Err(Error::UserInputError(format!(
+                "github.url: expected an organization or repository URL, got {}",
+                self.spec.url
+            ))),
+        }
+    }
+
+    /// Exchanges the private key of a GitHub App for an installation access token.
+    async fn installation_token(
+        &self,
+        http: &Client,
+        app: &GitHubAppSpec,
+        private_key: &str,
+    ) -> Result<String, Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(exchange_error)?
+            .as_secs();
+        // Backdate the token a minute to allow for clock drift, GitHub accepts at most 10 minutes
+        let claims = AppClaims {
+            iat: now - 60,
+            exp: now + 540,
+            iss: app.app_id.to_string(),
+        };
+        let jwt = jsonwebtoken::encode(
+            &Header::new(Algorithm::RS256),
+            &claims,
+            &EncodingKey::from_rsa_pem(private_key.as_bytes()).map_err(|e| {
+                exchange_error(format!(
+                    "invalid private key of GitHub App {}: {}",
+                    app.app_id, e
+                ))
+            })?,
+        )
+        .map_err(exchange_error)?;
+
+        let response: TokenResponse = http
+            .post(format!(
+                "{}/app/installations/{}/access_tokens",
+                self.api_url(),
+                app.installation_id
+            ))
+            .bearer_auth(jwt)
+            .header("Accept", "application/vnd.github+json")
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(exchange_error)?
+            .json()
+            .await
+            .map_err(exchange_error)?;
+        Ok(response.token)
+    }
+}
+
+#[async_trait]
+impl CiProvider for GitHubActions {
+    fn name(&self) -> &'static str {
+        "GitHub Actions"
+    }
+
+    fn image(&self) -> &'static str {
+        "ghcr.io/bartvanbenthem/gh-runner-ubuntu:latest"
+    }
+
+    fn token_key(&self) -> &'static str {
+        "RUNNER_TOKEN"
+    }
+
+    /// The registration token is only used when a runner registers, and is renewed about every
+    /// hour. Pods started later pick up the renewed token, so running runners are left alone.
+    fn restart_keys(&self) -> Vec<&'static str> {
+        vec!["SPN_SECRET"]
+    }
+
+    fn config(&self) -> BTreeMap<String, String> {
+        let mut config = BTreeMap::from([
+            ("RUNNER_URL".to_owned(), self.spec.url.clone()),
+            ("RUNNER_LABELS".to_owned(), self.spec.labels.join(",")),
+        ]);
+        if let Some(group) = &self.spec.runner_group {
+            config.insert("RUNNER_GROUP".to_owned(), group.clone());
+        }
+        config
+    }
+
+    /// Creates a runner registration token, authenticating with the personal access token or,
+    /// when `spec.github.app` is set, as the GitHub App whose private key is in the vault.
+    async fn exchange_token(&self, vault_value: &str) -> Result<AgentToken, Error> {
+        let http = Client::builder()
+            .user_agent("cdbootstrap-operator")
+            .build()
+            .map_err(exchange_error)?;
+
+        let bearer = match &self.spec.app {
+            Some(app) => {
+                info!("Authenticating as GitHub App {}", app.app_id);
+                self.installation_token(&http, app, vault_value).await?
+            }
+            None => vault_value.to_owned(),
+        };
+
+        let response: TokenResponse = http
+            .post(self.registration_endpoint()?)
+            .bearer_auth(bearer)
+            .header("Accept", "application/vnd.github+json")
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(exchange_error)?
+            .json()
+            .await
+            .map_err(exchange_error)?;
+
+        Ok(AgentToken {
+            value: response.token,
+            expires_at: response.expires_at,
+        })
+    }
+}
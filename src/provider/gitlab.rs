@@ -0,0 +1,179 @@
+use async_trait::async_trait;
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use tracing::*;
+
+use crate::crd::GitLabSpec;
+use crate::error::Error;
+use crate::provider::{AgentToken, CiProvider};
+
+const DEFAULT_EXECUTOR: &str = "shell";
+
+/// Directory the agent ConfigMap is mounted in.
+const TEMPLATE_DIR: &str = "/etc/gitlab-runner-template";
+
+/// GitLab runners, registering with a runner authentication token (`glrt-...`) stored in the
+/// vault. The token is created with the runner in GitLab and is used by the runners as is.
+///
+/// The rendered `config.toml` holds everything but the token, so it can live in a ConfigMap.
+/// On start the runner pod registers itself with `gitlab-runner register`, which merges the
+/// token from the agent Secret with the `config.toml` template.
+pub struct GitLabRunner {
+    spec: GitLabSpec,
+}
+
+impl GitLabRunner {
+    pub fn new(spec: GitLabSpec) -> Self {
+        GitLabRunner { spec }
+    }
+
+    fn url(&self) -> &str {
+        self.spec.url.trim_end_matches('/')
+    }
+
+    fn executor(&self) -> &str {
+        self.spec.executor.as_deref().unwrap_or(DEFAULT_EXECUTOR)
+    }
+
+    /// Renders the `[[runners]]` section of the runner `config.toml`, used as the template of
+    /// `gitlab-runner register`.
+    pub fn config_toml(&self) -> String {
+        format!(
+            "[[runners]]\n  url = \"{}\"\n  executor = \"{}\"\n",
+            escape(self.url()),
+            escape(self.executor()),
+        )
+    }
+}
+
+/// Escapes a value for use in a TOML basic string.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[async_trait]
+impl CiProvider for GitLabRunner {
+    fn name(&self) -> &'static str {
+        "GitLab"
+    }
+
+    fn image(&self) -> &'static str {
+        "gitlab/gitlab-runner:alpine"
+    }
+
+    fn token_key(&self) -> &'static str {
+        "CI_SERVER_TOKEN"
+    }
+
+    fn config(&self) -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("CI_SERVER_URL".to_owned(), self.url().to_owned()),
+            ("config.toml".to_owned(), self.config_toml()),
+        ])
+    }
+
+    /// `gitlab-runner register` is configured through its environment variables. Each pod
+    /// registers under its own name, so the runner managers can be told apart in GitLab.
+    fn env(&self, name: &str) -> Vec<Value> {
+        let secret_env = [self.token_key(), "SPN_SECRET"].map(|key| {
+            json!({
+                "name": key,
+                "valueFrom": {
+                    "secretKeyRef": {
+                        "name": name,
+                        "key": key,
+                        "optional": true,
+                    },
+                },
+            })
+        });
+        let runner_env = [
+            json!({
+                "name": "CI_SERVER_URL",
+                "valueFrom": {
+                    "configMapKeyRef": {
+                        "name": name,
+                        "key": "CI_SERVER_URL",
+                        "optional": true,
+                    },
+                },
+            }),
+            json!({
+                "name": "RUNNER_NAME",
+                "valueFrom": {
+                    "fieldRef": {
+                        "fieldPath": "metadata.name",
+                    },
+                },
+            }),
+            json!({
+                "name": "TEMPLATE_CONFIG_FILE",
+                "value": format!("{}/config.toml", TEMPLATE_DIR),
+            }),
+            json!({
+                "name": "REGISTER_NON_INTERACTIVE",
+                "value": "true",
+            }),
+        ];
+        secret_env.into_iter().chain(runner_env).collect()
+    }
+
+    fn command(&self) -> Option<Vec<String>> {
+        Some(vec![
+            "/bin/sh".to_owned(),
+            "-c".to_owned(),
+            "gitlab-runner register && exec gitlab-runner run --user=gitlab-runner --working-directory=/home/gitlab-runner".to_owned(),
+        ])
+    }
+
+    fn volumes(&self, name: &str) -> Vec<Value> {
+        vec![json!({
+            "name": "runner-config",
+            "configMap": {
+                "name": name,
+                "items": [
+                    {
+                        "key": "config.toml",
+                        "path": "config.toml",
+                    }
+                ],
+            },
+        })]
+    }
+
+    fn volume_mounts(&self) -> Vec<Value> {
+        vec![json!({
+            "name": "runner-config",
+            "mountPath": TEMPLATE_DIR,
+            "readOnly": true,
+        })]
+    }
+
+    /// The runner authentication token in the vault is used by the runners as is.
+    async fn exchange_token(&self, vault_value: &str) -> Result<AgentToken, Error> {
+        Ok(AgentToken {
+            value: vault_value.to_owned(),
+            expires_at: None,
+        })
+    }
+
+    /// Deletes the runner, including all runner managers registered by the pods, from GitLab.
+    async fn unregister(&self, token: &str) -> Result<(), Error> {
+        let provider_error = |e: reqwest::Error| Error::Provider(e.to_string());
+        let http = Client::builder()
+            .user_agent("cdbootstrap-operator")
+            .build()
+            .map_err(provider_error)?;
+
+        http.delete(format!("{}/api/v4/runners", self.url()))
+            .form(&[("token", token)])
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(provider_error)?;
+
+        info!("Unregistered runner from {}", self.url());
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+/// Binary SI suffixes of a Kubernetes quantity, with their power of 2.
+const BINARY_SUFFIXES: [(&str, u32); 6] = [
+    ("Ki", 10),
+    ("Mi", 20),
+    ("Gi", 30),
+    ("Ti", 40),
+    ("Pi", 50),
+    ("Ei", 60),
+];
+
+/// Decimal SI suffixes of a Kubernetes quantity, with their power of 10.
+const DECIMAL_SUFFIXES: [(&str, i32); 10] = [
+    ("n", -9),
+    ("u", -6),
+    ("m", -3),
+    ("", 0),
+    ("k", 3),
+    ("M", 6),
+    ("G", 9),
+    ("T", 12),
+    ("P", 15),
+    ("E", 18),
+];
+
+/// Returns the value of a Kubernetes resource quantity, e.g. `500m` or `1Gi`, in units of
+/// 10^-9, rounded up like the API server does. Quantities written differently but with the same
+/// value are equal: `0.5` and `500m`, `1024Mi` and `1Gi`. Returns an error for anything the API
+/// server would reject, e.g. `2GB` or `one`.
+pub fn nanos(quantity: &str) -> Result<i128, String> {
+    let invalid = || format!("invalid quantity {:?}, e.g. 500m or 1Gi", quantity);
+
+    let (negative, unsigned) = match quantity.strip_prefix('-') {
+        Some(unsigned) => (true, unsigned),
+        None => (false, quantity.strip_prefix('+').unwrap_or(quantity)),
+    };
+    let number_end = unsigned
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(unsigned.len());
+    let (number, suffix) = unsigned.split_at(number_end);
+
+    let (integer, fraction) = number.split_once('.').unwrap_or((number, ""));
+    if (integer.is_empty() && fraction.is_empty()) || fraction.contains('.') {
+        return Err(invalid());
+    }
+    let digits: i128 = format!("{}{}", integer, fraction)
+        .parse()
+        .map_err(|_| invalid())?;
+
+    let (binary, exponent) = suffix_scale(suffix).ok_or_else(invalid)?;
+    // The value is `digits * 2^binary * 10^exponent / 10^fraction`, scaled to units of 10^-9
+    let scale = 9 + exponent - fraction.len() as i32;
+    let mantissa = digits
+        .checked_mul(1i128.checked_shl(binary).ok_or_else(invalid)?)
+        .ok_or_else(invalid)?;
+    let value = if scale >= 0 {
+        10i128
+            .checked_pow(scale as u32)
+            .and_then(|power| mantissa.checked_mul(power))
+            .ok_or_else(invalid)?
+    } else {
+        match 10i128.checked_pow(scale.unsigned_abs()) {
+            Some(power) => (mantissa + power - 1) / power,
+            None => i128::from(mantissa > 0),
+        }
+    };
+    Ok(if negative { -value } else { value })
+}
+
+/// Returns the power of 2 and the power of 10 of a quantity suffix, `None` if it is not one.
+fn suffix_scale(suffix: &str) -> Option<(u32, i32)> {
+    if let Some((_, power)) = BINARY_SUFFIXES.iter().find(|(name, _)| *name == suffix) {
+        return Some((*power, 0));
+    }
+    if let Some((_, power)) = DECIMAL_SUFFIXES.iter().find(|(name, _)| *name == suffix) {
+        return Some((0, *power));
+    }
+    // A decimal exponent, e.g. `1e3`
+    let exponent = suffix.strip_prefix(['e', 'E'])?;
+    let digits = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some((0, exponent.parse().ok()?))
+}
@@ -0,0 +1,262 @@
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::{Api, Client};
+use serde::Deserialize;
+use std::env;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tokio::time::{sleep, Duration};
+use tracing::*;
+
+use crate::crd::ServiceTagStatus;
+use crate::error::Error;
+
+/// Service tag of the Azure DevOps endpoints the agents connect to.
+pub const AZURE_DEVOPS_TAG: &str = "AzureDevOps";
+
+const DEFAULT_CONFIGMAP_KEY: &str = "ServiceTags_Public.json";
+const DEFAULT_REFRESH_SECONDS: u64 = 300;
+
+/// The address prefixes of a single service tag, selected from an Azure Service Tags document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceTag {
+    /// Name of the tag, e.g. `AzureDevOps`, or the names of the regional tags joined by `,`.
+    pub name: String,
+    /// Region the tag was filtered on, if any.
+    pub region: Option<String>,
+    /// The `changeNumber` of the tag, increased by Microsoft on every change of its prefixes.
+    pub change_number: u64,
+    pub address_prefixes: Vec<String>,
+}
+
+impl ServiceTag {
+    /// Returns the version of the tag, as recorded in the resource status.
+    pub fn status(&self) -> ServiceTagStatus {
+        ServiceTagStatus {
+            name: self.name.clone(),
+            region: self.region.clone(),
+            change_number: self.change_number,
+        }
+    }
+}
+
+/// Azure Service Tags document, as downloaded from the Microsoft download center or returned by
+/// the Service Tag Discovery API.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Document {
+    values: Vec<Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Value {
+    name: String,
+    properties: Properties,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Properties {
+    #[serde(default)]
+    change_number: u64,
+    #[serde(default)]
+    region: String,
+    #[serde(default)]
+    system_service: String,
+    #[serde(default)]
+    address_prefixes: Vec<String>,
+}
+
+/// Selects a service tag from an Azure Service Tags document. A malformed document or a missing
+/// tag is reported as `Error::ServiceTags`.
+///
+/// # Arguments
+/// - `document` - The JSON document.
+/// - `tag` - Name of the tag, e.g. `AzureDevOps`.
+/// - `region` - Only take the regional tags of the service in this region, e.g. `westeurope`.
+pub fn select(document: &str, tag: &str, region: Option<&str>) -> Result<ServiceTag, Error> {
+    let document: Document = serde_json::from_str(document)
+        .map_err(|e| Error::ServiceTags(format!("invalid document: {}", e)))?;
+
+    let selected: Vec<&Value> = match region {
+        None => document.values.iter().filter(|v| v.name == tag).collect(),
+        Some(region) => document
+            .values
+            .iter()
+            .filter(|v| {
+                v.properties.system_service == tag
+                    && v.properties.region.eq_ignore_ascii_case(region)
+            })
+            .collect(),
+    };
+    if selected.is_empty() {
+        return Err(match region {
+            None => Error::ServiceTags(format!("service tag {} not found", tag)),
+            Some(region) => Error::ServiceTags(format!(
+                "service tag {} not found in region {}",
+                tag, region
+            )),
+        });
+    }
+
+    let mut address_prefixes: Vec<String> = selected
+        .iter()
+        .flat_map(|v| v.properties.address_prefixes.iter().cloned())
+        .collect();
+    address_prefixes.sort();
+    address_prefixes.dedup();
+
+    Ok(ServiceTag {
+        name: selected
+            .iter()
+            .map(|v| v.name.as_str())
+            .collect::<Vec<&str>>()
+            .join(","),
+        region: region.map(String::from),
+        change_number: selected
+            .iter()
+            .map(|v| v.properties.change_number)
+            .max()
+            .unwrap_or_default(),
+        address_prefixes,
+    })
+}
+
+/// Location of the Service Tags document.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    ConfigMap {
+        namespace: String,
+        name: String,
+        key: String,
+    },
+    File(PathBuf),
+}
+
+/// Operator configuration of the Service Tags document, read from the environment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceTagConfig {
+    pub source: Source,
+    pub region: Option<String>,
+    pub refresh: Duration,
+}
+
+impl ServiceTagConfig {
+    /// Reads the configuration from the environment of the operator, `None` when no document is
+    /// configured:
+    /// - `SERVICE_TAGS_FILE` - Path of the document, or
+    /// - `SERVICE_TAGS_CONFIGMAP` - `<namespace>/<name>` of a ConfigMap holding the document
+    ///   under `SERVICE_TAGS_CONFIGMAP_KEY`, defaults to `ServiceTags_Public.json`
+    /// - `SERVICE_TAGS_REGION` - Region to filter the `AzureDevOps` tag on
+    /// - `SERVICE_TAGS_REFRESH_SECONDS` - Interval the document is reloaded at, defaults to 300
+    pub fn from_env() -> Option<Self> {
+        ServiceTagConfig::from_vars(|key| env::var(key).ok())
+    }
+
+    /// Reads the configuration with the given variable lookup, empty values are ignored.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        let var = |key: &str| {
+            var(key)
+                .map(|value| value.trim().to_owned())
+                .filter(|value| !value.is_empty())
+        };
+
+        let source = match (var("SERVICE_TAGS_FILE"), var("SERVICE_TAGS_CONFIGMAP")) {
+            (Some(path), _) => Source::File(PathBuf::from(path)),
+            (None, Some(configmap)) => {
+                let (namespace, name) = configmap.split_once('/')?;
+                Source::ConfigMap {
+                    namespace: namespace.to_owned(),
+                    name: name.to_owned(),
+                    key: var("SERVICE_TAGS_CONFIGMAP_KEY")
+                        .unwrap_or(String::from(DEFAULT_CONFIGMAP_KEY)),
+                }
+            }
+            (None, None) => return None,
+        };
+        let refresh = var("SERVICE_TAGS_REFRESH_SECONDS")
+            .and_then(|seconds| seconds.parse().ok())
+            .unwrap_or(DEFAULT_REFRESH_SECONDS);
+
+        Some(ServiceTagConfig {
+            source,
+            region: var("SERVICE_TAGS_REGION"),
+            refresh: Duration::from_secs(refresh),
+        })
+    }
+}
+
+/// The `AzureDevOps` service tag currently in use, shared between the reconciliations and the
+/// task refreshing it.
+#[derive(Default)]
+pub struct ServiceTags {
+    current: RwLock<Option<Arc<ServiceTag>>>,
+}
+
+impl ServiceTags {
+    /// Returns the current tag, `None` until a document has been loaded.
+    pub fn current(&self) -> Option<Arc<ServiceTag>> {
+        self.current.read().ok().and_then(|current| current.clone())
+    }
+
+    /// Replaces the current tag, returns true if its change number differs.
+    pub fn set(&self, tag: ServiceTag) -> bool {
+        let Ok(mut current) = self.current.write() else {
+            return false;
+        };
+        let changed =
+            current.as_ref().map(|existing| existing.change_number) != Some(tag.change_number);
+        *current = Some(Arc::new(tag));
+        changed
+    }
+}
+
+/// Reads the document from its source.
+pub async fn load(client: Client, source: &Source) -> Result<String, Error> {
+    match source {
+        Source::File(path) => tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| Error::ServiceTags(format!("{}: {}", path.display(), e))),
+        Source::ConfigMap {
+            namespace,
+            name,
+            key,
+        } => {
+            let api: Api<ConfigMap> = Api::namespaced(client, namespace);
+            let configmap = api.get(name).await?;
+            configmap
+                .data
+                .and_then(|mut data| data.remove(key))
+                .ok_or_else(|| {
+                    Error::ServiceTags(format!(
+                        "key {} not found in ConfigMap {}/{}",
+                        key, namespace, name
+                    ))
+                })
+        }
+    }
+}
+
+/// Reloads the document every `config.refresh` and updates `tags` when the `AzureDevOps` tag
+/// changes. The NetworkPolicies are re-rendered by the reconciliations, which compare the
+/// change number recorded in the resource status with the current tag.
+pub async fn refresh(client: Client, config: ServiceTagConfig, tags: Arc<ServiceTags>) {
+    loop {
+        match load(client.clone(), &config.source)
+            .await
+            .and_then(|document| select(&document, AZURE_DEVOPS_TAG, config.region.as_deref()))
+        {
+            Ok(tag) => {
+                let change_number = tag.change_number;
+                if tags.set(tag) {
+                    info!(
+                        "Loaded service tag {} with change number {}",
+                        AZURE_DEVOPS_TAG, change_number
+                    );
+                }
+            }
+            Err(e) => warn!("Unable to load the Azure Service Tags document: {}", e),
+        }
+        sleep(config.refresh).await;
+    }
+}
@@ -1,3 +1,4 @@
+use k8s_openapi::chrono::{SecondsFormat, Utc};
 use kube::api::{Patch, PatchParams, PostParams};
 use kube::{Api, Client, Error, ResourceExt};
 use serde_json::{json, Value};
@@ -5,35 +6,201 @@ use tracing::*;
 
 use std::collections::BTreeMap;
 
-use crate::crd::{CDBootstrap, CDBootstrapStatus};
+use crate::crd::{CDBootstrap, CDBootstrapStatus, Condition, ConditionStatus, Phase, RetryStatus};
 
+/// The resource is fully reconciled and its agents are available.
+pub const READY: &str = "Ready";
+/// The agent token, e.g. the `AZP_TOKEN`, is present in the agent Secret.
+pub const SECRETS_RESOLVED: &str = "SecretsResolved";
+/// The operator can authenticate against and read from the Key Vault.
+pub const VAULT_REACHABLE: &str = "VaultReachable";
+/// The agent Deployment has the desired number of ready pods.
+pub const AGENTS_AVAILABLE: &str = "AgentsAvailable";
+/// The egress NetworkPolicy for the agent pods has been applied.
+pub const NETWORK_POLICY_APPLIED: &str = "NetworkPolicyApplied";
+/// The autoscaler can read the jobs of the agent pool and scale the agents.
+pub const AUTOSCALING: &str = "Autoscaling";
+/// The client certificate of the Key Vault service principal is valid and does not expire soon.
+pub const CERTIFICATE_VALID: &str = "CertificateValid";
+
+impl CDBootstrapStatus {
+    /// Returns the condition of the given type, if it has been reported.
+    pub fn condition(&self, type_: &str) -> Option<&Condition> {
+        self.conditions.iter().find(|c| c.type_ == type_)
+    }
+
+    /// Returns true if the condition of the given type has status `True`.
+    pub fn is_true(&self, type_: &str) -> bool {
+        self.condition(type_)
+            .is_some_and(|c| c.status == ConditionStatus::True)
+    }
+
+    /// Adds or updates a condition. The `lastTransitionTime` is only moved when the status of
+    /// the condition actually changes, as is customary for Kubernetes conditions.
+    pub fn set_condition(
+        &mut self,
+        type_: &str,
+        status: ConditionStatus,
+        reason: &str,
+        message: &str,
+    ) {
+        let last_transition_time = match self.condition(type_) {
+            Some(existing) if existing.status == status => existing.last_transition_time.clone(),
+            _ => now(),
+        };
+
+        let condition = Condition {
+            type_: type_.to_owned(),
+            status,
+            reason: reason.to_owned(),
+            message: message.to_owned(),
+            last_transition_time,
+        };
+
+        match self.conditions.iter_mut().find(|c| c.type_ == type_) {
+            Some(existing) => *existing = condition,
+            None => self.conditions.push(condition),
+        }
+    }
+
+    /// Removes the condition of the given type, e.g. when the feature it reports on is disabled.
+    pub fn remove_condition(&mut self, type_: &str) {
+        self.conditions.retain(|c| c.type_ != type_);
+    }
+
+    /// Derives the `Ready` condition from the other conditions and moves the phase along.
+    /// A phase explicitly set during this reconciliation, e.g. `Degraded`, is kept when the
+    /// resource is not ready.
+    pub fn refresh(&mut self) {
+        let network_policy_failed = self
+            .condition(NETWORK_POLICY_APPLIED)
+            .is_some_and(|c| c.status == ConditionStatus::False);
+
+        let waiting_for: Vec<&str> = [SECRETS_RESOLVED, AGENTS_AVAILABLE]
+            .into_iter()
+            .filter(|type_| !self.is_true(type_))
+            .chain(network_policy_failed.then_some(NETWORK_POLICY_APPLIED))
+            .collect();
+
+        if waiting_for.is_empty() {
+            self.set_condition(
+                READY,
+                ConditionStatus::True,
+                "Reconciled",
+                "All subresources are in desired state and the agents are available",
+            );
+            self.phase = Phase::Running;
+        } else {
+            self.set_condition(
+                READY,
+                ConditionStatus::False,
+                "NotReady",
+                &format!("Waiting for {}", waiting_for.join(", ")),
+            );
+            if self.phase == Phase::Pending || self.phase == Phase::Running {
+                self.phase = Phase::Provisioning;
+            }
+        }
+    }
+}
+
+/// Starts the status of a new reconciliation from the last reported status, so conditions keep
+/// their `lastTransitionTime`. The phase is reset and decided again during this reconciliation.
+pub fn observe(cr: &CDBootstrap) -> CDBootstrapStatus {
+    let mut status = cr.status.clone().unwrap_or_default();
+    status.phase = Phase::Pending;
+    status.retry = None;
+    status.observed_generation = cr.metadata.generation;
+    status
+}
+
+/// Moves the `lastReconcileTime` of the status along, if it differs from the previously reported
+/// status. Returns false if nothing changed, so the status does not need to be written.
+pub fn stamp(previous: Option<&CDBootstrapStatus>, status: &mut CDBootstrapStatus) -> bool {
+    if let Some(previous) = previous {
+        status.last_reconcile_time = previous.last_reconcile_time.clone();
+        if status == previous {
+            return false;
+        }
+    }
+    status.last_reconcile_time = Some(now());
+    true
+}
+
+/// Writes the status built up by a reconciliation of the `CDBootstrap` resource, unless it is
+/// the status already reported. A reconciliation that changes nothing does not write the status,
+/// nor does it move the `lastReconcileTime`.
+pub async fn update(
+    client: Client,
+    cr: &CDBootstrap,
+    status: &mut CDBootstrapStatus,
+) -> Result<(), Error> {
+    if !stamp(cr.status.as_ref(), status) {
+        return Ok(());
+    }
+    let namespace = cr.namespace().unwrap_or(String::from("default"));
+    patch(client, &cr.name_any(), &namespace, status)
+        .await
+        .map(|_| ())
+}
+
+/// Writes the given status to the status subresource of the `CDBootstrap` resource.
 pub async fn patch(
     client: Client,
     name: &str,
     namespace: &str,
-    success: bool,
+    status: &CDBootstrapStatus,
 ) -> Result<CDBootstrap, Error> {
     let api: Api<CDBootstrap> = Api::namespaced(client, namespace);
 
-    let data: Value = json!({
-        "status": CDBootstrapStatus { succeeded: success }
-    });
+    let data: Value = json!({ "status": status });
 
     api.patch_status(name, &PatchParams::default(), &Patch::Merge(&data))
         .await
 }
 
+/// Marks the latest status of the `CDBootstrap` resource as not ready. The latest status is
+/// fetched first, so the detailed conditions written by the failed reconciliation are kept.
+///
+/// # Arguments:
+/// - `phase` - Phase to report, `Failed` for errors that are not retried.
+/// - `reason` - CamelCase reason for the `Ready` condition.
+/// - `message` - Human readable description of the failure.
+/// - `retry` - Backoff of the failed reconciliation, `None` if it is not retried.
+pub async fn fail(
+    client: Client,
+    name: &str,
+    namespace: &str,
+    phase: Phase,
+    reason: &str,
+    message: &str,
+    retry: Option<RetryStatus>,
+) -> Result<CDBootstrap, Error> {
+    let api: Api<CDBootstrap> = Api::namespaced(client.clone(), namespace);
+
+    let cr = api.get_status(name).await?;
+    let mut status = cr.status.clone().unwrap_or_default();
+    status.phase = phase;
+    status.observed_generation = cr.metadata.generation;
+    status.retry = retry;
+    status.set_condition(READY, ConditionStatus::False, reason, message);
+    if !stamp(cr.status.as_ref(), &mut status) {
+        return Ok(cr);
+    }
+
+    patch(client, name, namespace, &status).await
+}
+
 pub async fn print(client: Client, name: &str, namespace: &str) -> Result<(), Error> {
     let api: Api<CDBootstrap> = Api::namespaced(client, namespace);
 
     let cdb = api.get_status(name).await?;
+    let status = cdb.status.clone().unwrap_or_default();
 
     info!(
-        "Got status succeeded {:?} for custom resource {} in namespace {}",
-        cdb.clone()
-            .status
-            .unwrap_or(CDBootstrapStatus { succeeded: false })
-            .succeeded,
+        "Got status phase {:?} ready {} for custom resource {} in namespace {}",
+        status.phase,
+        status.is_true(READY),
         cdb.name_any(),
         namespace
     );
@@ -41,6 +208,11 @@ pub async fn print(client: Client, name: &str, namespace: &str) -> Result<(), Er
     Ok(())
 }
 
+/// Returns the current time as an RFC 3339 timestamp, as recorded in the status.
+pub fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
 ////////////////////////////////////////////////////
 /// NOT USED
 
@@ -63,7 +235,9 @@ pub async fn replace(
             // Updates need to provide our last observed version:
             "resourceVersion": md.resource_version(),
         },
-        "status": CDBootstrapStatus { succeeded: success }
+        "status": {
+            "phase": if success { Phase::Running } else { Phase::Degraded }
+        }
     });
 
     let mut cdb = api.get(name).await?; // retrieve partial object
@@ -93,7 +267,7 @@ pub async fn patch_spec_label_status_debug(
             "replicas": 4
         },
         "status": {
-            "succeeded": true
+            "phase": "Running"
         }
     });
 
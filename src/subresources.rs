@@ -4,68 +4,348 @@ use k8s_openapi::api::core::v1::{
 };
 use k8s_openapi::api::networking::v1::NetworkPolicy;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::{LabelSelector, OwnerReference};
+use k8s_openapi::ByteString;
 use kube::api::{DeleteParams, ObjectMeta, Patch, PatchParams, PostParams};
-use kube::{Api, Client, Error, Resource, ResourceExt};
+use kube::{Api, Client, Resource, ResourceExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use serde_json::{json, Value};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
 use std::str::from_utf8;
 use tracing::*;
 
-use crate::crd::CDBootstrap;
+use crate::autoscaler;
+use crate::crd::{CDBootstrap, PortSpec, Protocol};
+use crate::defaults::AgentImage;
+use crate::drift;
+use crate::error::Error;
+use crate::provider::{AgentToken, CiProvider};
+use crate::service_tags::ServiceTag;
+
+/// Field manager the subresources are written with by server-side apply.
+pub const FIELD_MANAGER: &str = "cdbootstrap-operator";
+
+/// Field manager of the agent token in the agent Secret. The token is written separately from
+/// the Secret itself, with a field manager of its own, so applying the Secret does not remove it.
+pub const TOKEN_FIELD_MANAGER: &str = "cdbootstrap-operator-token";
+
+/// Writes a subresource with server-side apply under the `cdbootstrap-operator` field manager,
+/// creating it when it does not exist. Fields the operator does not render, such as containers
+/// added by a sidecar injector, are left as is. A field this operator applies, but another
+/// manager set to a different value, is not overwritten: the apply fails with
+/// `Error::SubresourceConflict`, naming the conflicting manager and fields. The replicas of the
+/// agent Deployment are the exception, see `replicas_manager`.
+async fn server_side_apply<K>(api: &Api<K>, name: &str, resource: &K) -> Result<K, Error>
+where
+    K: Clone + Debug + DeserializeOwned + Serialize + Resource<DynamicType = ()>,
+{
+    match api
+        .patch(
+            name,
+            &PatchParams::apply(FIELD_MANAGER),
+            &Patch::Apply(resource),
+        )
+        .await
+    {
+        Ok(applied) => Ok(applied),
+        Err(e) if is_conflict(&e) => Err(Error::SubresourceConflict {
+            kind: K::kind(&()).into_owned(),
+            message: match e {
+                kube::Error::Api(response) => response.message,
+                e => e.to_string(),
+            },
+        }),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Converts a rendered manifest to the subresource of type `K`.
+fn from_manifest<K: DeserializeOwned>(kind: &'static str, manifest: Value) -> Result<K, Error> {
+    serde_json::from_value(manifest).map_err(|source| Error::ManifestSerialization { kind, source })
+}
+
+/// Returns true if the live subresource drifted from the rendered one, or does not exist.
+async fn live_drift<K>(api: &Api<K>, name: &str, desired: &K) -> Result<bool, Error>
+where
+    K: Clone + Debug + DeserializeOwned + Serialize,
+{
+    match api.get_opt(name).await? {
+        Some(live) => Ok(drift::drifted(desired, &live)),
+        None => Ok(true),
+    }
+}
+
+/// Returns true if a request failed with reason `Conflict`: a server-side apply on fields owned
+/// by another field manager, or a replace at a stale `resourceVersion`, such as a Lease written
+/// by another replica first. Other `409` responses, e.g. `AlreadyExists`, are not conflicts.
+pub fn is_conflict(error: &kube::Error) -> bool {
+    matches!(error, kube::Error::Api(response) if response.code == 409 && response.reason == "Conflict")
+}
+
+/// Returns the field manager other than the operator that owns the replicas of the live
+/// Deployment, e.g. `kube-controller-manager` for a HorizontalPodAutoscaler or `keda-operator`.
+/// An autoscaler takes the replicas over by scaling the Deployment, from then on they are left
+/// out of the applied Deployment, so the apply does not conflict with the autoscaler. Only the
+/// server-side apply of the operator counts as its own: replicas written by an `Update` under the
+/// same manager name are owned by another field manager as far as the apply is concerned.
+pub fn replicas_manager(deployment: &Deployment) -> Option<String> {
+    deployment
+        .metadata
+        .managed_fields
+        .as_ref()?
+        .iter()
+        .filter(|entry| {
+            entry.manager.as_deref() != Some(FIELD_MANAGER)
+                || entry.operation.as_deref() != Some("Apply")
+        })
+        .find(|entry| {
+            entry
+                .fields_v1
+                .as_ref()
+                .is_some_and(|fields| fields.0.pointer("/f:spec/f:replicas").is_some())
+        })
+        .and_then(|entry| entry.manager.clone())
+}
+
+/// Returns the keys of the Secret data owned by the field manager. Keys written as `stringData`
+/// are recorded as such in the managed fields, they are included as well.
+pub fn owned_data_keys(secret: &Secret, manager: &str) -> Vec<String> {
+    secret
+        .metadata
+        .managed_fields
+        .iter()
+        .flatten()
+        .filter(|entry| entry.manager.as_deref() == Some(manager))
+        .filter_map(|entry| entry.fields_v1.as_ref())
+        .flat_map(|fields| {
+            ["f:data", "f:stringData"]
+                .into_iter()
+                .filter_map(|field| fields.0.get(field)?.as_object())
+                .flat_map(|data| data.keys())
+                .filter_map(|key| key.strip_prefix("f:"))
+                .map(String::from)
+                .collect::<Vec<String>>()
+        })
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect()
+}
+
+/// Annotation of the agent pod template with a checksum of the agent ConfigMap and Secret data.
+/// The agents read both as environment variables at startup, so a change of the data, e.g. an
+/// injected or rotated token, changes the pod template and rolls out new agent pods. Only the
+/// Secret keys in `CiProvider::restart_keys` are included, so e.g. the renewal of a short-lived
+/// registration token or its `<key>_EXPIRES_AT` does not restart the agents.
+pub const CONFIG_CHECKSUM_ANNOTATION: &str = "cndev.nl/config-checksum";
+
+/// Returns the checksum of the ConfigMap data and the `keys` of the Secret data, see
+/// `CONFIG_CHECKSUM_ANNOTATION`.
+pub fn config_checksum(
+    config: &BTreeMap<String, String>,
+    secret: &BTreeMap<String, ByteString>,
+    keys: &[&str],
+) -> String {
+    let secret: BTreeMap<&String, &ByteString> = secret
+        .iter()
+        .filter(|(key, _)| keys.contains(&key.as_str()))
+        .collect();
+    drift::spec_hash(&json!({ "configMap": config, "secret": secret }))
+}
+
+/// Labels of all subresources of a `CDBootstrap` resource and of the agent pods, following the
+/// Kubernetes recommended `app.kubernetes.io` labels.
+pub fn agent_labels(cr: &CDBootstrap) -> BTreeMap<String, String> {
+    let mut labels = agent_selector(cr);
+    labels.insert("app.kubernetes.io/component".to_owned(), "agent".to_owned());
+    labels.insert(
+        "app.kubernetes.io/managed-by".to_owned(),
+        "cdbootstrap-operator".to_owned(),
+    );
+    labels
+}
+
+/// Labels selecting the agent pods of a single `CDBootstrap` resource, used by the Deployment
+/// selector and the NetworkPolicy. Unique per resource within a namespace.
+pub fn agent_selector(cr: &CDBootstrap) -> BTreeMap<String, String> {
+    BTreeMap::from([
+        (
+            "app.kubernetes.io/name".to_owned(),
+            "cdbootstrap-agent".to_owned(),
+        ),
+        ("app.kubernetes.io/instance".to_owned(), cr.name_any()),
+    ])
+}
 
 pub struct Agent {}
 
 impl Agent {
-    /// Deploys a new or updates an existing deployment of `n` pods with the `nginx:latest`,
-    /// where `n` is the number of `replicas` given.
+    /// Deploys a new or updates an existing deployment of `n` pods with the agent image,
+    /// where `n` is the number of `replicas` given. The Deployment is written with server-side
+    /// apply, see `server_side_apply`. Returns `None` when nothing was applied, as the existing
+    /// Deployment is being deleted to migrate its selector; it is created again once it is gone.
     ///
     /// # Arguments
     /// - `client` - A Kubernetes client to create/update the Deployment with.
     /// - `name` - Name of the Deployment to be created/updated
     /// - `replicas` - Number of pod replicas for the Deployment to contain
     /// - `namespace` - Namespace to create/update the Kubernetes Deployment in.
+    /// - `image` - Image settings of the agent container, see `AgentDefaults::resolve`.
     pub async fn apply(
         client: Client,
         name: &str,
         namespace: &str,
         cr: &CDBootstrap,
-    ) -> Result<Deployment, Error> {
-        // check for existing Deployment
+        provider: &dyn CiProvider,
+        image: &AgentImage,
+    ) -> Result<Option<Deployment>, Error> {
         let api: Api<Deployment> = Api::namespaced(client.clone(), namespace);
 
-        if let Ok(_) = api.get(name).await {
-            info!("Deployment {} found in namespace {}", name, namespace);
-            info!(
-                "Update Deployment {} in namespace {} to desired state",
-                name, namespace
-            );
-            api.replace(
-                name,
-                &PostParams::default(),
-                &Agent::new(name, namespace, cr),
-            )
-            .await
-        } else {
-            info!("Deployment {} not found in namespace {}", name, namespace);
-            info!("Creating Deployment {} in namespace {}", name, namespace);
-            api.create(&PostParams::default(), &Agent::new(name, namespace, cr))
-                .await
+        let live = api.get_opt(name).await?;
+        if let Some(deployment) = &live {
+            // The selector of a Deployment is immutable. Deployments created before the
+            // `app.kubernetes.io` labels were introduced select `app: example`, so they are
+            // recreated with the new selector. The old agent pods are deleted first, in the
+            // foreground, and the Deployment is only applied again once it is gone: its deletion
+            // reconciles the resource, see `Controller::owns`.
+            if deployment.metadata.deletion_timestamp.is_some() {
+                info!(
+                    "Waiting for Deployment {} in namespace {} to be deleted",
+                    name, namespace
+                );
+                return Ok(None);
+            }
+            if !Agent::selects(deployment, cr) {
+                info!(
+                    "Recreating Deployment {} in namespace {} to migrate its selector",
+                    name, namespace
+                );
+                api.delete(name, &DeleteParams::foreground()).await?;
+                return Ok(None);
+            }
         }
+
+        let replicas = autoscaler::applied_replicas(cr, live.as_ref());
+        let checksum = Agent::checksum(client, name, namespace, provider).await?;
+        let mut deployment = Agent::new(name, namespace, cr, provider, image, &checksum, replicas)?;
+        Agent::yield_replicas(&mut deployment, live.as_ref());
+        info!("Applying Deployment {} in namespace {}", name, namespace);
+        server_side_apply(&api, name, &deployment).await.map(Some)
     }
 
-    fn new(name: &str, namespace: &str, cr: &CDBootstrap) -> Deployment {
-        let labels: BTreeMap<String, String> = [("app".to_owned(), cr.name_any().to_owned())]
-            .iter()
-            .cloned()
-            .collect();
+    /// Leaves the replicas out of the rendered Deployment when another field manager owns them
+    /// in the live Deployment, see `replicas_manager`.
+    fn yield_replicas(deployment: &mut Deployment, live: Option<&Deployment>) {
+        let Some(manager) = live.and_then(replicas_manager) else {
+            return;
+        };
+        debug!(
+            "Replicas of Deployment {} are managed by {}, leaving them as is",
+            deployment.name_any(),
+            manager
+        );
+        if let Some(spec) = deployment.spec.as_mut() {
+            spec.replicas = None;
+        }
+    }
+
+    /// Returns the checksum of the rendered ConfigMap data and the live Secret data, see
+    /// `config_checksum`. A missing Secret counts as empty, it is created along with the
+    /// Deployment.
+    async fn checksum(
+        client: Client,
+        name: &str,
+        namespace: &str,
+        provider: &dyn CiProvider,
+    ) -> Result<String, Error> {
+        let api: Api<Secret> = Api::namespaced(client, namespace);
+        let secret = api
+            .get_opt(name)
+            .await?
+            .and_then(|secret| secret.data)
+            .unwrap_or_default();
+        Ok(config_checksum(
+            &provider.config(),
+            &secret,
+            &provider.restart_keys(),
+        ))
+    }
 
-        let image = String::from("ghcr.io/bartvanbenthem/azp-agent-alpine:latest");
+    fn new(
+        name: &str,
+        namespace: &str,
+        cr: &CDBootstrap,
+        provider: &dyn CiProvider,
+        image: &AgentImage,
+        checksum: &str,
+        replicas: i32,
+    ) -> Result<Deployment, Error> {
+        let labels = agent_labels(cr);
 
         let owner = cr
             .controller_owner_ref(&())
             .unwrap_or(OwnerReference::default());
 
         // Define the NetworkPolicy configuration as JSON
+        let mut container = json!({
+            "name": name,
+            "image": image.image.clone(),
+            "env": provider.env(name),
+        });
+        if let Some(pull_policy) = image.pull_policy {
+            container["imagePullPolicy"] = json!(pull_policy.as_str());
+        }
+        if let Some(command) = provider.command() {
+            container["command"] = json!(command);
+        }
+        let volume_mounts = provider.volume_mounts();
+        if !volume_mounts.is_empty() {
+            container["volumeMounts"] = json!(volume_mounts);
+        }
+
+        let selector = agent_selector(cr);
+        let pod = cr.spec.agent.pod.clone().unwrap_or_default();
+        if let Some(resources) = &pod.resources {
+            container["resources"] = json!(resources);
+        }
+
+        let mut pod_spec = json!({
+            "containers": [container],
+            "volumes": provider.volumes(name),
+            "imagePullSecrets": image
+                .pull_secrets
+                .iter()
+                .map(|secret| json!({ "name": secret }))
+                .collect::<Vec<Value>>(),
+            "nodeSelector": pod.node_selector,
+            "tolerations": pod.tolerations,
+            "topologySpreadConstraints": pod.topology_spread_constraints,
+        });
+        // Without an affinity in the specification the agents prefer to run on different nodes,
+        // so a single node failure does not take down all agents of a pool
+        pod_spec["affinity"] = if pod.affinity.is_null() {
+            json!({
+                "podAntiAffinity": {
+                    "preferredDuringSchedulingIgnoredDuringExecution": [
+                        {
+                            "weight": 100,
+                            "podAffinityTerm": {
+                                "labelSelector": {
+                                    "matchLabels": selector
+                                },
+                                "topologyKey": "kubernetes.io/hostname"
+                            }
+                        }
+                    ]
+                }
+            })
+        } else {
+            pod.affinity
+        };
+        if let Some(priority_class_name) = pod.priority_class_name {
+            pod_spec["priorityClassName"] = json!(priority_class_name);
+        }
+
         let deployment_json: Value = json!({
             "apiVersion": "apps/v1",
             "kind": "Deployment",
@@ -84,87 +364,23 @@ impl Agent {
                 ]
             },
             "spec": {
-                "replicas": cr.spec.replicas,
+                "replicas": replicas,
                 "selector": {
-                    "matchLabels": {
-                        "app": "example"
-                    }
+                    "matchLabels": selector
                 },
                 "template": {
                     "metadata": {
-                        "labels": {
-                            "app": "example"
+                        "labels": labels,
+                        "annotations": {
+                            CONFIG_CHECKSUM_ANNOTATION: checksum
                         }
                     },
-                    "spec": {
-                        "containers": [
-                            {
-                                "name": name,
-                                "image": image.clone(),
-                                "env": [
-                                    {
-                                        "name": "AZP_TOKEN",
-                                        "valueFrom": {
-                                            "secretKeyRef": {
-                                                "name": name,
-                                                "key": "AZP_TOKEN",
-                                                "optional": true,
-                                            },
-                                        },
-                                    },
-                                    {
-                                        "name": "SPN_SECRET",
-                                        "valueFrom": {
-                                            "secretKeyRef": {
-                                                "name": name,
-                                                "key": "SPN_SECRET",
-                                                "optional": true,
-                                            },
-                                        },
-                                    },
-                                    {
-                                        "name": "AZP_URL",
-                                        "valueFrom": {
-                                            "configMapKeyRef": {
-                                                "name": name,
-                                                "key": "AZP_URL",
-                                                "optional": true,
-                                            },
-                                        },
-                                    },
-                                    {
-                                        "name": "AZP_POOL",
-                                        "valueFrom": {
-                                            "configMapKeyRef": {
-                                                "name": name,
-                                                "key": "AZP_POOL",
-                                                "optional": true,
-                                            },
-                                        },
-                                    },
-                                ]
-                            }
-                        ]
-                    }
+                    "spec": pod_spec
                 }
             }
         });
 
-        // Convert the JSON to Deployment struct using serde
-        let deployment_result: Result<Deployment, serde_json::Error> =
-            serde_json::from_value(deployment_json);
-        let deployment = match deployment_result {
-            Ok(deployment) => deployment,
-            Err(err) => {
-                error!(
-                    "Error creating Deployment {} applying default",
-                    kube::Error::SerdeError(err)
-                );
-                let default_deployment: Deployment = Default::default();
-                return default_deployment;
-            }
-        };
-        deployment
+        from_manifest("Deployment", deployment_json)
     }
 
     /// Deletes an existing deployment.
@@ -174,45 +390,87 @@ impl Agent {
     /// - `name` - Name of the deployment to delete
     /// - `namespace` - Namespace the existing deployment resides in
     ///
-    /// Note: It is assumed the deployment exists for simplicity. Otherwise returns an Error.
+    /// Note: A missing Deployment is not an error, it may have been deleted by an earlier attempt.
     pub async fn delete(client: Client, name: &str, namespace: &str) -> Result<(), Error> {
         let api: Api<Deployment> = Api::namespaced(client, namespace);
-        api.delete(name, &DeleteParams::default()).await?;
-        Ok(())
+        match api.delete(name, &DeleteParams::default()).await {
+            Ok(_) => Ok(()),
+            Err(kube::Error::Api(response)) if response.code == 404 => Ok(()),
+            Err(e) => Err(e.into()),
+        }
     }
 
-    pub async fn desired_state(
+    /// Returns true if the live Deployment differs from the rendered Deployment, or does not
+    /// exist. Covers the selector, the replicas unless another manager owns them, and the whole
+    /// pod template, see `drift::drifted`.
+    pub async fn drift(
         client: Client,
-        cr: &CDBootstrap,
         name: &str,
         namespace: &str,
+        cr: &CDBootstrap,
+        provider: &dyn CiProvider,
+        image: &AgentImage,
     ) -> Result<bool, Error> {
-        // Fetch the existing deployment
-        let deployment_api: Api<Deployment> = Api::namespaced(client.clone(), namespace);
-        let existing_deployment_result = deployment_api.get(name).await;
-
-        let existing_deployment = match existing_deployment_result {
-            Ok(existing_deployment) => existing_deployment,
-            Err(_) => {
-                // Handle the case when the deployment is not found
-                info!(
-                    "Not able to find the existing {} deployment in {}",
-                    name, namespace
-                );
-                return Ok(false);
-            }
+        let checksum = Agent::checksum(client.clone(), name, namespace, provider).await?;
+        let api: Api<Deployment> = Api::namespaced(client, namespace);
+        let Some(live) = api.get_opt(name).await? else {
+            return Ok(true);
         };
+        let replicas = autoscaler::applied_replicas(cr, Some(&live));
+        let mut deployment = Agent::new(name, namespace, cr, provider, image, &checksum, replicas)?;
+        Agent::yield_replicas(&mut deployment, Some(&live));
+        Ok(drift::drifted(&deployment, &live))
+    }
 
-        let current_replicas = existing_deployment
+    /// Returns true if the Deployment selects the agent pods by the labels of `agent_selector`.
+    fn selects(deployment: &Deployment, cr: &CDBootstrap) -> bool {
+        deployment
             .spec
-            .and_then(|spec| spec.replicas)
-            .unwrap_or(1);
+            .as_ref()
+            .and_then(|spec| spec.selector.match_labels.as_ref())
+            .is_some_and(|match_labels| *match_labels == agent_selector(cr))
+    }
 
-        if current_replicas == cr.spec.replicas {
-            return Ok(true);
-        } else {
-            return Ok(false);
-        }
+    /// Sets the number of agent pods of an existing Deployment. The whole Deployment is applied
+    /// with the new replicas, so the replicas stay owned by the `cdbootstrap-operator` field
+    /// manager through server-side apply, and the next apply keeps them, see `applied_replicas`.
+    ///
+    /// # Arguments:
+    /// - `client` - A Kubernetes client to apply the Deployment with
+    /// - `name` - Name of the deployment to scale
+    /// - `namespace` - Namespace the existing deployment resides in
+    /// - `image` - Image settings of the agent container, see `AgentDefaults::resolve`.
+    /// - `replicas` - Number of pod replicas for the Deployment to contain
+    pub async fn scale(
+        client: Client,
+        name: &str,
+        namespace: &str,
+        cr: &CDBootstrap,
+        provider: &dyn CiProvider,
+        image: &AgentImage,
+        replicas: i32,
+    ) -> Result<Deployment, Error> {
+        let api: Api<Deployment> = Api::namespaced(client.clone(), namespace);
+        let live = api.get(name).await?;
+        let checksum = Agent::checksum(client, name, namespace, provider).await?;
+        let mut deployment = Agent::new(name, namespace, cr, provider, image, &checksum, replicas)?;
+        Agent::yield_replicas(&mut deployment, Some(&live));
+        server_side_apply(&api, name, &deployment).await
+    }
+
+    /// Returns the number of ready agent pods as reported by the Deployment status.
+    ///
+    /// # Arguments:
+    /// - `client` - A Kubernetes client to read the Deployment with
+    /// - `name` - Name of the deployment to read
+    /// - `namespace` - Namespace the existing deployment resides in
+    pub async fn ready_replicas(client: Client, name: &str, namespace: &str) -> Result<i32, Error> {
+        let api: Api<Deployment> = Api::namespaced(client, namespace);
+        let deployment = api.get(name).await?;
+        Ok(deployment
+            .status
+            .and_then(|status| status.ready_replicas)
+            .unwrap_or(0))
     }
 }
 
@@ -224,41 +482,43 @@ impl AgentConfig {
         name: &str,
         namespace: &str,
         cr: &CDBootstrap,
+        provider: &dyn CiProvider,
     ) -> Result<ConfigMap, Error> {
-        // check for existing ConfigMap
         let api: Api<ConfigMap> = Api::namespaced(client.clone(), namespace);
 
-        if let Ok(_) = api.get(&name).await {
-            info!("ConfigMap {} found in namespace {}", name, namespace);
-            info!(
-                "Update ConfigMap {} in namespace {} to desired state",
-                name, namespace
-            );
-            api.replace(
-                name,
-                &PostParams::default(),
-                &AgentConfig::new(name, namespace, cr),
-            )
-            .await
-        } else {
-            info!("ConfigMap {} not found in namespace {}", name, namespace);
-            info!("Creating ConfigMap {} in namespace {}", name, namespace);
-            api.create(
-                &PostParams::default(),
-                &AgentConfig::new(name, namespace, cr),
-            )
-            .await
-        }
+        info!("Applying ConfigMap {} in namespace {}", name, namespace);
+        server_side_apply(
+            &api,
+            name,
+            &AgentConfig::new(name, namespace, cr, provider)?,
+        )
+        .await
     }
 
-    fn new(name: &str, namespace: &str, cr: &CDBootstrap) -> ConfigMap {
-        let labels: BTreeMap<String, String> = [("app".to_owned(), cr.name_any().to_owned())]
-            .iter()
-            .cloned()
-            .collect();
+    /// Returns true if the live ConfigMap differs from the rendered ConfigMap, or does not exist.
+    pub async fn drift(
+        client: Client,
+        name: &str,
+        namespace: &str,
+        cr: &CDBootstrap,
+        provider: &dyn CiProvider,
+    ) -> Result<bool, Error> {
+        let api: Api<ConfigMap> = Api::namespaced(client, namespace);
+        live_drift(
+            &api,
+            name,
+            &AgentConfig::new(name, namespace, cr, provider)?,
+        )
+        .await
+    }
 
-        let url = cr.spec.url.clone();
-        let pool = cr.spec.pool.clone();
+    fn new(
+        name: &str,
+        namespace: &str,
+        cr: &CDBootstrap,
+        provider: &dyn CiProvider,
+    ) -> Result<ConfigMap, Error> {
+        let labels = agent_labels(cr);
 
         let owner = cr
             .controller_owner_ref(&())
@@ -282,31 +542,10 @@ impl AgentConfig {
                       }
                 ]
                },
-                "data": {
-                  "AZP_POOL": pool,
-                  "AZP_URL": url,
-                  //"AZP_WORK": "placeholder",
-                  //"AZP_AGENT_NAME": "placeholder",
-                  //"AGENT_MTU_VALUE": "placeholder"
-                }
-
+                "data": provider.config()
         });
 
-        // Convert the JSON to NetworkPolicy struct using serde
-        let configmap_result: Result<ConfigMap, serde_json::Error> =
-            serde_json::from_value(configmap_json);
-        let configmap = match configmap_result {
-            Ok(configmap) => configmap,
-            Err(err) => {
-                error!(
-                    "Error creating ConfigMap {} applying default",
-                    kube::Error::SerdeError(err)
-                );
-                let default_configmap: ConfigMap = Default::default();
-                return default_configmap;
-            }
-        };
-        configmap
+        from_manifest("ConfigMap", configmap_json)
     }
 
     /// Deletes an existing ConfigMap.
@@ -316,11 +555,14 @@ impl AgentConfig {
     /// - `name` - Name of the deployment to delete
     /// - `namespace` - Namespace the existing ConfigMap resides in
     ///
-    /// Note: It is assumed the deployment exists for simplicity. Otherwise returns an Error.
+    /// Note: A missing ConfigMap is not an error, it may have been deleted by an earlier attempt.
     pub async fn delete(client: Client, name: &str, namespace: &str) -> Result<(), Error> {
         let api: Api<ConfigMap> = Api::namespaced(client, namespace);
-        api.delete(&name, &DeleteParams::default()).await?;
-        Ok(())
+        match api.delete(name, &DeleteParams::default()).await {
+            Ok(_) => Ok(()),
+            Err(kube::Error::Api(response)) if response.code == 404 => Ok(()),
+            Err(e) => Err(e.into()),
+        }
     }
 }
 
@@ -333,38 +575,80 @@ impl AgentSecret {
         namespace: &str,
         cr: &CDBootstrap,
     ) -> Result<Secret, Error> {
-        // check for existing Secret
         let api: Api<Secret> = Api::namespaced(client.clone(), namespace);
 
-        if let Ok(_) = api.get(name).await {
-            info!("Secret {} found in namespace {}", name, namespace);
-            info!(
-                "Update Secret {} in namespace {} to desired state",
-                name, namespace
-            );
-            api.replace(
-                name,
-                &PostParams::default(),
-                &AgentSecret::new(name, namespace, cr),
-            )
-            .await
-        } else {
-            info!("Secret {} not found in namespace {}", name, namespace);
-            info!("Creating Secret {} in namespace {}", name, namespace);
-            api.create(
-                &PostParams::default(),
-                &AgentSecret::new(name, namespace, cr),
-            )
-            .await
+        if let Some(live) = api.get_opt(name).await? {
+            AgentSecret::migrate_data(&api, name, namespace, &live).await?;
         }
+        info!("Applying Secret {} in namespace {}", name, namespace);
+        server_side_apply(&api, name, &AgentSecret::new(name, namespace, cr)?).await
     }
 
-    fn new(name: &str, namespace: &str, cr: &CDBootstrap) -> Secret {
-        let labels: BTreeMap<String, String> = [("app".to_owned(), cr.name_any().to_owned())]
-            .iter()
-            .cloned()
+    /// Hands the data written by earlier versions over to `TOKEN_FIELD_MANAGER`. Those versions
+    /// wrote the token and the `SPN_SECRET` under the `cdbootstrap-operator` field manager, so
+    /// applying the Secret without data would remove them. The token manager applies the same
+    /// values first, so they are kept when the operator gives up its ownership.
+    async fn migrate_data(
+        api: &Api<Secret>,
+        name: &str,
+        namespace: &str,
+        live: &Secret,
+    ) -> Result<(), Error> {
+        let legacy = owned_data_keys(live, FIELD_MANAGER);
+        if legacy.is_empty() {
+            return Ok(());
+        }
+        let data = live.data.clone().unwrap_or_default();
+        let string_data: BTreeMap<String, String> = legacy
+            .into_iter()
+            .chain(owned_data_keys(live, TOKEN_FIELD_MANAGER))
+            .filter_map(|key| {
+                let value = String::from_utf8_lossy(&data.get(&key)?.0).into_owned();
+                Some((key, value))
+            })
             .collect();
 
+        info!(
+            "Migrating {:?} of Secret {} in namespace {} to field manager {}",
+            string_data.keys(),
+            name,
+            namespace,
+            TOKEN_FIELD_MANAGER
+        );
+        api.patch(
+            name,
+            &PatchParams::apply(TOKEN_FIELD_MANAGER),
+            &Patch::Apply(Secret {
+                metadata: ObjectMeta {
+                    name: Some(name.to_owned()),
+                    namespace: Some(namespace.to_owned()),
+                    ..ObjectMeta::default()
+                },
+                string_data: Some(string_data),
+                ..Secret::default()
+            }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Returns true if the labels, owner or type of the live Secret differ from the rendered
+    /// Secret, or it does not exist. The data of the Secret is not compared.
+    pub async fn drift(
+        client: Client,
+        name: &str,
+        namespace: &str,
+        cr: &CDBootstrap,
+    ) -> Result<bool, Error> {
+        let api: Api<Secret> = Api::namespaced(client, namespace);
+        live_drift(&api, name, &AgentSecret::new(name, namespace, cr)?).await
+    }
+
+    /// The agent Secret without data. The `SPN_SECRET` is set by the user and the token by
+    /// `set_token`, applying them here would claim or clear them.
+    fn new(name: &str, namespace: &str, cr: &CDBootstrap) -> Result<Secret, Error> {
+        let labels = agent_labels(cr);
+
         let owner = cr
             .controller_owner_ref(&())
             .unwrap_or(OwnerReference::default());
@@ -387,27 +671,10 @@ impl AgentSecret {
                       }
                 ]
                },
-                "data": {
-                  "AZP_TOKEN": null,
-                  "SPN_SECRET": null,
-                }
-
+               "type": "Opaque"
         });
 
-        // Convert the JSON to NetworkPolicy struct using serde
-        let secret_result: Result<Secret, serde_json::Error> = serde_json::from_value(secret_json);
-        let secret = match secret_result {
-            Ok(secret) => secret,
-            Err(err) => {
-                error!(
-                    "Error creating Secret {} applying default",
-                    kube::Error::SerdeError(err)
-                );
-                let default_secret: Secret = Default::default();
-                return default_secret;
-            }
-        };
-        secret
+        from_manifest("Secret", secret_json)
     }
 
     /// Deletes an existing Secret.
@@ -417,11 +684,14 @@ impl AgentSecret {
     /// - `name` - Name of the deployment to delete
     /// - `namespace` - Namespace the existing Secret resides in
     ///
-    /// Note: It is assumed the deployment exists for simplicity. Otherwise returns an Error.
+    /// Note: A missing Secret is not an error, it may have been deleted by an earlier attempt.
     pub async fn delete(client: Client, name: &str, namespace: &str) -> Result<(), Error> {
         let api: Api<Secret> = Api::namespaced(client, namespace);
-        api.delete(&name, &DeleteParams::default()).await?;
-        Ok(())
+        match api.delete(name, &DeleteParams::default()).await {
+            Ok(_) => Ok(()),
+            Err(kube::Error::Api(response)) if response.code == 404 => Ok(()),
+            Err(e) => Err(e.into()),
+        }
     }
 
     pub async fn value_is_set(
@@ -493,12 +763,21 @@ impl AgentSecret {
         Ok(client_secret)
     }
 
-    #[allow(dead_code, unused_variables)]
-    pub async fn set_azp_token(
+    /// Stores the credential the agents register with in the agent Secret, next to the
+    /// `SPN_SECRET`. When the token expires, its expiry is stored under `<key>_EXPIRES_AT`.
+    ///
+    /// # Arguments:
+    /// - `client` - A Kubernetes client to patch the Secret with
+    /// - `name` - Name of the Secret to patch
+    /// - `namespace` - Namespace the existing Secret resides in
+    /// - `key` - Key of the token, see `CiProvider::token_key`
+    /// - `token` - The token to store
+    pub async fn set_token(
         client: Client,
         name: &str,
         namespace: &str,
-        value: &str,
+        key: &str,
+        token: &AgentToken,
     ) -> Result<(), Error> {
         // Retrieve the existing Secret
         let api: Api<Secret> = Api::namespaced(client.clone(), namespace);
@@ -528,24 +807,29 @@ impl AgentSecret {
         let mut data_patch: BTreeMap<String, String> = BTreeMap::new();
 
         // Add key-value pairs to the BTreeMap
-        data_patch.insert("AZP_TOKEN".to_string(), value.to_string());
-        data_patch.insert("SPN_SECRET".to_string(), client_secret);
-
-        let result = api
-            .patch(
-                &name,
-                &PatchParams::apply("cdbootstrap-operator"),
-                &Patch::Apply(Secret {
-                    metadata: ObjectMeta {
-                        name: Some(name.to_owned()),
-                        namespace: Some(namespace.to_owned()),
-                        ..ObjectMeta::default()
-                    },
-                    string_data: Some(data_patch.clone()),
-                    ..Secret::default()
-                }),
-            )
-            .await?;
+        data_patch.insert(key.to_string(), token.value.clone());
+        // Without a client secret, e.g. with workload identity, no SPN_SECRET is claimed
+        if !client_secret.is_empty() {
+            data_patch.insert("SPN_SECRET".to_string(), client_secret);
+        }
+        if let Some(expires_at) = &token.expires_at {
+            data_patch.insert(format!("{}_EXPIRES_AT", key), expires_at.clone());
+        }
+
+        api.patch(
+            name,
+            &PatchParams::apply(TOKEN_FIELD_MANAGER),
+            &Patch::Apply(Secret {
+                metadata: ObjectMeta {
+                    name: Some(name.to_owned()),
+                    namespace: Some(namespace.to_owned()),
+                    ..ObjectMeta::default()
+                },
+                string_data: Some(data_patch.clone()),
+                ..Secret::default()
+            }),
+        )
+        .await?;
 
         Ok(())
     }
@@ -554,48 +838,133 @@ impl AgentSecret {
 pub struct AgentPolicy {}
 
 impl AgentPolicy {
+    /// Creates or updates the egress NetworkPolicy of the agent pods, or removes it when it is
+    /// disabled in the specification. Returns `None` when the policy is disabled.
     pub async fn apply(
         client: Client,
         name: &str,
         namespace: &str,
         cr: &CDBootstrap,
-    ) -> Result<NetworkPolicy, Error> {
-        // check for existing networkpolicy
+        provider: &dyn CiProvider,
+        service_tag: Option<&ServiceTag>,
+    ) -> Result<Option<NetworkPolicy>, Error> {
         let api: Api<NetworkPolicy> = Api::namespaced(client.clone(), namespace);
 
         let precise_name = String::from("allow-egress-".to_owned() + name);
 
-        if let Ok(_) = api.get(&precise_name).await {
-            info!("NetworkPolicy {} found in namespace {}", name, namespace);
-            info!(
-                "Update NetworkPolicy {} in namespace {} to desired state",
-                name, namespace
-            );
-            api.replace(
-                &precise_name,
-                &PostParams::default(),
-                &AgentPolicy::new(&precise_name, namespace, cr),
-            )
-            .await
+        if matches!(&cr.spec.network_policy, Some(policy) if !policy.enabled) {
+            info!("NetworkPolicy {} disabled in namespace {}", name, namespace);
+            AgentPolicy::delete(client, name, namespace).await?;
+            return Ok(None);
+        }
+
+        info!("Applying NetworkPolicy {} in namespace {}", name, namespace);
+        server_side_apply(
+            &api,
+            &precise_name,
+            &AgentPolicy::new(&precise_name, namespace, cr, provider, service_tag)?,
+        )
+        .await
+        .map(Some)
+    }
+
+    /// Returns true if the live NetworkPolicy differs from the rendered NetworkPolicy, does not
+    /// exist, or still exists while it is disabled.
+    pub async fn drift(
+        client: Client,
+        name: &str,
+        namespace: &str,
+        cr: &CDBootstrap,
+        provider: &dyn CiProvider,
+        service_tag: Option<&ServiceTag>,
+    ) -> Result<bool, Error> {
+        let api: Api<NetworkPolicy> = Api::namespaced(client, namespace);
+        let precise_name = format!("allow-egress-{}", name);
+
+        if matches!(&cr.spec.network_policy, Some(policy) if !policy.enabled) {
+            return Ok(api.get_opt(&precise_name).await?.is_some());
+        }
+        live_drift(
+            &api,
+            &precise_name,
+            &AgentPolicy::new(&precise_name, namespace, cr, provider, service_tag)?,
+        )
+        .await
+    }
+
+    /// Returns the egress rules of the agent pods: the `cidrs` on the `ports` of the
+    /// specification, falling back to the address prefixes of the service tag or else the
+    /// endpoints of the provider on 443/TCP, DNS to kube-dns and any extra rules.
+    pub fn egress(
+        cr: &CDBootstrap,
+        provider: &dyn CiProvider,
+        service_tag: Option<&ServiceTag>,
+    ) -> Vec<Value> {
+        let policy = cr.spec.network_policy.clone().unwrap_or_default();
+
+        let cidrs = if !policy.cidrs.is_empty() {
+            policy.cidrs
+        } else if let Some(service_tag) = service_tag {
+            service_tag.address_prefixes.clone()
         } else {
-            info!(
-                "NetworkPolicy {} not found in namespace {}",
-                name, namespace
-            );
-            info!("Creating NetworkPolicy {} in namespace {}", name, namespace);
-            api.create(
-                &PostParams::default(),
-                &AgentPolicy::new(&precise_name, namespace, cr),
-            )
-            .await
+            provider.egress_cidrs()
+        };
+        let ports = if policy.ports.is_empty() {
+            vec![PortSpec {
+                port: 443,
+                protocol: Protocol::Tcp,
+            }]
+        } else {
+            policy.ports
+        };
+
+        let mut egress = vec![json!({
+            "to": cidrs
+                .iter()
+                .map(|cidr| json!({ "ipBlock": { "cidr": cidr } }))
+                .collect::<Vec<Value>>(),
+            "ports": ports,
+        })];
+        if policy.dns {
+            egress.push(json!({
+                "to": [
+                    {
+                        "namespaceSelector": {
+                            "matchLabels": {
+                                "kubernetes.io/metadata.name": "kube-system"
+                            }
+                        },
+                        "podSelector": {
+                            "matchLabels": {
+                                "k8s-app": "kube-dns"
+                            }
+                        }
+                    }
+                ],
+                "ports": [
+                    {
+                        "port": 53,
+                        "protocol": "UDP"
+                    },
+                    {
+                        "port": 53,
+                        "protocol": "TCP"
+                    }
+                ]
+            }));
         }
+        egress.extend(policy.extra_egress);
+        egress
     }
 
-    fn new(name: &str, namespace: &str, cr: &CDBootstrap) -> NetworkPolicy {
-        let labels: BTreeMap<String, String> = [("app".to_owned(), cr.name_any().to_owned())]
-            .iter()
-            .cloned()
-            .collect();
+    fn new(
+        name: &str,
+        namespace: &str,
+        cr: &CDBootstrap,
+        provider: &dyn CiProvider,
+        service_tag: Option<&ServiceTag>,
+    ) -> Result<NetworkPolicy, Error> {
+        let labels = agent_labels(cr);
 
         let owner = cr
             .controller_owner_ref(&())
@@ -621,70 +990,14 @@ impl AgentPolicy {
             },
             "spec": {
                 "podSelector": {
-                    "matchLabels": {
-                        "app": cr.name_any().to_owned(),
-                        // Add other labels as needed
-                    }
+                    "matchLabels": agent_selector(cr)
                 },
-                "egress": [
-                    {
-                        "to": [
-                            {
-                                "ports": [
-                                    {
-                                        "port": 443,
-                                        "protocol": "TCP"
-                                    },
-                                    {
-                                        "port": 443,
-                                        "protocol": "UDP"
-                                    }
-                                ]
-                            }
-                        ],
-                        "to": [
-                            {
-                                "ipBlock": {
-                                    "cidr": "13.107.6.0/24"
-                                }
-                            },
-                            {
-                                "ipBlock": {
-                                    "cidr": "13.107.9.0/24"
-                                }
-                            },
-                            {
-                                "ipBlock": {
-                                    "cidr": "13.107.42.0/24"
-                                }
-                            },
-                            {
-                                "ipBlock": {
-                                    "cidr": "13.107.43.0/24"
-                                }
-                            }
-                        ]
-                    }
-                ],
+                "egress": AgentPolicy::egress(cr, provider, service_tag),
                 "policyTypes": ["Egress"]
             }
         });
 
-        // Convert the JSON to NetworkPolicy struct using serde
-        let network_policy_result: Result<NetworkPolicy, serde_json::Error> =
-            serde_json::from_value(network_policy_json);
-        let network_policy = match network_policy_result {
-            Ok(network_policy) => network_policy,
-            Err(err) => {
-                error!(
-                    "Error creating network policy {} applying default",
-                    kube::Error::SerdeError(err)
-                );
-                let default_network_policy: NetworkPolicy = Default::default();
-                return default_network_policy;
-            }
-        };
-        network_policy
+        from_manifest("NetworkPolicy", network_policy_json)
     }
 
     /// Deletes an existing NetworkPolicy.
@@ -694,12 +1007,15 @@ impl AgentPolicy {
     /// - `name` - Name of the deployment to delete
     /// - `namespace` - Namespace the existing NetworkPolicy resides in
     ///
-    /// Note: It is assumed the deployment exists for simplicity. Otherwise returns an Error.
+    /// Note: A missing NetworkPolicy is not an error, the policy may be disabled.
     pub async fn delete(client: Client, name: &str, namespace: &str) -> Result<(), Error> {
         let precise_name = String::from("allow-egress-".to_owned() + name);
         let api: Api<NetworkPolicy> = Api::namespaced(client, namespace);
-        api.delete(&precise_name, &DeleteParams::default()).await?;
-        Ok(())
+        match api.delete(&precise_name, &DeleteParams::default()).await {
+            Ok(_) => Ok(()),
+            Err(kube::Error::Api(response)) if response.code == 404 => Ok(()),
+            Err(e) => Err(e.into()),
+        }
     }
 }
 
@@ -712,7 +1028,7 @@ pub async fn apply_old(
     name: &str,
     namespace: &str,
     cr: &CDBootstrap,
-) -> Result<Deployment, Error> {
+) -> Result<Deployment, kube::Error> {
     let image = String::from("ghcr.io/bartvanbenthem/azp-agent-alpine:latest");
 
     let mut labels: BTreeMap<String, String> = BTreeMap::new();
@@ -734,7 +1050,7 @@ pub async fn apply_old(
                     ..ObjectMeta::default()
                 },
                 spec: Some(DeploymentSpec {
-                    replicas: Some(cr.spec.replicas),
+                    replicas: Some(cr.spec.agent.replicas),
                     selector: LabelSelector {
                         match_expressions: None,
                         match_labels: Some(labels.clone()),
@@ -785,7 +1101,7 @@ pub async fn apply_old(
                     ..ObjectMeta::default()
                 },
                 spec: Some(DeploymentSpec {
-                    replicas: Some(cr.spec.replicas),
+                    replicas: Some(cr.spec.agent.replicas),
                     selector: LabelSelector {
                         match_expressions: None,
                         match_labels: Some(labels.clone()),
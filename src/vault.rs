@@ -1,15 +1,33 @@
-use anyhow::Error;
-use azure_core::new_http_client;
+use azure_core::auth::TokenCredential;
+use azure_core::error::ErrorKind;
+use azure_core::{new_http_client, StatusCode};
 use azure_identity::{ClientSecretCredential, TokenCredentialOptions};
 use azure_security_keyvault::prelude::*;
-use futures::StreamExt;
-use kube::Client;
-use std::{process, sync::Arc};
+use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::chrono::{self, DateTime, Utc};
+use kube::{Api, Client};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::Instant;
 use tracing::{error, info, warn};
 
-use crate::crd::CDBootstrap;
+use crate::crd::{CDBootstrap, TokenStatus, VaultAuth};
+use crate::credential::{
+    CachedCredential, ClientCertificate, ClientCertificateCredential, ManagedIdentityCredential,
+    WorkloadIdentityCredential,
+};
+use crate::error::Error;
+use crate::identities::IdentityAllowList;
+use crate::metrics::metrics;
+use crate::provider::CiProvider;
+use crate::status;
 use crate::subresources::AgentSecret;
 
+/// How long the version of the vault secret is trusted after it was read. The vault is read at
+/// most once per interval while the agent token is in place, so the resources do not keep Azure
+/// AD and the Key Vault busy between rotations.
+pub const VERSION_CHECK_INTERVAL: chrono::Duration = chrono::Duration::minutes(5);
+
 #[derive(Debug)]
 pub struct AzureVault {
     pub oid: String,
@@ -18,6 +36,71 @@ pub struct AzureVault {
     pub spn: String,
 }
 
+/// Credential the operator authenticates against the Key Vault with, see `VaultAuth`.
+pub enum VaultCredential {
+    /// Client secret of the service principal, the `SPN_SECRET` of the agent Secret.
+    ClientSecret(String),
+    /// Projected service account token of the operator, see `WorkloadIdentityCredential`.
+    WorkloadIdentity,
+    /// Managed identity of the node, see `ManagedIdentityCredential`.
+    ManagedIdentity,
+    /// Client certificate of the service principal, see `ClientCertificateCredential`.
+    Certificate(ClientCertificate),
+}
+
+impl VaultCredential {
+    /// Returns the credential authenticating as the service principal of the Key Vault. Its
+    /// access tokens are cached until they expire, see `CachedCredential`.
+    fn token_credential(&self, az: &AzureVault) -> Arc<dyn TokenCredential> {
+        let key = format!("{}/{}/{}", az.tenant, az.spn, self.cache_key());
+        Arc::new(CachedCredential::new(&key, self.uncached(az)))
+    }
+
+    /// Identifies what the service principal authenticates with, so a new client secret or
+    /// certificate requests a new token. The client secret itself is not kept in the key.
+    fn cache_key(&self) -> String {
+        match self {
+            VaultCredential::ClientSecret(client_secret) => {
+                format!("secret-{:x}", Sha256::digest(client_secret))
+            }
+            VaultCredential::WorkloadIdentity => String::from("workload-identity"),
+            VaultCredential::ManagedIdentity => String::from("managed-identity"),
+            VaultCredential::Certificate(certificate) => {
+                format!("certificate-{}", certificate.thumbprint)
+            }
+        }
+    }
+
+    fn uncached(&self, az: &AzureVault) -> Arc<dyn TokenCredential> {
+        match self {
+            VaultCredential::ClientSecret(client_secret) => Arc::new(ClientSecretCredential::new(
+                new_http_client(),
+                az.tenant.clone(),
+                az.spn.clone(),
+                client_secret.clone(),
+                TokenCredentialOptions::default(),
+            )),
+            VaultCredential::WorkloadIdentity => {
+                Arc::new(WorkloadIdentityCredential::from_env(&az.tenant, &az.spn))
+            }
+            VaultCredential::ManagedIdentity => {
+                Arc::new(ManagedIdentityCredential::from_env(&az.spn))
+            }
+            VaultCredential::Certificate(certificate) => Arc::new(
+                ClientCertificateCredential::from_env(&az.tenant, &az.spn, certificate.clone()),
+            ),
+        }
+    }
+}
+
+/// A secret read from the Key Vault.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VaultSecret {
+    pub value: String,
+    /// Version of the secret, changed by the Key Vault with every new value.
+    pub version: String,
+}
+
 impl AzureVault {
     pub fn new(oid: &str, tenant: &str, keyvault_url: &str, spn: &str) -> Self {
         Self {
@@ -28,51 +111,172 @@ impl AzureVault {
         }
     }
 
+    /// Returns a client of the Key Vault, authenticating as the service principal. A malformed
+    /// Key Vault URL is reported as `Error::VaultNotFound`.
     pub async fn new_client(
         az: &AzureVault,
-        client_secret: &String,
+        credential: &VaultCredential,
     ) -> Result<SecretClient, Error> {
-        let creds = Arc::new(ClientSecretCredential::new(
-            new_http_client(),
-            az.tenant.clone(),
-            az.spn.clone(),
-            client_secret.clone(),
-            TokenCredentialOptions::default(),
-        ));
-
-        let client_result = SecretClient::new(&az.url, creds);
-        let client = match client_result {
-            Ok(client) => client,
-            Err(error) => {
-                eprintln!("Error creating new Azure Secret CLient {}", error);
-                process::exit(1)
-            }
+        SecretClient::new(&az.url, credential.token_credential(az))
+            .map_err(|e| Error::VaultNotFound(format!("invalid Key Vault URL {}: {}", az.url, e)))
+    }
+
+    /// Returns the latest version of the secret, with the version read from its identifier.
+    /// Rejected credentials or an unreachable vault fail the same way as in `vault_error`.
+    pub async fn get_value(az: &AzureVault, client: &SecretClient) -> Result<VaultSecret, Error> {
+        let start = Instant::now();
+        let result = match client.clone().get(format!("{}", az.oid)).await {
+            Ok(secret_response) => Ok(VaultSecret {
+                version: secret_version(&secret_response.id),
+                value: secret_response.value,
+            }),
+            Err(e) if http_status(&e) == Some(StatusCode::NotFound) => Err(Error::SecretMissing(
+                format!("{} not found in Key Vault {}", az.oid, az.url),
+            )),
+            Err(e) => Err(vault_error(e, &az.url)),
         };
+        metrics().vault_request("get_secret", result.is_ok(), start.elapsed());
+        result
+    }
+}
 
-        Ok(client)
+/// Returns the version of a Key Vault secret from its identifier,
+/// `https://<vault>.vault.azure.net/secrets/<name>/<version>`.
+pub fn secret_version(id: &str) -> String {
+    id.trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .to_owned()
+}
+
+/// What a vault pass does with the agent token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenPlan {
+    /// The agent token was collected from the current version of the vault secret.
+    Keep,
+    /// The agent token is missing or expires soon, collect it again.
+    Collect,
+    /// The vault secret changed since the agent token was collected, collect it again and
+    /// restart the agents.
+    Rotate,
+}
+
+/// Decides what to do with the agent token.
+///
+/// # Arguments
+/// - `applied` - Version of the vault secret the agent token was collected from, as recorded in
+///   the status. A token collected before versions were recorded is taken to be current.
+/// - `version` - Current version of the vault secret.
+/// - `present` - Whether the agent token is set in the agent Secret.
+/// - `expired` - Whether the agent token expires soon.
+pub fn plan(applied: Option<&str>, version: &str, present: bool, expired: bool) -> TokenPlan {
+    if applied.is_some_and(|applied| applied != version) {
+        TokenPlan::Rotate
+    } else if !present || expired {
+        TokenPlan::Collect
+    } else {
+        TokenPlan::Keep
+    }
+}
+
+/// Returns true if the version of the vault secret has to be read again: it was never read, or
+/// longer than `VERSION_CHECK_INTERVAL` before `now`.
+pub fn version_check_due(applied: Option<&TokenStatus>, now: DateTime<Utc>) -> bool {
+    let check_time = applied
+        .and_then(|token| token.check_time.as_deref())
+        .and_then(|check_time| DateTime::parse_from_rfc3339(check_time).ok());
+    match check_time {
+        Some(check_time) => check_time.with_timezone(&Utc) + VERSION_CHECK_INTERVAL <= now,
+        None => true,
     }
+}
 
-    // test the connection en authentication to the azure keyvault
-    pub async fn test_connection(az: &AzureVault, client_secret: &String) -> Result<bool, Error> {
-        let client = AzureVault::new_client(az, client_secret).await?;
-        client
-            .clone()
-            .list_secrets()
-            .into_stream()
-            .next()
-            .await
-            .unwrap()?;
-        Ok(true)
+/// Returns the HTTP status the Key Vault responded with, if it responded.
+fn http_status(error: &azure_core::Error) -> Option<StatusCode> {
+    match error.kind() {
+        ErrorKind::HttpResponse { status, .. } => Some(*status),
+        _ => None,
     }
+}
 
-    pub async fn get_value(az: &AzureVault, client_secret: &String) -> Result<String, Error> {
-        let client = AzureVault::new_client(az, client_secret).await?;
-        let secret_response = client.clone().get(format!("{}", az.oid)).await?;
-        Ok(secret_response.value)
+/// Classifies an error of the Key Vault client: rejected credentials, an unknown or unreachable
+/// vault, or any other failed request.
+fn vault_error(error: azure_core::Error, url: &str) -> Error {
+    match (error.kind(), http_status(&error)) {
+        (ErrorKind::Credential, _)
+        | (_, Some(StatusCode::Unauthorized))
+        | (_, Some(StatusCode::Forbidden)) => Error::VaultAuthentication(error.to_string()),
+        (ErrorKind::Io, _) | (_, Some(StatusCode::NotFound)) => {
+            Error::VaultNotFound(format!("{}: {}", url, error))
+        }
+        _ => Error::Vault(error.to_string()),
     }
 }
 
-pub async fn run(client: Client, name: &str, namespace: &str, cr: &CDBootstrap) {
+/// Outcome of a vault synchronisation pass, reported as conditions on the `CDBootstrap` status.
+#[derive(Debug)]
+pub enum VaultSync {
+    /// The agent token was injected in the agent Secret without a `SPN_SECRET`, the vault was
+    /// not contacted.
+    TokenPresent,
+    /// The agent token in the agent Secret was collected from the current version of the vault
+    /// secret.
+    TokenCurrent(TokenStatus),
+    /// The agent token has been collected from the vault and stored in the agent Secret.
+    TokenCollected(TokenStatus),
+    /// A new version of the vault secret has been collected and stored in the agent Secret. The
+    /// agents roll over to it as the checksum annotation of their pod template changes, see
+    /// `CONFIG_CHECKSUM_ANNOTATION`.
+    TokenRotated(TokenStatus),
+    /// Neither the agent token nor the `SPN_SECRET` has been injected in the agent Secret.
+    MissingCredentials,
+    /// Authentication against, or the connection to, the vault failed.
+    VaultUnreachable(Error),
+    /// The vault was reachable, but the token could not be read or stored.
+    TokenUnresolved(Error),
+}
+
+impl VaultSync {
+    /// Returns the error of a failed pass, which is retried by its retry policy.
+    pub fn into_error(self) -> Option<Error> {
+        match self {
+            VaultSync::VaultUnreachable(error) | VaultSync::TokenUnresolved(error) => Some(error),
+            VaultSync::TokenPresent
+            | VaultSync::TokenCurrent(_)
+            | VaultSync::TokenCollected(_)
+            | VaultSync::TokenRotated(_)
+            | VaultSync::MissingCredentials => None,
+        }
+    }
+
+    /// Returns the version of the vault secret applied to the agent Secret, if the vault was read.
+    pub fn token(&self) -> Option<&TokenStatus> {
+        match self {
+            VaultSync::TokenCurrent(token)
+            | VaultSync::TokenCollected(token)
+            | VaultSync::TokenRotated(token) => Some(token),
+            _ => None,
+        }
+    }
+}
+
+/// Makes sure the agent Secret holds a token for the agents to register with. When the token has
+/// not been injected, or has expired, it is collected from the vault and exchanged for an agent
+/// token by the provider. With a `SPN_SECRET` the vault is read every `VERSION_CHECK_INTERVAL`,
+/// so a new version of the vault secret, e.g. a rotated PAT, is collected as well. A resource
+/// authenticating with workload or managed identity has to be on the `IdentityAllowList` of the
+/// operator.
+pub async fn run(
+    client: Client,
+    name: &str,
+    namespace: &str,
+    cr: &CDBootstrap,
+    provider: &dyn CiProvider,
+    identities: &IdentityAllowList,
+) -> VaultSync {
+    let token_key = provider.token_key();
+
     let sps_result = AgentSecret::value_is_set(client.clone(), name, namespace, "SPN_SECRET").await;
     let sps = match sps_result {
         Ok(sps) => sps,
@@ -82,7 +286,7 @@ pub async fn run(client: Client, name: &str, namespace: &str, cr: &CDBootstrap)
         }
     };
 
-    let azp_result = AgentSecret::value_is_set(client.clone(), name, namespace, "AZP_TOKEN").await;
+    let azp_result = AgentSecret::value_is_set(client.clone(), name, namespace, token_key).await;
     let azp = match azp_result {
         Ok(azp) => azp,
         Err(err) => {
@@ -91,66 +295,218 @@ pub async fn run(client: Client, name: &str, namespace: &str, cr: &CDBootstrap)
         }
     };
 
-    if azp == false && sps == false {
-        info!("Make sure to inject the AZP_TOKEN in Namespace {}, or set the SPN_SECRET to collect a Token from the Vault",
-        namespace);
-    }
-
-    if sps == true && azp == false {
-        info!("SPN_SECRET value in Namespace {} Has been set", namespace);
-        if let Ok(secret_value) =
-            AgentSecret::get_value(client.clone(), name, namespace, "SPN_SECRET").await
-        {
-            info!("Testing authentication to the Vault");
-            let azure_vault = AzureVault::new(
-                &cr.spec.oid,
-                &cr.spec.tenant,
-                &cr.spec.keyvault,
-                &cr.spec.spn,
-            );
-            let connection_result =
-                AzureVault::test_connection(&azure_vault, &secret_value.to_string()).await;
-            match connection_result {
-                Ok(true) => {
-                    info!("Connection to the Azure KeyVault is successful");
-                    let vault_secret_result =
-                        AzureVault::get_value(&azure_vault, &secret_value.to_string()).await;
-                    info!(
-                        "AZP_TOKEN Collected from the Keyvault for Namespace {}",
+    let expires_key = format!("{}_EXPIRES_AT", token_key);
+    let expired = match AgentSecret::get_value(client.clone(), name, namespace, &expires_key).await
+    {
+        Ok(expires_at) => expires_soon(&expires_at),
+        Err(_) => false,
+    };
+
+    // Only a client secret has to be injected, the other credentials come with the operator
+    let has_credential = match cr.spec.vault.auth {
+        VaultAuth::ClientSecret => sps,
+        VaultAuth::WorkloadIdentity | VaultAuth::ManagedIdentity | VaultAuth::Certificate => true,
+    };
+
+    if !has_credential && azp && !expired {
+        info!(
+            "{} value in Namespace {} has been SET",
+            token_key, namespace
+        );
+        info!("Check the Pod logs to see if the Agent is polling");
+        return VaultSync::TokenPresent;
+    }
+
+    if !has_credential {
+        info!("Make sure to inject the {} in Namespace {}, or set the SPN_SECRET to collect a Token from the Vault",
+        token_key, namespace);
+        return VaultSync::MissingCredentials;
+    }
+
+    let applied = cr.status.as_ref().and_then(|status| status.token.as_ref());
+    if azp && !expired && !version_check_due(applied, Utc::now()) {
+        if let Some(applied) = applied {
+            return VaultSync::TokenCurrent(applied.clone());
+        }
+    }
+
+    let credential = match cr.spec.vault.auth {
+        VaultAuth::ClientSecret => {
+            info!("SPN_SECRET value in Namespace {} Has been set", namespace);
+            match AgentSecret::get_value(client.clone(), name, namespace, "SPN_SECRET").await {
+                Ok(secret_value) => VaultCredential::ClientSecret(secret_value),
+                Err(err) => {
+                    error!(
+                        "Error retrieving SPN_SECRET value in Namespace {}",
                         namespace
                     );
-                    let vault_secret = match vault_secret_result {
-                        Ok(s) => s,
-                        Err(error) => {
-                            warn!(
-                                "Connection to the Azure KeyVault is unsuccessful: {:?}",
-                                error
-                            );
-                            String::default()
-                        }
-                    };
-
-                    let _ =
-                        AgentSecret::set_azp_token(client, name, namespace, &vault_secret).await;
-                    info!("AZP_TOKEN Secret value Set in Namespace {}", namespace);
-                }
-                Ok(false) => {
-                    warn!("Connection to the Azure KeyVault is unsuccessful");
-                }
-                Err(err) => {
-                    warn!("Connection to the Azure KeyVault is unsuccessful: {}", err);
+                    return VaultSync::TokenUnresolved(err);
                 }
             }
-        } else {
-            error!(
-                "Error retrieving SPN_SECRET value in Namespace {}",
-                namespace
+        }
+        // The service account token and the identities of the node belong to the operator, not
+        // to the namespace of the resource
+        VaultAuth::WorkloadIdentity | VaultAuth::ManagedIdentity => {
+            if let Err(err) =
+                identities.check(namespace, &cr.spec.vault.client_id, &cr.spec.vault.url)
+            {
+                warn!("{}", err);
+                return VaultSync::VaultUnreachable(err);
+            }
+            match cr.spec.vault.auth {
+                VaultAuth::WorkloadIdentity => VaultCredential::WorkloadIdentity,
+                _ => VaultCredential::ManagedIdentity,
+            }
+        }
+        VaultAuth::Certificate => match certificate(client.clone(), namespace, cr).await {
+            Ok(certificate) if certificate.expires_within(Utc::now(), chrono::Duration::zero()) => {
+                return VaultSync::VaultUnreachable(Error::InvalidCertificate(format!(
+                    "certificate {} expired at {}",
+                    certificate.thumbprint,
+                    certificate.not_after.to_rfc3339()
+                )));
+            }
+            Ok(certificate) => VaultCredential::Certificate(certificate),
+            Err(err) => {
+                warn!(
+                    "Unable to read the client certificate in Namespace {}: {}",
+                    namespace, err
+                );
+                return VaultSync::VaultUnreachable(err);
+            }
+        },
+    };
+
+    let azure_vault = AzureVault::new(
+        &cr.spec.vault.secret_name,
+        &cr.spec.vault.tenant_id,
+        &cr.spec.vault.url,
+        &cr.spec.vault.client_id,
+    );
+    let vault_client = match AzureVault::new_client(&azure_vault, &credential).await {
+        Ok(vault_client) => vault_client,
+        Err(err) => return VaultSync::VaultUnreachable(err),
+    };
+
+    let vault_secret = match AzureVault::get_value(&azure_vault, &vault_client).await {
+        Ok(s) => s,
+        // The vault was reached and the service principal accepted, but the secret is missing
+        Err(err @ Error::SecretMissing(_)) => {
+            warn!(
+                "Unable to collect the {} from the Azure KeyVault: {:?}",
+                token_key, err
             );
+            return VaultSync::TokenUnresolved(err);
+        }
+        Err(err) => {
+            warn!("Connection to the Azure KeyVault is unsuccessful: {}", err);
+            return VaultSync::VaultUnreachable(err);
         }
+    };
+
+    let plan = plan(
+        applied.map(|token| token.vault_version.as_str()),
+        &vault_secret.version,
+        azp,
+        expired,
+    );
+    let mut token_status = TokenStatus {
+        vault_version: vault_secret.version.clone(),
+        rotation_time: applied.and_then(|token| token.rotation_time.clone()),
+        check_time: Some(status::now()),
+    };
+    if plan == TokenPlan::Keep {
+        return VaultSync::TokenCurrent(token_status);
     }
 
-    if azp == true {
-        info!("AZP_TOKEN value in Namespace {} has been SET", namespace);
-        info!("Check the Pod logs to see if the Agent is polling")
+    let token = match provider.exchange_token(&vault_secret.value).await {
+        Ok(token) => token,
+        Err(err) => {
+            warn!(
+                "Unable to exchange the vault secret for a {} token: {:?}",
+                provider.name(),
+                err
+            );
+            return VaultSync::TokenUnresolved(err);
+        }
+    };
+    info!(
+        "{} Collected from the Keyvault for Namespace {}",
+        token_key, namespace
+    );
+
+    if let Err(err) = AgentSecret::set_token(client, name, namespace, token_key, &token).await {
+        warn!(
+            "Unable to set the {} in Namespace {}: {:?}",
+            token_key, namespace, err
+        );
+        return VaultSync::TokenUnresolved(err);
+    }
+    info!("{} Secret value Set in Namespace {}", token_key, namespace);
+    if plan == TokenPlan::Collect {
+        return VaultSync::TokenCollected(token_status);
     }
+
+    info!(
+        "Vault secret {} rotated to version {} for Namespace {}",
+        azure_vault.oid, vault_secret.version, namespace
+    );
+    token_status.rotation_time = Some(status::now());
+    VaultSync::TokenRotated(token_status)
+}
+
+/// Reads the client certificate of the service principal from the Secret referenced in
+/// `spec.vault.certificate`: a PFX in `certificate.pfx` with its password in `password`, or PEM
+/// in `tls.crt` and `tls.key`.
+pub async fn certificate(
+    client: Client,
+    namespace: &str,
+    cr: &CDBootstrap,
+) -> Result<ClientCertificate, Error> {
+    let Some(spec) = &cr.spec.vault.certificate else {
+        return Err(Error::UserInputError(String::from(
+            "vault.auth Certificate requires the vault.certificate section",
+        )));
+    };
+    let api: Api<Secret> = Api::namespaced(client, namespace);
+    let Some(secret) = api.get_opt(&spec.secret_name).await? else {
+        return Err(Error::SecretMissing(format!(
+            "Secret {} with the client certificate not found in namespace {}",
+            spec.secret_name, namespace
+        )));
+    };
+    let data = secret.data.unwrap_or_default();
+
+    let certificate = match (
+        data.get("certificate.pfx"),
+        data.get("tls.crt"),
+        data.get("tls.key"),
+    ) {
+        (Some(pfx), _, _) => {
+            let password = data
+                .get("password")
+                .map(|password| String::from_utf8_lossy(&password.0).into_owned())
+                .unwrap_or_default();
+            ClientCertificate::from_pfx(&pfx.0, &password)
+        }
+        (None, Some(certificate), Some(key)) => ClientCertificate::from_pem(&certificate.0, &key.0),
+        _ => {
+            return Err(Error::SecretMissing(format!(
+                "Secret {} holds neither certificate.pfx nor tls.crt and tls.key",
+                spec.secret_name
+            )))
+        }
+    };
+    certificate
+        .map_err(|e| Error::InvalidCertificate(format!("Secret {}: {}", spec.secret_name, e)))
+}
+
+/// Returns true if the RFC 3339 timestamp is less than five minutes away, so a token is renewed
+/// before agents fail to register with it. An empty or malformed timestamp never expires.
+fn expires_soon(expires_at: &str) -> bool {
+    DateTime::parse_from_rfc3339(expires_at)
+        .map(|expires_at| {
+            expires_at.with_timezone(&Utc) < Utc::now() + chrono::Duration::minutes(5)
+        })
+        .unwrap_or(false)
 }
@@ -0,0 +1,179 @@
+use cdbootstrap::autoscaler::{self, AzureDevOpsClient, JobCounts};
+use cdbootstrap::crd::{
+    AgentSpec, AutoscalingSpec, AutoscalingStatus, CDBootstrap, CDBootstrapSpec, CDBootstrapStatus,
+};
+use cdbootstrap::error::Error;
+use k8s_openapi::api::apps::v1::{Deployment, DeploymentSpec};
+use k8s_openapi::chrono::{Duration, TimeZone, Utc};
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use warp::Filter;
+
+fn spec() -> AutoscalingSpec {
+    AutoscalingSpec {
+        min_replicas: 1,
+        max_replicas: 5,
+        cooldown_seconds: Some(300),
+    }
+}
+
+/// Serves a stand-in of the Azure DevOps distributed task API on a random local port.
+async fn mock_azure_devops() -> SocketAddr {
+    let pools = warp::path!("org" / "_apis" / "distributedtask" / "pools")
+        .and(warp::header::<String>("authorization"))
+        .and(warp::query::<HashMap<String, String>>())
+        .map(|authorization: String, query: HashMap<String, String>| {
+            assert!(authorization.starts_with("Basic "));
+            assert_eq!(query.get("api-version").map(String::as_str), Some("7.0"));
+            let value = match query.get("poolName").map(String::as_str) {
+                Some("poc pool") => json!([{ "id": 42, "name": "poc pool" }]),
+                _ => json!([]),
+            };
+            warp::reply::json(&json!({ "count": value.as_array().unwrap().len(), "value": value }))
+        });
+    let jobs = warp::path!("org" / "_apis" / "distributedtask" / "pools" / u64 / "jobrequests")
+        .and(warp::query::<HashMap<String, String>>())
+        .map(|pool_id: u64, query: HashMap<String, String>| {
+            assert_eq!(pool_id, 42);
+            assert_eq!(query.get("api-version").map(String::as_str), Some("7.0"));
+            assert_eq!(
+                query.get("completedRequestCount").map(String::as_str),
+                Some("0")
+            );
+            warp::reply::json(&json!({
+                "count": 4,
+                "value": [
+                    { "requestId": 1, "queueTime": "2024-01-01T10:00:00Z" },
+                    { "requestId": 2, "queueTime": "2024-01-01T10:00:00Z" },
+                    { "requestId": 3, "assignTime": "2024-01-01T10:00:01Z" },
+                    {
+                        "requestId": 4,
+                        "assignTime": "2024-01-01T09:00:01Z",
+                        "finishTime": "2024-01-01T09:10:00Z",
+                        "result": "succeeded"
+                    }
+                ]
+            }))
+        });
+
+    let (addr, server) =
+        warp::serve(pools.or(jobs)).bind_ephemeral(SocketAddr::from(([127, 0, 0, 1], 0)));
+    tokio::spawn(server);
+    addr
+}
+
+#[tokio::test]
+async fn client_counts_queued_and_running_jobs() {
+    let addr = mock_azure_devops().await;
+    let client = AzureDevOpsClient::new(&format!("http://{}/org/", addr), "pat").unwrap();
+
+    let pool_id = client.pool_id("poc pool").await.unwrap();
+    assert_eq!(pool_id, 42);
+    assert!(matches!(
+        client.pool_id("unknown").await,
+        Err(Error::JobRequests(_))
+    ));
+
+    let counts = client.job_counts(pool_id).await.unwrap();
+    assert_eq!(
+        counts,
+        JobCounts {
+            queued: 2,
+            running: 1
+        }
+    );
+}
+
+#[tokio::test]
+async fn poll_records_the_scale_decision() {
+    let addr = mock_azure_devops().await;
+    let client = AzureDevOpsClient::new(&format!("http://{}/org", addr), "pat").unwrap();
+    let mut status = AutoscalingStatus {
+        desired_replicas: 1,
+        ..AutoscalingStatus::default()
+    };
+
+    let decision = autoscaler::poll(&client, "poc pool", &spec(), 1, &mut status)
+        .await
+        .unwrap();
+    assert!(decision.scaled);
+    assert_eq!(decision.replicas, 3);
+    assert_eq!(status.queued_jobs, 2);
+    assert_eq!(status.running_jobs, 1);
+    assert_eq!(status.desired_replicas, 3);
+    assert!(status.last_scale_time.is_some());
+    assert_eq!(
+        status.last_decision.as_deref(),
+        Some(decision.message.as_str())
+    );
+}
+
+#[test]
+fn scale_up_is_immediate_and_bounded() {
+    let now = Utc.with_ymd_and_hms(2024, 1, 1, 10, 0, 0).unwrap();
+    let counts = JobCounts {
+        queued: 8,
+        running: 2,
+    };
+    let decision = autoscaler::decide(2, &counts, &spec(), Some(now), now);
+    assert!(decision.scaled);
+    assert_eq!(decision.replicas, 5);
+}
+
+#[test]
+fn scale_down_waits_for_the_cooldown() {
+    let now = Utc.with_ymd_and_hms(2024, 1, 1, 10, 0, 0).unwrap();
+    let idle = JobCounts::default();
+
+    let decision = autoscaler::decide(4, &idle, &spec(), Some(now - Duration::seconds(60)), now);
+    assert!(!decision.scaled);
+    assert!(decision.delayed);
+    assert_eq!(decision.replicas, 4);
+
+    let decision = autoscaler::decide(4, &idle, &spec(), Some(now - Duration::seconds(301)), now);
+    assert!(decision.scaled);
+    assert_eq!(decision.replicas, 1);
+
+    let decision = autoscaler::decide(1, &idle, &spec(), None, now);
+    assert!(!decision.scaled && !decision.delayed);
+}
+
+#[test]
+fn reconcile_after_an_autoscale_keeps_the_scaled_replicas() {
+    let mut cr = CDBootstrap::new(
+        "pool-a",
+        CDBootstrapSpec {
+            agent: AgentSpec {
+                replicas: 1,
+                autoscaling: Some(spec()),
+                ..AgentSpec::default()
+            },
+            ..CDBootstrapSpec::default()
+        },
+    );
+    // The status still holds the decision from before the scale to 4 agents
+    cr.status = Some(CDBootstrapStatus {
+        autoscaling: Some(AutoscalingStatus {
+            desired_replicas: 2,
+            ..AutoscalingStatus::default()
+        }),
+        ..CDBootstrapStatus::default()
+    });
+    let live = |replicas: i32| Deployment {
+        spec: Some(DeploymentSpec {
+            replicas: Some(replicas),
+            ..DeploymentSpec::default()
+        }),
+        ..Deployment::default()
+    };
+
+    assert_eq!(autoscaler::applied_replicas(&cr, Some(&live(4))), 4);
+    assert_eq!(autoscaler::applied_replicas(&cr, None), 2);
+    // Lowering `maxReplicas` scales the agents down on the next apply
+    assert_eq!(autoscaler::applied_replicas(&cr, Some(&live(9))), 5);
+
+    // Without autoscaling the replicas of the specification are applied
+    cr.spec.agent.autoscaling = None;
+    assert_eq!(autoscaler::applied_replicas(&cr, Some(&live(4))), 1);
+}
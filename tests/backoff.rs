@@ -0,0 +1,78 @@
+use cdbootstrap::backoff::{self, Backoff};
+use cdbootstrap::crd::{CDBootstrap, CDBootstrapSpec, CDBootstrapStatus, RetryStatus};
+use k8s_openapi::chrono::{DateTime, Utc};
+use std::time::Duration;
+
+#[test]
+fn delay_doubles_up_to_the_maximum() {
+    let backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(300));
+
+    // Without jitter half of the exponential delay is taken, with the most jitter all of it
+    assert_eq!(backoff.delay(1, 0.0), Duration::from_millis(2500));
+    assert_eq!(backoff.delay(1, 1.0), Duration::from_secs(5));
+    assert_eq!(backoff.delay(2, 1.0), Duration::from_secs(10));
+    assert_eq!(backoff.delay(4, 1.0), Duration::from_secs(40));
+    assert_eq!(backoff.delay(7, 1.0), Duration::from_secs(300));
+    assert_eq!(backoff.delay(64, 1.0), Duration::from_secs(300));
+    assert_eq!(backoff.delay(64, 0.0), Duration::from_secs(150));
+}
+
+#[test]
+fn failures_are_counted_per_resource_until_reset() {
+    let backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(300));
+
+    assert_eq!(backoff.failed("team-a/pool-a").attempts, 1);
+    let second = backoff.failed("team-a/pool-a");
+    assert_eq!(second.attempts, 2);
+    assert!(second.delay >= Duration::from_secs(5) && second.delay <= Duration::from_secs(10));
+    assert_eq!(backoff.next_delay("team-a/pool-a"), second.delay);
+    assert_eq!(backoff.failed("team-b/pool-b").attempts, 1);
+
+    backoff.reset("team-a/pool-a");
+    assert_eq!(backoff.next_delay("team-a/pool-a"), Duration::from_secs(5));
+    assert_eq!(backoff.failed("team-a/pool-a").attempts, 1);
+    assert_eq!(backoff.failed("team-b/pool-b").attempts, 2);
+}
+
+#[test]
+fn retry_is_recorded_with_the_next_retry_time() {
+    let backoff = Backoff::default();
+    let status = backoff.failed("team-a/pool-a").status();
+
+    assert_eq!(status.attempts, 1);
+    assert!(status.next_retry_time.unwrap().ends_with('Z'));
+}
+
+#[test]
+fn failing_resource_is_not_reconciled_before_the_next_retry() {
+    let mut cr = CDBootstrap::new("team-a", CDBootstrapSpec::default());
+    cr.metadata.generation = Some(3);
+    cr.status = Some(CDBootstrapStatus {
+        observed_generation: Some(3),
+        retry: Some(RetryStatus {
+            attempts: 2,
+            next_retry_time: Some(String::from("2024-01-01T10:00:10Z")),
+        }),
+        ..CDBootstrapStatus::default()
+    });
+    let at = |time: &str| {
+        DateTime::parse_from_rfc3339(time)
+            .unwrap()
+            .with_timezone(&Utc)
+    };
+
+    // The status written by the failure, or a subresource event, does not skip the backoff
+    assert_eq!(
+        backoff::pending(&cr, at("2024-01-01T10:00:00Z")),
+        Some(Duration::from_secs(10))
+    );
+    assert_eq!(backoff::pending(&cr, at("2024-01-01T10:00:10Z")), None);
+    assert_eq!(backoff::pending(&cr, at("2024-01-01T10:05:00Z")), None);
+
+    // A new specification is reconciled right away
+    cr.metadata.generation = Some(4);
+    assert_eq!(backoff::pending(&cr, at("2024-01-01T10:00:00Z")), None);
+
+    cr.status = Some(CDBootstrapStatus::default());
+    assert_eq!(backoff::pending(&cr, at("2024-01-01T10:00:00Z")), None);
+}
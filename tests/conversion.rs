@@ -0,0 +1,109 @@
+use cdbootstrap::conversion::{self, V1BETA1_API_VERSION, V1_API_VERSION, V1_SPEC_ANNOTATION};
+use kube::core::conversion::ConversionReview;
+use serde_json::{json, Value};
+
+fn v1beta1_object() -> Value {
+    json!({
+        "apiVersion": "cndev.nl/v1beta1",
+        "kind": "CDBootstrap",
+        "metadata": {
+            "name": "test-bootstrap",
+            "namespace": "default"
+        },
+        "spec": {
+            "oid": "mycluster-default",
+            "url": "https://dev.azure.com/DevOps-SST",
+            "pool": "poc-pool",
+            "replicas": 2,
+            "keyvault": "https://kmcs-p-weu-prd.vault.azure.net/",
+            "spn": "69f74670-5cf9-4cfe-b795-8dc3a6cc975f",
+            "tenant": "0baeb517-c6ec-4d6c-a394-96a5affa5ada"
+        }
+    })
+}
+
+#[test]
+fn v1beta1_is_converted_to_structured_v1() {
+    let converted = conversion::convert(v1beta1_object(), V1_API_VERSION).unwrap();
+    assert_eq!(converted["apiVersion"], V1_API_VERSION);
+    assert_eq!(converted["metadata"]["name"], "test-bootstrap");
+    assert_eq!(converted["spec"]["azureDevOps"]["pool"], "poc-pool");
+    assert_eq!(
+        converted["spec"]["vault"]["secretName"],
+        "mycluster-default"
+    );
+    assert_eq!(
+        converted["spec"]["vault"]["clientId"],
+        "69f74670-5cf9-4cfe-b795-8dc3a6cc975f"
+    );
+    assert_eq!(converted["spec"]["agent"]["replicas"], 2);
+}
+
+#[test]
+fn v1beta1_round_trips_through_v1() {
+    let original = v1beta1_object();
+    let v1 = conversion::convert(original.clone(), V1_API_VERSION).unwrap();
+    let back = conversion::convert(v1, V1BETA1_API_VERSION).unwrap();
+
+    assert_eq!(back["apiVersion"], V1BETA1_API_VERSION);
+    assert_eq!(back["spec"], original["spec"]);
+    assert!(back["metadata"]["annotations"][V1_SPEC_ANNOTATION].is_string());
+
+    // Converting up again drops the annotation and yields the same v1 object
+    let again = conversion::convert(back, V1_API_VERSION).unwrap();
+    assert_eq!(
+        again["spec"],
+        conversion::convert(original, V1_API_VERSION).unwrap()["spec"]
+    );
+    assert!(again["metadata"]["annotations"]
+        .get(V1_SPEC_ANNOTATION)
+        .is_none());
+}
+
+#[test]
+fn same_version_is_returned_unchanged() {
+    let original = v1beta1_object();
+    let converted = conversion::convert(original.clone(), V1BETA1_API_VERSION).unwrap();
+    assert_eq!(converted, original);
+}
+
+#[test]
+fn invalid_spec_fails_the_whole_review() {
+    let mut broken = v1beta1_object();
+    broken["spec"]["replicas"] = json!("two");
+
+    let review: ConversionReview = serde_json::from_value(json!({
+        "apiVersion": "apiextensions.k8s.io/v1",
+        "kind": "ConversionReview",
+        "request": {
+            "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+            "desiredAPIVersion": "cndev.nl/v1",
+            "objects": [v1beta1_object(), broken]
+        }
+    }))
+    .unwrap();
+
+    let response = conversion::review(review).response.unwrap();
+    assert_eq!(response.uid, "705ab4f5-6393-11e8-b7cc-42010a800002");
+    assert!(response.result.is_failure());
+    assert!(response.converted_objects.is_empty());
+}
+
+#[test]
+fn review_converts_all_objects() {
+    let review: ConversionReview = serde_json::from_value(json!({
+        "apiVersion": "apiextensions.k8s.io/v1",
+        "kind": "ConversionReview",
+        "request": {
+            "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+            "desiredAPIVersion": "cndev.nl/v1",
+            "objects": [v1beta1_object(), v1beta1_object()]
+        }
+    }))
+    .unwrap();
+
+    let response = conversion::review(review).response.unwrap();
+    assert!(response.result.is_success());
+    assert_eq!(response.converted_objects.len(), 2);
+    assert_eq!(response.converted_objects[0]["apiVersion"], V1_API_VERSION);
+}
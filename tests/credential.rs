@@ -0,0 +1,303 @@
+use azure_core::auth::TokenCredential;
+use cdbootstrap::credential::{
+    scope, CachedCredential, ClientCertificate, ClientCertificateCredential,
+    ManagedIdentityCredential, WorkloadIdentityCredential,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use k8s_openapi::chrono::{Duration, TimeZone, Utc};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use warp::Filter;
+
+const TENANT_ID: &str = "0baeb517-c6ec-4d6c-a394-96a5affa5ada";
+const CLIENT_ID: &str = "69f74670-5cf9-4cfe-b795-8dc3a6cc975f";
+
+/// Test certificate, generated with `openssl req -x509 -newkey rsa:2048 -days 3650`, and its
+/// key in PKCS#8 and PFX encoding.
+const CERTIFICATE: &[u8] = include_bytes!("fixtures/client.crt");
+const KEY: &[u8] = include_bytes!("fixtures/client.key");
+const PUBLIC_KEY: &[u8] = include_bytes!("fixtures/client.pub");
+const PFX: &[u8] = include_bytes!("fixtures/client.pfx");
+/// `openssl x509 -outform der | openssl dgst -sha1 -binary | basenc --base64url`
+const THUMBPRINT: &str = "EtOX-mjyi64NCd-eWnbKxBrxH98";
+
+/// Forms received by the stand-in token endpoint.
+type Requests = Arc<Mutex<Vec<HashMap<String, String>>>>;
+
+/// Serves a stand-in of the Azure AD token endpoint on a random port, recording the forms it
+/// receives.
+fn token_endpoint(status: u16) -> (SocketAddr, Requests) {
+    let requests = Requests::default();
+    let recorded = requests.clone();
+    let route = warp::post()
+        .and(warp::path!(String / "oauth2" / "v2.0" / "token"))
+        .and(warp::body::form())
+        .map(move |tenant: String, mut form: HashMap<String, String>| {
+            form.insert("tenant".to_owned(), tenant);
+            recorded.lock().unwrap().push(form);
+            warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({
+                    "token_type": "Bearer",
+                    "expires_in": 3599,
+                    "access_token": "vault-access-token",
+                })),
+                warp::http::StatusCode::from_u16(status).unwrap(),
+            )
+        });
+    let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    (addr, requests)
+}
+
+/// Serves a stand-in of the managed identity endpoint of the instance metadata service on a
+/// random port, handing out tokens for `CLIENT_ID` only. Like IMDS, requests without the
+/// `Metadata: true` header are refused.
+fn imds() -> (SocketAddr, Requests) {
+    let requests = Requests::default();
+    let recorded = requests.clone();
+    let route = warp::get()
+        .and(warp::path!("metadata" / "identity" / "oauth2" / "token"))
+        .and(warp::header::optional::<String>("Metadata"))
+        .and(warp::query::<HashMap<String, String>>())
+        .map(
+            move |metadata: Option<String>, query: HashMap<String, String>| {
+                recorded.lock().unwrap().push(query.clone());
+                let (status, body) = match (metadata.as_deref(), query.get("client_id")) {
+                    (Some("true"), Some(client_id)) if client_id == CLIENT_ID => (
+                        warp::http::StatusCode::OK,
+                        serde_json::json!({
+                            "access_token": "managed-identity-token",
+                            "expires_in": "86399",
+                            "resource": query.get("resource"),
+                            "token_type": "Bearer",
+                        }),
+                    ),
+                    (Some("true"), _) => (
+                        warp::http::StatusCode::BAD_REQUEST,
+                        serde_json::json!({
+                            "error": "invalid_request",
+                            "error_description": "Identity not found",
+                        }),
+                    ),
+                    _ => (
+                        warp::http::StatusCode::BAD_REQUEST,
+                        serde_json::json!({
+                            "error": "invalid_request",
+                            "error_description": "Required metadata header not specified",
+                        }),
+                    ),
+                };
+                warp::reply::with_status(warp::reply::json(&body), status)
+            },
+        );
+    let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    (addr, requests)
+}
+
+fn token_file(name: &str, content: &str) -> std::path::PathBuf {
+    let path = env::temp_dir().join(format!("cdbootstrap-{}-{}", std::process::id(), name));
+    fs::write(&path, content).unwrap();
+    path
+}
+
+#[test]
+fn scope_of_a_resource() {
+    assert_eq!(
+        scope("https://vault.azure.net"),
+        "https://vault.azure.net/.default"
+    );
+    assert_eq!(
+        scope("https://vault.azure.net/"),
+        "https://vault.azure.net/.default"
+    );
+    assert_eq!(
+        scope("https://vault.azure.net/.default"),
+        "https://vault.azure.net/.default"
+    );
+}
+
+#[tokio::test]
+async fn service_account_token_is_sent_as_client_assertion() {
+    let (addr, requests) = token_endpoint(200);
+    let file = token_file("federated", "service-account-token\n");
+    let credential =
+        WorkloadIdentityCredential::new(&format!("http://{}", addr), TENANT_ID, CLIENT_ID, file);
+
+    let token = credential
+        .get_token("https://vault.azure.net")
+        .await
+        .unwrap();
+    assert_eq!(token.token.secret(), "vault-access-token");
+
+    let requests = requests.lock().unwrap();
+    let form = &requests[0];
+    assert_eq!(form["tenant"], TENANT_ID);
+    assert_eq!(form["grant_type"], "client_credentials");
+    assert_eq!(form["client_id"], CLIENT_ID);
+    assert_eq!(form["scope"], "https://vault.azure.net/.default");
+    assert_eq!(
+        form["client_assertion_type"],
+        "urn:ietf:params:oauth:client-assertion-type:jwt-bearer"
+    );
+    assert_eq!(form["client_assertion"], "service-account-token");
+}
+
+#[tokio::test]
+async fn rejected_or_missing_assertions_fail() {
+    let (addr, _) = token_endpoint(401);
+    let host = format!("http://{}", addr);
+    let file = token_file("rejected", "service-account-token");
+    let rejected = WorkloadIdentityCredential::new(&host, TENANT_ID, CLIENT_ID, file);
+    assert!(rejected.get_token("https://vault.azure.net").await.is_err());
+
+    let missing = env::temp_dir().join("cdbootstrap-missing-token");
+    let missing = WorkloadIdentityCredential::new(&host, TENANT_ID, CLIENT_ID, missing);
+    assert!(missing.get_token("https://vault.azure.net").await.is_err());
+}
+
+#[tokio::test]
+async fn cached_tokens_are_reused_until_they_expire() {
+    let (addr, requests) = token_endpoint(200);
+    let host = format!("http://{}", addr);
+    let credential = |key: &str| {
+        let file = token_file("cached", "service-account-token");
+        CachedCredential::new(
+            key,
+            Arc::new(WorkloadIdentityCredential::new(
+                &host, TENANT_ID, CLIENT_ID, file,
+            )),
+        )
+    };
+
+    // Credentials are built per reconciliation, the cache is shared
+    for _ in 0..3 {
+        let token = credential("cached-test")
+            .get_token("https://vault.azure.net")
+            .await
+            .unwrap();
+        assert_eq!(token.token.secret(), "vault-access-token");
+    }
+    assert_eq!(requests.lock().unwrap().len(), 1);
+
+    credential("other-test")
+        .get_token("https://vault.azure.net")
+        .await
+        .unwrap();
+    assert_eq!(requests.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn managed_identity_token_is_requested_from_imds() {
+    let (addr, requests) = imds();
+    let credential = ManagedIdentityCredential::new(&format!("http://{}", addr), CLIENT_ID);
+
+    let token = credential
+        .get_token("https://vault.azure.net/.default")
+        .await
+        .unwrap();
+    assert_eq!(token.token.secret(), "managed-identity-token");
+
+    let requests = requests.lock().unwrap();
+    let query = &requests[0];
+    assert_eq!(query["api-version"], "2018-02-01");
+    assert_eq!(query["resource"], "https://vault.azure.net");
+    assert_eq!(query["client_id"], CLIENT_ID);
+}
+
+#[tokio::test]
+async fn unassigned_managed_identity_fails() {
+    let (addr, _) = imds();
+    let credential = ManagedIdentityCredential::new(
+        &format!("http://{}", addr),
+        "00000000-0000-0000-0000-000000000000",
+    );
+    assert!(credential
+        .get_token("https://vault.azure.net")
+        .await
+        .is_err());
+}
+
+#[test]
+fn pem_certificate_is_read_with_its_thumbprint_and_expiry() {
+    let certificate = ClientCertificate::from_pem(CERTIFICATE, KEY).unwrap();
+    assert_eq!(certificate.thumbprint, THUMBPRINT);
+    assert_eq!(
+        certificate.not_after,
+        Utc.with_ymd_and_hms(2036, 10, 13, 8, 48, 17).unwrap()
+    );
+
+    assert!(ClientCertificate::from_pem(KEY, KEY).is_err());
+    assert!(ClientCertificate::from_pem(CERTIFICATE, CERTIFICATE).is_err());
+}
+
+#[test]
+fn pfx_certificate_is_read_with_its_password() {
+    let certificate = ClientCertificate::from_pfx(PFX, "cdbootstrap").unwrap();
+    assert_eq!(certificate.thumbprint, THUMBPRINT);
+
+    assert!(ClientCertificate::from_pfx(PFX, "wrong").is_err());
+}
+
+#[test]
+fn certificate_expires_within_the_window() {
+    let certificate = ClientCertificate::from_pem(CERTIFICATE, KEY).unwrap();
+    let not_after = certificate.not_after;
+
+    assert!(!certificate.expires_within(not_after - Duration::days(31), Duration::days(30)));
+    assert!(certificate.expires_within(not_after - Duration::days(29), Duration::days(30)));
+    assert!(certificate.expires_within(not_after, Duration::zero()));
+}
+
+#[test]
+fn assertion_is_signed_by_the_certificate() {
+    let certificate = ClientCertificate::from_pem(CERTIFICATE, KEY).unwrap();
+    let audience = format!(
+        "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
+        TENANT_ID
+    );
+    let assertion = certificate
+        .assertion(&audience, CLIENT_ID, Utc::now())
+        .unwrap();
+
+    let header = jsonwebtoken::decode_header(&assertion).unwrap();
+    assert_eq!(header.alg, Algorithm::RS256);
+    assert_eq!(header.x5t.as_deref(), Some(THUMBPRINT));
+
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.set_audience(&[&audience]);
+    validation.set_issuer(&[CLIENT_ID]);
+    let claims = jsonwebtoken::decode::<serde_json::Value>(
+        &assertion,
+        &DecodingKey::from_rsa_pem(PUBLIC_KEY).unwrap(),
+        &validation,
+    )
+    .unwrap()
+    .claims;
+    assert_eq!(claims["sub"], CLIENT_ID);
+    assert!(claims["jti"].as_str().is_some_and(|jti| !jti.is_empty()));
+}
+
+#[tokio::test]
+async fn certificate_assertion_is_sent_to_the_token_endpoint() {
+    let (addr, requests) = token_endpoint(200);
+    let host = format!("http://{}", addr);
+    let certificate = ClientCertificate::from_pem(CERTIFICATE, KEY).unwrap();
+    let credential = ClientCertificateCredential::new(&host, TENANT_ID, CLIENT_ID, certificate);
+
+    let token = credential
+        .get_token("https://vault.azure.net")
+        .await
+        .unwrap();
+    assert_eq!(token.token.secret(), "vault-access-token");
+
+    let requests = requests.lock().unwrap();
+    let form = &requests[0];
+    assert_eq!(form["client_id"], CLIENT_ID);
+    assert!(!form.contains_key("client_secret"));
+    let header = jsonwebtoken::decode_header(&form["client_assertion"]).unwrap();
+    assert_eq!(header.x5t.as_deref(), Some(THUMBPRINT));
+}
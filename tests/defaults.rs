@@ -0,0 +1,79 @@
+use cdbootstrap::crd::{AgentSpec, AzureDevOpsSpec, CDBootstrapSpec, ImagePullPolicy, Provider};
+use cdbootstrap::defaults::AgentDefaults;
+use cdbootstrap::provider;
+use std::collections::BTreeMap;
+
+fn spec(agent: AgentSpec) -> CDBootstrapSpec {
+    CDBootstrapSpec {
+        azure_devops: Some(AzureDevOpsSpec {
+            url: "https://dev.azure.com/DevOps-SST".to_string(),
+            pool: "poc-pool".to_string(),
+        }),
+        agent,
+        ..CDBootstrapSpec::default()
+    }
+}
+
+#[test]
+fn defaults_are_read_from_variables() {
+    let vars = BTreeMap::from([
+        (
+            "AGENT_IMAGE_AZURE_PIPELINES",
+            "registry.local/azp-agent:1.0",
+        ),
+        ("AGENT_IMAGE_PULL_POLICY", "IfNotPresent"),
+        ("AGENT_IMAGE_PULL_SECRETS", "regcred, ,mirror"),
+        ("AGENT_IMAGE_GITLAB_RUNNER", " "),
+    ]);
+    let defaults = AgentDefaults::from_vars(|key| vars.get(key).map(|v| v.to_string()));
+
+    assert_eq!(
+        defaults.images.get(&Provider::AzurePipelines).unwrap(),
+        "registry.local/azp-agent:1.0"
+    );
+    assert!(!defaults.images.contains_key(&Provider::GitLabRunner));
+    assert_eq!(
+        defaults.image_pull_policy,
+        Some(ImagePullPolicy::IfNotPresent)
+    );
+    assert_eq!(defaults.image_pull_secrets, ["regcred", "mirror"]);
+}
+
+#[test]
+fn spec_takes_precedence_over_operator_defaults() {
+    let defaults = AgentDefaults {
+        images: BTreeMap::from([(
+            Provider::AzurePipelines,
+            "registry.local/azp-agent:1.0".to_string(),
+        )]),
+        image_pull_policy: Some(ImagePullPolicy::IfNotPresent),
+        image_pull_secrets: vec!["regcred".to_string()],
+    };
+
+    let spec = spec(AgentSpec {
+        replicas: 1,
+        image: Some("registry.local/azp-agent@sha256:0123".to_string()),
+        image_pull_policy: Some(ImagePullPolicy::Always),
+        image_pull_secrets: vec!["team-regcred".to_string()],
+        pod: None,
+        autoscaling: None,
+    });
+    let provider = provider::from_spec(&spec);
+    let image = defaults.resolve(&spec, provider.as_ref());
+    assert_eq!(image.image, "registry.local/azp-agent@sha256:0123");
+    assert_eq!(image.pull_policy, Some(ImagePullPolicy::Always));
+    assert_eq!(image.pull_secrets, ["team-regcred"]);
+}
+
+#[test]
+fn provider_image_is_the_last_resort() {
+    let spec = spec(AgentSpec {
+        replicas: 1,
+        ..AgentSpec::default()
+    });
+    let provider = provider::from_spec(&spec);
+    let image = AgentDefaults::default().resolve(&spec, provider.as_ref());
+    assert_eq!(image.image, provider.image());
+    assert_eq!(image.pull_policy, None);
+    assert!(image.pull_secrets.is_empty());
+}
@@ -0,0 +1,110 @@
+use cdbootstrap::drift::{self, Subresource};
+use k8s_openapi::api::core::v1::ConfigMap;
+use serde_json::json;
+
+fn configmap(value: serde_json::Value) -> ConfigMap {
+    serde_json::from_value(value).unwrap()
+}
+
+#[test]
+fn fields_added_by_the_api_server_are_ignored() {
+    let desired = configmap(json!({
+        "metadata": {
+            "name": "team-a",
+            "labels": { "app.kubernetes.io/instance": "team-a" },
+            "annotations": {}
+        },
+        "data": { "AZP_POOL": "poc-pool" }
+    }));
+    let live = configmap(json!({
+        "metadata": {
+            "name": "team-a",
+            "labels": {
+                "app.kubernetes.io/instance": "team-a",
+                "team": "platform"
+            },
+            "resourceVersion": "4711",
+            "uid": "7d3f0a4e-0c7b-4a43-9d5e-1f1c2b3a4d5e"
+        },
+        "data": { "AZP_POOL": "poc-pool" }
+    }));
+
+    assert!(!drift::drifted(&desired, &live));
+}
+
+#[test]
+fn edited_and_added_fields_are_drift() {
+    let desired = configmap(json!({
+        "metadata": { "name": "team-a" },
+        "data": { "AZP_POOL": "poc-pool" }
+    }));
+    let edited = configmap(json!({
+        "metadata": { "name": "team-a" },
+        "data": { "AZP_POOL": "other-pool" }
+    }));
+    let missing = configmap(json!({
+        "metadata": { "name": "team-a" }
+    }));
+
+    assert!(drift::drifted(&desired, &edited));
+    assert!(drift::drifted(&desired, &missing));
+}
+
+#[test]
+fn list_entries_are_compared_by_position() {
+    let desired = json!({ "tolerations": [{ "key": "ci" }] });
+    let live = json!({ "tolerations": [{ "key": "ci", "operator": "Exists" }, { "key": "gpu" }] });
+
+    assert_eq!(
+        drift::rendered_fields(&desired, &live),
+        json!({ "tolerations": [{ "key": "ci" }, { "key": "gpu" }] })
+    );
+    assert_ne!(
+        drift::spec_hash(&desired),
+        drift::spec_hash(&drift::rendered_fields(&desired, &live))
+    );
+    assert_eq!(Subresource::NetworkPolicy.to_string(), "NetworkPolicy");
+}
+
+#[test]
+fn hash_is_stable_across_builds() {
+    assert_eq!(
+        drift::spec_hash(&json!({ "data": { "AZP_POOL": "poc-pool" } })),
+        "080d9ebd0ef98106aaba0173f642ba74264641834ad799b3f5527cb980c073b6"
+    );
+}
+
+#[test]
+fn canonicalised_quantities_are_not_drift() {
+    let desired = json!({
+        "containers": [{
+            "name": "team-a",
+            "resources": {
+                "requests": { "cpu": "0.5", "memory": "1024Mi" },
+                "limits": { "cpu": "2", "memory": "2048Mi" }
+            }
+        }]
+    });
+    let canonical = json!({
+        "containers": [{
+            "name": "team-a",
+            "resources": {
+                "requests": { "cpu": "500m", "memory": "1Gi" },
+                "limits": { "cpu": "2", "memory": "2Gi" }
+            },
+            "terminationMessagePath": "/dev/termination-log"
+        }]
+    });
+    let resized = json!({
+        "containers": [{
+            "name": "team-a",
+            "resources": {
+                "requests": { "cpu": "500m", "memory": "1Gi" },
+                "limits": { "cpu": "2", "memory": "4Gi" }
+            }
+        }]
+    });
+
+    assert!(!drift::drifted(&desired, &canonical));
+    assert!(drift::drifted(&desired, &resized));
+}
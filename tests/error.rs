@@ -0,0 +1,88 @@
+use cdbootstrap::error::{Error, RetryPolicy};
+
+#[test]
+fn errors_the_user_has_to_fix_are_not_retried() {
+    let invalid = Error::UserInputError(String::from("spec.vault.url is not a URL"));
+    assert_eq!(invalid.reason(), "InvalidSpec");
+    assert_eq!(invalid.retry_policy(), RetryPolicy::AwaitChange);
+
+    let manifest = Error::ManifestSerialization {
+        kind: "Deployment",
+        source: serde_json::from_str::<serde_json::Value>("{").unwrap_err(),
+    };
+    assert_eq!(manifest.reason(), "ManifestSerializationFailed");
+    assert_eq!(manifest.retry_policy(), RetryPolicy::AwaitChange);
+    assert!(manifest.to_string().contains("Deployment manifest"));
+
+    let identity = Error::IdentityNotAllowed(String::from("namespace team-b"));
+    assert_eq!(identity.reason(), "IdentityNotAllowed");
+    assert_eq!(identity.retry_policy(), RetryPolicy::AwaitChange);
+}
+
+#[test]
+fn errors_outside_the_resource_are_retried_with_backoff() {
+    let errors = [
+        (
+            Error::VaultAuthentication(String::from("AADSTS7000215: Invalid client secret")),
+            "VaultAuthenticationFailed",
+        ),
+        (
+            Error::VaultNotFound(String::from("https://kv-missing.vault.azure.net")),
+            "VaultNotFound",
+        ),
+        (
+            Error::Vault(String::from("429 Too Many Requests")),
+            "VaultRequestFailed",
+        ),
+        (
+            Error::SecretMissing(String::from("azp-token")),
+            "SecretMissing",
+        ),
+        (
+            Error::InvalidCertificate(String::from("expired at 2024-03-01T12:00:00Z")),
+            "InvalidCertificate",
+        ),
+        (
+            Error::TokenExchange(String::from("401")),
+            "TokenExchangeFailed",
+        ),
+        (
+            Error::Provider(String::from("503 Service Unavailable")),
+            "ProviderRequestFailed",
+        ),
+        (
+            Error::JobRequests(String::from("agent pool poc pool not found")),
+            "JobRequestsUnavailable",
+        ),
+        (
+            Error::ServiceTags(String::from("service tag AzureDevOps not found")),
+            "ServiceTagsUnavailable",
+        ),
+        (
+            Error::SubresourceConflict {
+                kind: String::from("Deployment"),
+                message: String::from("conflict with \"kubectl\": .spec.replicas"),
+            },
+            "FieldConflict",
+        ),
+    ];
+
+    for (error, reason) in errors {
+        assert_eq!(error.reason(), reason);
+        assert_eq!(error.retry_policy(), RetryPolicy::Backoff);
+    }
+}
+
+#[test]
+fn kubernetes_errors_are_retried_with_backoff() {
+    let error: Error = kube::Error::Api(kube::core::ErrorResponse {
+        status: "Failure".to_string(),
+        message: "etcdserver: request timed out".to_string(),
+        reason: "InternalError".to_string(),
+        code: 500,
+    })
+    .into();
+
+    assert_eq!(error.reason(), "ReconcileError");
+    assert_eq!(error.retry_policy(), RetryPolicy::Backoff);
+}
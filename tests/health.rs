@@ -0,0 +1,45 @@
+use cdbootstrap::health::{self, Health};
+use std::sync::Arc;
+
+#[test]
+fn ready_once_the_crd_is_installed_and_the_watcher_synced() {
+    let health = Health::default();
+    assert_eq!(health.readiness(), Err("CRD not installed"));
+
+    health.set_crd_installed();
+    assert_eq!(health.readiness(), Err("watcher not synced"));
+
+    health.set_synced();
+    assert_eq!(health.readiness(), Ok(()));
+
+    health.set_shutting_down();
+    assert_eq!(health.readiness(), Err("shutting down"));
+}
+
+#[test]
+fn a_standby_is_ready_without_a_synced_watcher() {
+    let health = Health::default();
+    health.set_crd_installed();
+    health.set_standby(true);
+    assert_eq!(health.readiness(), Ok(()));
+
+    health.set_standby(false);
+    assert_eq!(health.readiness(), Err("watcher not synced"));
+}
+
+#[tokio::test]
+async fn readyz_fails_until_ready_while_healthz_succeeds() {
+    let health = Arc::new(Health::default());
+    let routes = health::routes(health.clone());
+
+    let live = warp::test::request().path("/healthz").reply(&routes).await;
+    assert_eq!(live.status(), 200);
+    let ready = warp::test::request().path("/readyz").reply(&routes).await;
+    assert_eq!(ready.status(), 503);
+    assert_eq!(ready.body(), "CRD not installed");
+
+    health.set_crd_installed();
+    health.set_synced();
+    let ready = warp::test::request().path("/readyz").reply(&routes).await;
+    assert_eq!(ready.status(), 200);
+}
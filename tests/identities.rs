@@ -0,0 +1,54 @@
+use cdbootstrap::identities::IdentityAllowList;
+use std::collections::BTreeMap;
+
+const CLIENT_ID: &str = "11111111-2222-3333-4444-555555555555";
+
+fn allow_list(value: &str) -> IdentityAllowList {
+    let vars = BTreeMap::from([("VAULT_IDENTITY_ALLOW_LIST", value)]);
+    IdentityAllowList::from_vars(|key| vars.get(key).map(|v| v.to_string()))
+}
+
+#[test]
+fn nothing_is_allowed_without_an_allow_list() {
+    let identities = IdentityAllowList::from_vars(|_| None);
+    let error = identities
+        .check("team-a", CLIENT_ID, "https://kv-team-a.vault.azure.net/")
+        .unwrap_err();
+    assert_eq!(error.reason(), "IdentityNotAllowed");
+}
+
+#[test]
+fn only_listed_namespaces_use_the_identity_for_the_listed_vault() {
+    let identities = allow_list(&format!(
+        "team-a/{}@https://kv-team-a.vault.azure.net/, malformed, team-c/@https://kv.vault.azure.net",
+        CLIENT_ID
+    ));
+    assert_eq!(identities.allowed.len(), 1);
+
+    // The client ID and vault URL are compared without case and trailing `/`
+    assert!(identities
+        .check("team-a", CLIENT_ID, "https://kv-team-a.vault.azure.net")
+        .is_ok());
+    assert!(identities
+        .check(
+            "team-a",
+            &CLIENT_ID.to_uppercase(),
+            "https://KV-TEAM-A.vault.azure.net/"
+        )
+        .is_ok());
+
+    // Another namespace, identity or vault is rejected
+    assert!(identities
+        .check("team-b", CLIENT_ID, "https://kv-team-a.vault.azure.net")
+        .is_err());
+    assert!(identities
+        .check(
+            "team-a",
+            "99999999-2222-3333-4444-555555555555",
+            "https://kv-team-a.vault.azure.net"
+        )
+        .is_err());
+    assert!(identities
+        .check("team-a", CLIENT_ID, "https://kv-platform.vault.azure.net")
+        .is_err());
+}
@@ -0,0 +1,148 @@
+use cdbootstrap::leader::{self, LeaderConfig, Observation};
+use k8s_openapi::api::coordination::v1::Lease;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::MicroTime;
+use k8s_openapi::chrono::{Duration, TimeZone, Utc};
+use serde_json::json;
+use std::collections::BTreeMap;
+use tokio::time::Instant;
+
+fn config(identity: &str) -> LeaderConfig {
+    LeaderConfig {
+        lease_name: String::from("cdbootstrap-operator"),
+        namespace: String::from("cdbootstrap-system"),
+        identity: identity.to_owned(),
+        lease_duration: std::time::Duration::from_secs(15),
+        renew_deadline: std::time::Duration::from_secs(10),
+        retry_interval: std::time::Duration::from_secs(3),
+    }
+}
+
+fn lease(holder: &str, renewed: MicroTime) -> Lease {
+    serde_json::from_value(json!({
+        "metadata": { "name": "cdbootstrap-operator", "resourceVersion": "4711" },
+        "spec": {
+            "holderIdentity": holder,
+            "leaseDurationSeconds": 15,
+            "acquireTime": renewed,
+            "renewTime": renewed,
+            "leaseTransitions": 2
+        }
+    }))
+    .unwrap()
+}
+
+#[test]
+fn leader_election_is_configured_from_the_environment() {
+    let config = |vars: &[(&str, &str)]| {
+        let vars: BTreeMap<&str, &str> = vars.iter().copied().collect();
+        LeaderConfig::from_vars(|key| vars.get(key).map(|v| v.to_string()))
+    };
+
+    assert_eq!(config(&[]), None);
+    assert_eq!(config(&[("LEADER_ELECTION", "false")]), None);
+
+    let enabled = config(&[
+        ("LEADER_ELECTION", "true"),
+        ("POD_NAMESPACE", "cdbootstrap-system"),
+        ("POD_NAME", "cdbootstrap-operator-7d9f-x2k4q"),
+        ("LEASE_DURATION_SECONDS", "30"),
+    ])
+    .unwrap();
+    assert_eq!(enabled.lease_name, "cdbootstrap-operator");
+    assert_eq!(enabled.namespace, "cdbootstrap-system");
+    assert_eq!(enabled.identity, "cdbootstrap-operator-7d9f-x2k4q");
+    assert_eq!(enabled.lease_duration.as_secs(), 30);
+    assert_eq!(enabled.renew_deadline.as_secs(), 20);
+    assert_eq!(enabled.retry_interval.as_secs(), 6);
+    // The leader gives up before a standby takes over
+    assert!(enabled.renew_deadline < enabled.lease_duration);
+}
+
+#[test]
+fn a_lease_renewed_by_another_replica_is_not_taken_over() {
+    let now = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+    let held = lease("replica-a", MicroTime(now - Duration::seconds(10)));
+    let unchanged_for = std::time::Duration::from_secs(10);
+
+    assert!(!leader::expired(&held, unchanged_for));
+    assert_eq!(
+        leader::claim(Some(&held), unchanged_for, &config("replica-b"), now),
+        None
+    );
+}
+
+#[test]
+fn lease_expiry_does_not_depend_on_the_clock_of_the_holder() {
+    // The clock of the holder runs an hour behind, its renewals look stale to this replica
+    let now = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+    let skewed = |seconds| lease("replica-a", MicroTime(now - Duration::hours(1) + seconds));
+    let start = Instant::now();
+    let seconds = std::time::Duration::from_secs;
+
+    let seen = Observation::new(None, Some(&skewed(Duration::zero())), start);
+    assert!(!leader::expired(
+        &skewed(Duration::zero()),
+        seen.unchanged_for(start)
+    ));
+
+    // Renewed within the lease duration, as seen by this replica
+    let renewed = skewed(Duration::seconds(3));
+    let seen = Observation::new(Some(&seen), Some(&renewed), start + seconds(12));
+    assert_eq!(seen.unchanged_for(start + seconds(12)), seconds(0));
+    assert_eq!(
+        leader::claim(
+            Some(&renewed),
+            seen.unchanged_for(start + seconds(12)),
+            &config("replica-b"),
+            now
+        ),
+        None
+    );
+
+    // Not renewed for longer than the lease duration
+    let seen = Observation::new(Some(&seen), Some(&renewed), start + seconds(28));
+    assert_eq!(seen.unchanged_for(start + seconds(28)), seconds(16));
+    assert!(leader::expired(
+        &renewed,
+        seen.unchanged_for(start + seconds(28))
+    ));
+}
+
+#[test]
+fn an_expired_lease_is_taken_over() {
+    let now = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+    let held = lease("replica-a", MicroTime(now - Duration::seconds(20)));
+    let unchanged_for = std::time::Duration::from_secs(20);
+    assert!(leader::expired(&held, unchanged_for));
+
+    let claimed = leader::claim(Some(&held), unchanged_for, &config("replica-b"), now)
+        .unwrap()
+        .spec
+        .unwrap();
+    assert_eq!(claimed.holder_identity.as_deref(), Some("replica-b"));
+    assert_eq!(claimed.acquire_time, Some(MicroTime(now)));
+    assert_eq!(claimed.renew_time, Some(MicroTime(now)));
+    assert_eq!(claimed.lease_transitions, Some(3));
+}
+
+#[test]
+fn the_leader_renews_its_lease() {
+    let now = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+    let acquired = MicroTime(now - Duration::seconds(10));
+    let held = lease("replica-a", acquired.clone());
+
+    let renewed = leader::claim(Some(&held), Default::default(), &config("replica-a"), now)
+        .unwrap()
+        .spec
+        .unwrap();
+    assert_eq!(renewed.acquire_time, Some(acquired));
+    assert_eq!(renewed.renew_time, Some(MicroTime(now)));
+    assert_eq!(renewed.lease_transitions, Some(2));
+
+    let created = leader::claim(None, Default::default(), &config("replica-a"), now).unwrap();
+    assert_eq!(
+        created.metadata.name.as_deref(),
+        Some("cdbootstrap-operator")
+    );
+    assert_eq!(created.spec.unwrap().lease_transitions, Some(0));
+}
@@ -0,0 +1,50 @@
+use cdbootstrap::metrics::metrics;
+use std::time::Duration;
+
+#[test]
+fn reconciliations_are_counted_by_action_and_result() {
+    metrics().reconciled("Create", true, Duration::from_millis(120));
+    metrics().reconciled("Validate", false, Duration::from_millis(3));
+
+    let rendered = metrics().render().unwrap();
+    assert!(
+        rendered.contains(r#"cdbootstrap_reconciliations_total{action="Create",result="success"}"#)
+    );
+    assert!(
+        rendered.contains(r#"cdbootstrap_reconciliations_total{action="Validate",result="error"}"#)
+    );
+    assert!(rendered.contains(r#"cdbootstrap_reconcile_duration_seconds_bucket{action="Create""#));
+}
+
+#[test]
+fn only_failed_vault_requests_are_counted_as_errors() {
+    metrics().vault_request("list_secrets", true, Duration::from_millis(40));
+    metrics().vault_request("get_secret", false, Duration::from_millis(40));
+
+    let rendered = metrics().render().unwrap();
+    assert!(rendered
+        .contains(r#"cdbootstrap_vault_request_duration_seconds_count{operation="list_secrets"}"#));
+    assert!(
+        rendered.contains(r#"cdbootstrap_vault_request_errors_total{operation="get_secret"} 1"#)
+    );
+    assert!(
+        !rendered.contains(r#"cdbootstrap_vault_request_errors_total{operation="list_secrets"}"#)
+    );
+}
+
+#[test]
+fn agent_gauges_are_removed_with_the_resource() {
+    metrics().agents("team-a", "pool-a", 3, 2);
+
+    let rendered = metrics().render().unwrap();
+    assert!(rendered
+        .contains(r#"cdbootstrap_agent_replicas_desired{name="pool-a",namespace="team-a"} 3"#));
+    assert!(rendered
+        .contains(r#"cdbootstrap_agent_replicas_ready{name="pool-a",namespace="team-a"} 2"#));
+
+    metrics().forget("team-a", "pool-a");
+    assert!(!metrics()
+        .render()
+        .unwrap()
+        .contains(r#"namespace="team-a""#));
+}
@@ -0,0 +1,99 @@
+use cdbootstrap::crd::{AzureDevOpsSpec, GitHubSpec, GitLabSpec};
+use cdbootstrap::error::Error;
+use cdbootstrap::provider::{AzurePipelines, CiProvider, GitHubActions, GitLabRunner};
+
+fn github(url: &str) -> GitHubActions {
+    GitHubActions::new(GitHubSpec {
+        url: url.to_string(),
+        labels: vec!["linux".to_string(), "k8s".to_string()],
+        ..GitHubSpec::default()
+    })
+}
+
+#[test]
+fn azure_pipelines_renders_azp_configuration() {
+    let provider = AzurePipelines::new(AzureDevOpsSpec {
+        url: "https://dev.azure.com/DevOps-SST".to_string(),
+        pool: "poc-pool".to_string(),
+    });
+    let config = provider.config();
+    assert_eq!(config["AZP_URL"], "https://dev.azure.com/DevOps-SST");
+    assert_eq!(config["AZP_POOL"], "poc-pool");
+
+    let env = provider.env("test-bootstrap");
+    let names: Vec<&str> = env.iter().filter_map(|e| e["name"].as_str()).collect();
+    assert_eq!(names, ["AZP_TOKEN", "SPN_SECRET", "AZP_POOL", "AZP_URL"]);
+}
+
+#[test]
+fn github_renders_runner_configuration() {
+    let provider = github("https://github.com/cndev/platform");
+    let config = provider.config();
+    assert_eq!(config["RUNNER_URL"], "https://github.com/cndev/platform");
+    assert_eq!(config["RUNNER_LABELS"], "linux,k8s");
+    assert!(!config.contains_key("RUNNER_GROUP"));
+
+    let env = provider.env("test-bootstrap");
+    assert_eq!(env[0]["name"], "RUNNER_TOKEN");
+    assert_eq!(
+        env[0]["valueFrom"]["secretKeyRef"]["name"],
+        "test-bootstrap"
+    );
+}
+
+#[test]
+fn github_registration_endpoint_for_organization_and_repository() {
+    assert_eq!(
+        github("https://github.com/cndev")
+            .registration_endpoint()
+            .unwrap(),
+        "https://api.github.com/orgs/cndev/actions/runners/registration-token"
+    );
+    assert_eq!(
+        github("https://github.com/cndev/platform/")
+            .registration_endpoint()
+            .unwrap(),
+        "https://api.github.com/repos/cndev/platform/actions/runners/registration-token"
+    );
+    // Only a change of the specification resolves a URL of neither
+    assert!(matches!(
+        github("https://github.com/").registration_endpoint(),
+        Err(Error::UserInputError(_))
+    ));
+}
+
+#[test]
+fn github_enterprise_server_api_url() {
+    let provider = GitHubActions::new(GitHubSpec {
+        url: "https://github.example.com/cndev".to_string(),
+        api_url: Some("https://github.example.com/api/v3/".to_string()),
+        ..GitHubSpec::default()
+    });
+    assert_eq!(
+        provider.registration_endpoint().unwrap(),
+        "https://github.example.com/api/v3/orgs/cndev/actions/runners/registration-token"
+    );
+}
+
+#[test]
+fn gitlab_renders_config_toml_without_token() {
+    let provider = GitLabRunner::new(GitLabSpec {
+        url: "https://gitlab.com/".to_string(),
+        executor: None,
+    });
+    let config = provider.config();
+    assert_eq!(config["CI_SERVER_URL"], "https://gitlab.com");
+    assert_eq!(
+        config["config.toml"],
+        "[[runners]]\n  url = \"https://gitlab.com\"\n  executor = \"shell\"\n"
+    );
+
+    let env = provider.env("test-bootstrap");
+    assert_eq!(env[0]["name"], "CI_SERVER_TOKEN");
+    assert!(env.iter().all(|e| e["name"] != "config.toml"));
+    assert_eq!(
+        provider.volume_mounts().len(),
+        provider.volumes("test-bootstrap").len()
+    );
+    assert!(provider.command().is_some());
+}
@@ -0,0 +1,21 @@
+use cdbootstrap::quantity;
+
+#[test]
+fn quantities_are_compared_by_value() {
+    assert_eq!(quantity::nanos("500m"), quantity::nanos("0.5"));
+    assert_eq!(quantity::nanos("1Gi"), quantity::nanos("1024Mi"));
+    assert_eq!(quantity::nanos("1k"), quantity::nanos("1e3"));
+    assert_eq!(quantity::nanos("2"), Ok(2_000_000_000));
+    assert_eq!(quantity::nanos("1Ki"), Ok(1_024_000_000_000));
+    assert_eq!(quantity::nanos("-1.5"), Ok(-1_500_000_000));
+    assert_eq!(quantity::nanos("100n"), Ok(100));
+    // Rounded up to whole units of 10^-9, like the API server does
+    assert_eq!(quantity::nanos("0.1n"), Ok(1));
+}
+
+#[test]
+fn malformed_quantities_are_rejected() {
+    for malformed in ["", "one", "2GB", "1.2.3", "Mi", "1e", "1 Gi", "--1", "1mi"] {
+        assert!(quantity::nanos(malformed).is_err(), "{}", malformed);
+    }
+}
@@ -0,0 +1,150 @@
+use cdbootstrap::crd::{CDBootstrap, CDBootstrapSpec, ServiceTagStatus};
+use cdbootstrap::error::Error;
+use cdbootstrap::provider;
+use cdbootstrap::service_tags::{self, ServiceTagConfig, ServiceTags, Source, AZURE_DEVOPS_TAG};
+use cdbootstrap::subresources::AgentPolicy;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+const DOCUMENT: &str = r#"{
+  "changeNumber": 312,
+  "cloud": "Public",
+  "values": [
+    {
+      "name": "AzureDevOps",
+      "id": "AzureDevOps",
+      "properties": {
+        "changeNumber": 7,
+        "region": "",
+        "regionId": 0,
+        "platform": "Azure",
+        "systemService": "AzureDevOps",
+        "addressPrefixes": ["20.37.158.0/23", "13.107.6.0/24", "2603:1030:40b:400::/56"]
+      }
+    },
+    {
+      "name": "AzureDevOps.WestEurope",
+      "id": "AzureDevOps.WestEurope",
+      "properties": {
+        "changeNumber": 3,
+        "region": "westeurope",
+        "systemService": "AzureDevOps",
+        "addressPrefixes": ["40.74.28.0/23"]
+      }
+    },
+    {
+      "name": "AzureCloud.westeurope",
+      "id": "AzureCloud.westeurope",
+      "properties": {
+        "changeNumber": 41,
+        "region": "westeurope",
+        "systemService": "",
+        "addressPrefixes": ["13.69.0.0/17"]
+      }
+    }
+  ]
+}"#;
+
+#[test]
+fn global_tag_is_selected_by_name() {
+    let tag = service_tags::select(DOCUMENT, AZURE_DEVOPS_TAG, None).unwrap();
+
+    assert_eq!(tag.name, "AzureDevOps");
+    assert_eq!(tag.change_number, 7);
+    assert_eq!(
+        tag.address_prefixes,
+        ["13.107.6.0/24", "20.37.158.0/23", "2603:1030:40b:400::/56"]
+    );
+    assert_eq!(
+        tag.status(),
+        ServiceTagStatus {
+            name: "AzureDevOps".to_string(),
+            region: None,
+            change_number: 7,
+        }
+    );
+}
+
+#[test]
+fn regional_tag_is_filtered_on_the_service() {
+    let tag = service_tags::select(DOCUMENT, AZURE_DEVOPS_TAG, Some("WestEurope")).unwrap();
+
+    assert_eq!(tag.name, "AzureDevOps.WestEurope");
+    assert_eq!(tag.region.as_deref(), Some("WestEurope"));
+    assert_eq!(tag.address_prefixes, ["40.74.28.0/23"]);
+
+    assert!(matches!(
+        service_tags::select(DOCUMENT, AZURE_DEVOPS_TAG, Some("northeurope")),
+        Err(Error::ServiceTags(_))
+    ));
+    assert!(matches!(
+        service_tags::select("{}", AZURE_DEVOPS_TAG, None),
+        Err(Error::ServiceTags(_))
+    ));
+}
+
+#[test]
+fn change_number_decides_on_a_change() {
+    let tags = ServiceTags::default();
+    assert!(tags.current().is_none());
+
+    let tag = service_tags::select(DOCUMENT, AZURE_DEVOPS_TAG, None).unwrap();
+    assert!(tags.set(tag.clone()));
+    assert!(!tags.set(tag.clone()));
+    assert!(tags.set(service_tags::ServiceTag {
+        change_number: 8,
+        ..tag
+    }));
+    assert_eq!(tags.current().unwrap().change_number, 8);
+}
+
+#[test]
+fn tag_replaces_the_built_in_ranges() {
+    let cr = CDBootstrap::new("team-a", CDBootstrapSpec::default());
+    let provider = provider::from_spec(&cr.spec);
+    let tag = service_tags::select(DOCUMENT, AZURE_DEVOPS_TAG, None).unwrap();
+    let egress = AgentPolicy::egress(&cr, provider.as_ref(), Some(&tag));
+
+    assert_eq!(egress[0]["to"].as_array().unwrap().len(), 3);
+    assert_eq!(egress[0]["to"][0]["ipBlock"]["cidr"], "13.107.6.0/24");
+}
+
+#[test]
+fn config_is_read_from_variables() {
+    let config = |vars: &[(&str, &str)]| {
+        let vars: BTreeMap<&str, &str> = vars.iter().copied().collect();
+        ServiceTagConfig::from_vars(|key| vars.get(key).map(|v| v.to_string()))
+    };
+
+    assert_eq!(config(&[]), None);
+    assert_eq!(config(&[("SERVICE_TAGS_CONFIGMAP", "no-namespace")]), None);
+
+    let file = config(&[
+        (
+            "SERVICE_TAGS_FILE",
+            "/etc/service-tags/ServiceTags_Public.json",
+        ),
+        ("SERVICE_TAGS_REGION", "westeurope"),
+    ])
+    .unwrap();
+    assert_eq!(
+        file.source,
+        Source::File(PathBuf::from("/etc/service-tags/ServiceTags_Public.json"))
+    );
+    assert_eq!(file.region.as_deref(), Some("westeurope"));
+
+    let configmap = config(&[
+        ("SERVICE_TAGS_CONFIGMAP", "cdbootstrap-system/service-tags"),
+        ("SERVICE_TAGS_REFRESH_SECONDS", "60"),
+    ])
+    .unwrap();
+    assert_eq!(
+        configmap.source,
+        Source::ConfigMap {
+            namespace: "cdbootstrap-system".to_string(),
+            name: "service-tags".to_string(),
+            key: "ServiceTags_Public.json".to_string(),
+        }
+    );
+    assert_eq!(configmap.refresh.as_secs(), 60);
+}
@@ -0,0 +1,93 @@
+use cdbootstrap::crd::{CDBootstrapStatus, ConditionStatus, Phase};
+use cdbootstrap::status::{
+    stamp, AGENTS_AVAILABLE, NETWORK_POLICY_APPLIED, READY, SECRETS_RESOLVED,
+};
+
+#[test]
+fn transition_time_only_moves_on_status_change() {
+    let mut status = CDBootstrapStatus::default();
+    status.set_condition(SECRETS_RESOLVED, ConditionStatus::False, "TokenPending", "");
+    status.conditions[0].last_transition_time = String::from("2023-01-01T00:00:00Z");
+
+    status.set_condition(
+        SECRETS_RESOLVED,
+        ConditionStatus::False,
+        "MissingCredentials",
+        "",
+    );
+    let condition = status.condition(SECRETS_RESOLVED).unwrap();
+    assert_eq!(condition.reason, "MissingCredentials");
+    assert_eq!(condition.last_transition_time, "2023-01-01T00:00:00Z");
+
+    status.set_condition(SECRETS_RESOLVED, ConditionStatus::True, "TokenPresent", "");
+    let condition = status.condition(SECRETS_RESOLVED).unwrap();
+    assert_ne!(condition.last_transition_time, "2023-01-01T00:00:00Z");
+    assert_eq!(status.conditions.len(), 1);
+}
+
+#[test]
+fn ready_requires_secrets_and_agents() {
+    let mut status = CDBootstrapStatus::default();
+    status.set_condition(
+        AGENTS_AVAILABLE,
+        ConditionStatus::True,
+        "MinimumReplicasAvailable",
+        "",
+    );
+    status.refresh();
+    assert!(!status.is_true(READY));
+    assert_eq!(status.phase, Phase::Provisioning);
+    assert!(status
+        .condition(READY)
+        .unwrap()
+        .message
+        .contains(SECRETS_RESOLVED));
+
+    status.set_condition(SECRETS_RESOLVED, ConditionStatus::True, "TokenPresent", "");
+    status.refresh();
+    assert!(status.is_true(READY));
+    assert_eq!(status.phase, Phase::Running);
+}
+
+#[test]
+fn failing_network_policy_blocks_ready() {
+    let mut status = CDBootstrapStatus::default();
+    status.set_condition(
+        AGENTS_AVAILABLE,
+        ConditionStatus::True,
+        "MinimumReplicasAvailable",
+        "",
+    );
+    status.set_condition(SECRETS_RESOLVED, ConditionStatus::True, "TokenPresent", "");
+    status.set_condition(
+        NETWORK_POLICY_APPLIED,
+        ConditionStatus::False,
+        "NetworkPolicyApplyFailed",
+        "",
+    );
+    status.phase = Phase::Degraded;
+    status.refresh();
+    assert!(!status.is_true(READY));
+    assert_eq!(status.phase, Phase::Degraded);
+}
+
+#[test]
+fn reconcile_time_only_moves_with_the_status() {
+    let mut previous = CDBootstrapStatus::default();
+    previous.set_condition(SECRETS_RESOLVED, ConditionStatus::True, "TokenPresent", "");
+    previous.last_reconcile_time = Some(String::from("2023-01-01T00:00:00Z"));
+
+    // A reconciliation that observes the same state does not write the status
+    let mut status = previous.clone();
+    status.last_reconcile_time = None;
+    assert!(!stamp(Some(&previous), &mut status));
+    assert_eq!(status, previous);
+
+    status.ready_replicas = Some(2);
+    assert!(stamp(Some(&previous), &mut status));
+    assert_ne!(status.last_reconcile_time, previous.last_reconcile_time);
+
+    let mut first = CDBootstrapStatus::default();
+    assert!(stamp(None, &mut first));
+    assert!(first.last_reconcile_time.is_some());
+}
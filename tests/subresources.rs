@@ -0,0 +1,345 @@
+use cdbootstrap::crd::{
+    AzureDevOpsSpec, CDBootstrap, CDBootstrapSpec, GitHubSpec, NetworkPolicySpec, PortSpec,
+    Protocol,
+};
+use cdbootstrap::defaults::AgentImage;
+use cdbootstrap::provider::{self, CiProvider, GitHubActions};
+use cdbootstrap::subresources::{
+    agent_labels, agent_selector, config_checksum, is_conflict, owned_data_keys, replicas_manager,
+    Agent, AgentConfig, AgentPolicy, AgentSecret,
+};
+use k8s_openapi::api::apps::v1::Deployment;
+use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::ByteString;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use warp::Filter;
+
+#[test]
+fn selector_is_unique_per_resource() {
+    let first = CDBootstrap::new("team-a", CDBootstrapSpec::default());
+    let second = CDBootstrap::new("team-b", CDBootstrapSpec::default());
+
+    assert_ne!(agent_selector(&first), agent_selector(&second));
+    assert_eq!(
+        agent_selector(&first)["app.kubernetes.io/instance"],
+        "team-a"
+    );
+}
+
+#[test]
+fn pod_labels_match_the_selector() {
+    let cr = CDBootstrap::new("team-a", CDBootstrapSpec::default());
+    let labels = agent_labels(&cr);
+    for (key, value) in agent_selector(&cr) {
+        assert_eq!(labels.get(&key), Some(&value));
+    }
+    assert_eq!(
+        labels["app.kubernetes.io/managed-by"],
+        "cdbootstrap-operator"
+    );
+    assert!(!labels.contains_key("app"));
+}
+
+fn azure_pipelines() -> CDBootstrap {
+    CDBootstrap::new(
+        "team-a",
+        CDBootstrapSpec {
+            azure_devops: Some(AzureDevOpsSpec {
+                url: "https://dev.azure.com/DevOps-SST".to_string(),
+                pool: "poc-pool".to_string(),
+            }),
+            ..CDBootstrapSpec::default()
+        },
+    )
+}
+
+#[test]
+fn default_egress_allows_azure_devops_and_dns() {
+    let cr = azure_pipelines();
+    let provider = provider::from_spec(&cr.spec);
+    let egress = AgentPolicy::egress(&cr, provider.as_ref(), None);
+
+    assert_eq!(egress.len(), 2);
+    assert_eq!(egress[0]["to"][0]["ipBlock"]["cidr"], "13.107.6.0/24");
+    assert_eq!(
+        egress[0]["ports"],
+        json!([{ "port": 443, "protocol": "TCP" }])
+    );
+    assert_eq!(egress[1]["ports"][0]["port"], 53);
+}
+
+#[test]
+fn egress_follows_the_specification() {
+    let mut cr = azure_pipelines();
+    cr.spec.network_policy = Some(NetworkPolicySpec {
+        cidrs: vec!["10.20.0.0/16".to_string()],
+        ports: vec![PortSpec {
+            port: 8443,
+            protocol: Protocol::Tcp,
+        }],
+        dns: false,
+        extra_egress: vec![json!({ "to": [{ "ipBlock": { "cidr": "10.30.0.0/16" } }] })],
+        ..NetworkPolicySpec::default()
+    });
+    let provider = provider::from_spec(&cr.spec);
+    let egress = AgentPolicy::egress(&cr, provider.as_ref(), None);
+
+    assert_eq!(
+        egress,
+        [
+            json!({
+                "to": [{ "ipBlock": { "cidr": "10.20.0.0/16" } }],
+                "ports": [{ "port": 8443, "protocol": "TCP" }]
+            }),
+            json!({ "to": [{ "ipBlock": { "cidr": "10.30.0.0/16" } }] }),
+        ]
+    );
+}
+
+#[test]
+fn conflicts_are_recognised() {
+    let error = |code: u16, reason: &str| {
+        kube::Error::Api(kube::core::ErrorResponse {
+            status: "Failure".to_string(),
+            message: "Apply failed with 1 conflict".to_string(),
+            reason: reason.to_string(),
+            code,
+        })
+    };
+
+    assert!(is_conflict(&error(409, "Conflict")));
+    assert!(!is_conflict(&error(409, "AlreadyExists")));
+    assert!(!is_conflict(&error(404, "NotFound")));
+}
+
+#[test]
+fn checksum_changes_with_the_configmap_and_secret_data() {
+    let keys = ["AZP_TOKEN", "SPN_SECRET"];
+    let config = BTreeMap::from([("AZP_URL".to_owned(), "https://dev.azure.com/org".to_owned())]);
+    let secret = BTreeMap::from([("AZP_TOKEN".to_owned(), ByteString(b"first".to_vec()))]);
+    let checksum = config_checksum(&config, &secret, &keys);
+    assert_eq!(checksum, config_checksum(&config, &secret, &keys));
+
+    let rotated = BTreeMap::from([("AZP_TOKEN".to_owned(), ByteString(b"second".to_vec()))]);
+    assert_ne!(checksum, config_checksum(&config, &rotated, &keys));
+
+    let moved = BTreeMap::from([(
+        "AZP_URL".to_owned(),
+        "https://dev.azure.com/other".to_owned(),
+    )]);
+    assert_ne!(checksum, config_checksum(&moved, &secret, &keys));
+}
+
+#[test]
+fn renewed_registration_token_keeps_the_checksum() {
+    let provider = GitHubActions::new(GitHubSpec {
+        url: "https://github.com/cndev/platform".to_string(),
+        ..GitHubSpec::default()
+    });
+    let secret = |token: &[u8], expires_at: &[u8]| {
+        BTreeMap::from([
+            ("RUNNER_TOKEN".to_owned(), ByteString(token.to_vec())),
+            (
+                "RUNNER_TOKEN_EXPIRES_AT".to_owned(),
+                ByteString(expires_at.to_vec()),
+            ),
+            ("SPN_SECRET".to_owned(), ByteString(b"spn-secret".to_vec())),
+        ])
+    };
+    let checksum = |secret: BTreeMap<String, ByteString>| {
+        config_checksum(&provider.config(), &secret, &provider.restart_keys())
+    };
+
+    let issued = checksum(secret(b"AABF3JGZ", b"2024-03-01T13:00:00Z"));
+    let renewed = checksum(secret(b"AABF3KQX", b"2024-03-01T14:00:00Z"));
+    assert_eq!(issued, renewed);
+
+    let mut spn_rotated = secret(b"AABF3KQX", b"2024-03-01T14:00:00Z");
+    spn_rotated.insert("SPN_SECRET".to_owned(), ByteString(b"rotated".to_vec()));
+    assert_ne!(issued, checksum(spn_rotated));
+}
+
+#[test]
+fn replicas_scaled_by_an_autoscaler_are_yielded() {
+    let deployment = |managed_fields: serde_json::Value| -> Deployment {
+        serde_json::from_value(json!({
+            "metadata": { "name": "team-a", "managedFields": managed_fields }
+        }))
+        .unwrap()
+    };
+    let applied = json!({
+        "manager": "cdbootstrap-operator",
+        "operation": "Apply",
+        "fieldsType": "FieldsV1",
+        "fieldsV1": { "f:spec": { "f:replicas": {}, "f:template": {} } }
+    });
+    let scaled = json!({
+        "manager": "kube-controller-manager",
+        "operation": "Update",
+        "subresource": "scale",
+        "fieldsType": "FieldsV1",
+        "fieldsV1": { "f:spec": { "f:replicas": {} } }
+    });
+    let status = json!({
+        "manager": "kube-controller-manager",
+        "operation": "Update",
+        "subresource": "status",
+        "fieldsType": "FieldsV1",
+        "fieldsV1": { "f:status": { "f:replicas": {} } }
+    });
+
+    assert_eq!(
+        replicas_manager(&deployment(json!([applied, status]))),
+        None
+    );
+    assert_eq!(
+        replicas_manager(&deployment(json!([applied, status, scaled]))),
+        Some("kube-controller-manager".to_string())
+    );
+    assert_eq!(replicas_manager(&deployment(json!(null))), None);
+
+    // Replicas patched under the name of the operator, outside server-side apply
+    let patched = json!({
+        "manager": "cdbootstrap-operator",
+        "operation": "Update",
+        "fieldsType": "FieldsV1",
+        "fieldsV1": { "f:spec": { "f:replicas": {} } }
+    });
+    assert_eq!(
+        replicas_manager(&deployment(json!([applied, patched]))),
+        Some("cdbootstrap-operator".to_string())
+    );
+}
+
+#[test]
+fn data_written_by_earlier_versions_is_found() {
+    let secret: Secret = serde_json::from_value(json!({
+        "metadata": {
+            "name": "team-a",
+            "managedFields": [
+                {
+                    "manager": "cdbootstrap-operator",
+                    "operation": "Apply",
+                    "fieldsType": "FieldsV1",
+                    "fieldsV1": {
+                        "f:data": { "f:AZP_TOKEN": {}, "f:SPN_SECRET": {} },
+                        "f:metadata": { "f:labels": {} }
+                    }
+                },
+                {
+                    "manager": "cdbootstrap-operator-token",
+                    "operation": "Apply",
+                    "fieldsType": "FieldsV1",
+                    "fieldsV1": { "f:stringData": { "f:AZP_TOKEN_EXPIRES_AT": {} } }
+                },
+                {
+                    "manager": "kubectl-client-side-apply",
+                    "operation": "Update",
+                    "fieldsType": "FieldsV1",
+                    "fieldsV1": { "f:data": { "f:SPN_SECRET": {} } }
+                }
+            ]
+        }
+    }))
+    .unwrap();
+
+    assert_eq!(
+        owned_data_keys(&secret, "cdbootstrap-operator"),
+        ["AZP_TOKEN", "SPN_SECRET"]
+    );
+    assert_eq!(
+        owned_data_keys(&secret, "cdbootstrap-operator-token"),
+        ["AZP_TOKEN_EXPIRES_AT"]
+    );
+    assert!(owned_data_keys(&Secret::default(), "cdbootstrap-operator").is_empty());
+}
+
+#[tokio::test]
+async fn deleting_subresources_that_are_gone_succeeds() {
+    // A Kubernetes API server on which every subresource was deleted by an earlier attempt
+    let gone = warp::delete().map(|| {
+        warp::reply::with_status(
+            warp::reply::json(&json!({
+                "kind": "Status",
+                "apiVersion": "v1",
+                "status": "Failure",
+                "message": "not found",
+                "reason": "NotFound",
+                "code": 404
+            })),
+            warp::http::StatusCode::NOT_FOUND,
+        )
+    });
+    let (addr, server) = warp::serve(gone).bind_ephemeral(SocketAddr::from(([127, 0, 0, 1], 0)));
+    tokio::spawn(server);
+    let config = kube::Config::new(format!("http://{}", addr).parse().unwrap());
+    let client = kube::Client::try_from(config).unwrap();
+
+    assert!(Agent::delete(client.clone(), "team-a", "team-a")
+        .await
+        .is_ok());
+    assert!(AgentConfig::delete(client.clone(), "team-a", "team-a")
+        .await
+        .is_ok());
+    assert!(AgentSecret::delete(client.clone(), "team-a", "team-a")
+        .await
+        .is_ok());
+    assert!(AgentPolicy::delete(client, "team-a", "team-a")
+        .await
+        .is_ok());
+}
+
+#[tokio::test]
+async fn deployment_with_an_old_selector_is_deleted_before_it_is_applied() {
+    let path =
+        warp::path!("apis" / "apps" / "v1" / "namespaces" / "team-a" / "deployments" / "team-a");
+    let get = path.and(warp::get()).map(|| {
+        warp::reply::json(&json!({
+            "apiVersion": "apps/v1",
+            "kind": "Deployment",
+            "metadata": { "name": "team-a", "namespace": "team-a" },
+            "spec": {
+                "selector": { "matchLabels": { "app": "example" } },
+                "template": {}
+            }
+        }))
+    });
+    let deletes: Arc<Mutex<Vec<Value>>> = Arc::default();
+    let recorded = deletes.clone();
+    let delete = path
+        .and(warp::delete())
+        .and(warp::body::json())
+        .map(move |params: Value| {
+            recorded.lock().unwrap().push(params);
+            warp::reply::json(&json!({
+                "kind": "Status",
+                "apiVersion": "v1",
+                "status": "Success",
+                "code": 200
+            }))
+        });
+    let (addr, server) =
+        warp::serve(get.or(delete)).bind_ephemeral(SocketAddr::from(([127, 0, 0, 1], 0)));
+    tokio::spawn(server);
+    let config = kube::Config::new(format!("http://{}", addr).parse().unwrap());
+    let client = kube::Client::try_from(config).unwrap();
+
+    let cr = azure_pipelines();
+    let provider = provider::from_spec(&cr.spec);
+    let image = AgentImage {
+        image: provider.image().to_string(),
+        pull_policy: None,
+        pull_secrets: vec![],
+    };
+    // Nothing is applied until the old agent pods are gone, any other request would fail
+    let applied = Agent::apply(client, "team-a", "team-a", &cr, provider.as_ref(), &image)
+        .await
+        .unwrap();
+    assert!(applied.is_none());
+    let deletes = deletes.lock().unwrap();
+    assert_eq!(deletes.len(), 1);
+    assert_eq!(deletes[0]["propagationPolicy"], "Foreground");
+}
@@ -0,0 +1,163 @@
+use cdbootstrap::crd::{
+    AgentPodSpec, AgentSpec, AutoscalingSpec, AzureDevOpsSpec, CDBootstrapSpec, NetworkPolicySpec,
+    Provider, ResourceRequirements, VaultAuth, VaultCertificateSpec, VaultSpec,
+};
+use cdbootstrap::provider;
+use garde::Validate;
+use serde_json::json;
+use std::collections::BTreeMap;
+
+fn valid_spec() -> CDBootstrapSpec {
+    CDBootstrapSpec {
+        provider: Provider::AzurePipelines,
+        azure_devops: Some(AzureDevOpsSpec {
+            url: "https://dev.azure.com/DevOps-SST".to_string(),
+            pool: "poc-pool".to_string(),
+        }),
+        github: None,
+        gitlab: None,
+        network_policy: None,
+        vault: VaultSpec {
+            url: "https://kmcs-p-weu-prd.vault.azure.net/".to_string(),
+            secret_name: "mycluster-default".to_string(),
+            client_id: "69f74670-5cf9-4cfe-b795-8dc3a6cc975f".to_string(),
+            tenant_id: "0baeb517-c6ec-4d6c-a394-96a5affa5ada".to_string(),
+            ..VaultSpec::default()
+        },
+        agent: AgentSpec {
+            replicas: 2,
+            ..AgentSpec::default()
+        },
+    }
+}
+
+#[test]
+fn sample_spec_is_valid() {
+    assert!(valid_spec().validate(&()).is_ok());
+}
+
+#[test]
+fn negative_replicas_are_rejected() {
+    let mut spec = valid_spec();
+    spec.agent.replicas = -1;
+    assert!(spec.validate(&()).is_err());
+}
+
+#[test]
+fn malformed_urls_and_ids_are_rejected() {
+    let mut spec = valid_spec();
+    spec.azure_devops.as_mut().unwrap().url = "dev.azure.com".to_string();
+    spec.vault.url = "not a url".to_string();
+    spec.vault.client_id = "my-spn".to_string();
+    spec.vault.tenant_id = String::new();
+    let errors = spec.validate(&()).unwrap_err().to_string();
+    for field in ["azure_devops", "url", "client_id", "tenant_id"] {
+        assert!(errors.contains(field), "expected {} in {}", field, errors);
+    }
+}
+
+#[test]
+fn empty_pool_and_secret_name_are_rejected() {
+    let mut spec = valid_spec();
+    spec.azure_devops.as_mut().unwrap().pool = String::new();
+    spec.vault.secret_name = String::new();
+    assert!(spec.validate(&()).is_err());
+}
+
+#[test]
+fn provider_section_is_required() {
+    let mut spec = valid_spec();
+    assert!(provider::check(&spec).is_ok());
+
+    spec.provider = Provider::GitHubActions;
+    assert!(provider::check(&spec).is_err());
+}
+
+#[test]
+fn certificate_auth_requires_a_certificate() {
+    let mut spec = valid_spec();
+    spec.vault.auth = VaultAuth::Certificate;
+    assert!(provider::check(&spec).is_err());
+
+    spec.vault.certificate = Some(VaultCertificateSpec {
+        secret_name: "cdbootstrap-spn-certificate".to_string(),
+    });
+    assert!(provider::check(&spec).is_ok());
+}
+
+#[test]
+fn malformed_pod_settings_are_rejected() {
+    let mut spec = valid_spec();
+    spec.agent.pod = Some(AgentPodSpec {
+        tolerations: vec![json!({ "key": "dedicated", "operator": "Exists" })],
+        affinity: json!({ "nodeAffinity": {} }),
+        ..AgentPodSpec::default()
+    });
+    assert!(spec.validate(&()).is_ok());
+
+    spec.agent.pod.as_mut().unwrap().affinity = json!({ "podAntiAffinity": "spread" });
+    assert!(spec.validate(&()).is_err());
+
+    let mut spec = valid_spec();
+    spec.agent.pod = Some(AgentPodSpec {
+        topology_spread_constraints: vec![json!({ "maxSkew": "one" })],
+        ..AgentPodSpec::default()
+    });
+    assert!(spec.validate(&()).is_err());
+}
+
+#[test]
+fn malformed_resource_quantities_are_rejected() {
+    let resources = |memory: &str| {
+        Some(ResourceRequirements {
+            requests: BTreeMap::from([("cpu".to_string(), "500m".to_string())]),
+            limits: BTreeMap::from([("memory".to_string(), memory.to_string())]),
+        })
+    };
+    let mut spec = valid_spec();
+    spec.agent.pod = Some(AgentPodSpec {
+        resources: resources("1Gi"),
+        ..AgentPodSpec::default()
+    });
+    assert!(spec.validate(&()).is_ok());
+
+    for malformed in ["2GB", "one"] {
+        spec.agent.pod.as_mut().unwrap().resources = resources(malformed);
+        assert!(spec.validate(&()).is_err());
+    }
+}
+
+#[test]
+fn autoscaling_bounds_and_provider_are_checked() {
+    let mut spec = valid_spec();
+    spec.agent.autoscaling = Some(AutoscalingSpec {
+        min_replicas: 1,
+        max_replicas: 5,
+        cooldown_seconds: None,
+    });
+    assert!(provider::check(&spec).is_ok());
+
+    spec.agent.autoscaling.as_mut().unwrap().min_replicas = 6;
+    assert!(provider::check(&spec).is_err());
+
+    spec.agent.autoscaling.as_mut().unwrap().min_replicas = 1;
+    spec.provider = Provider::GitHubActions;
+    spec.github = Some(Default::default());
+    assert!(provider::check(&spec).is_err());
+}
+
+#[test]
+fn malformed_cidrs_are_rejected() {
+    let mut spec = valid_spec();
+    spec.network_policy = Some(NetworkPolicySpec {
+        cidrs: vec!["13.107.6.0/24".to_string(), "2603:1000::/25".to_string()],
+        ..NetworkPolicySpec::default()
+    });
+    assert!(spec.validate(&()).is_ok());
+
+    spec.network_policy.as_mut().unwrap().cidrs = vec!["13.107.6.0/33".to_string()];
+    assert!(spec.validate(&()).is_err());
+
+    spec.network_policy.as_mut().unwrap().cidrs = vec!["dev.azure.com".to_string()];
+    assert!(spec.validate(&()).is_err());
+}
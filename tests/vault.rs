@@ -1,7 +1,9 @@
 use azure_core::new_http_client;
 use azure_identity::{ClientSecretCredential, TokenCredentialOptions};
 use azure_security_keyvault::SecretClient;
+use cdbootstrap::crd::TokenStatus;
 use cdbootstrap::vault::*;
+use k8s_openapi::chrono::{Duration, TimeZone, Utc};
 use std::sync::Arc;
 use std::{env, process};
 
@@ -63,3 +65,58 @@ async fn print_secret_works() {
     let azure = AzureVault::new(&oid, &tenant, &keyvault_url, &spn);
     print_secret_from_vault(&azure, &namespace).await;
 }
+
+#[test]
+fn secret_version_is_the_last_segment_of_the_id() {
+    assert_eq!(
+        secret_version(
+            "https://example.vault.azure.net/secrets/azp-token/4387e9f3d6e14c459867679a90fd0f79"
+        ),
+        "4387e9f3d6e14c459867679a90fd0f79"
+    );
+    assert_eq!(
+        secret_version("https://example.vault.azure.net/secrets/azp-token/4387e9f3/"),
+        "4387e9f3"
+    );
+}
+
+#[test]
+fn plan_rotates_when_the_vault_version_changed() {
+    assert_eq!(plan(Some("v1"), "v2", true, false), TokenPlan::Rotate);
+    assert_eq!(plan(Some("v1"), "v2", false, false), TokenPlan::Rotate);
+    assert_eq!(plan(Some("v2"), "v2", true, false), TokenPlan::Keep);
+}
+
+#[test]
+fn plan_collects_a_missing_or_expiring_token() {
+    assert_eq!(plan(None, "v1", false, false), TokenPlan::Collect);
+    assert_eq!(plan(Some("v1"), "v1", true, true), TokenPlan::Collect);
+    // A token collected before versions were recorded is adopted
+    assert_eq!(plan(None, "v1", true, false), TokenPlan::Keep);
+}
+
+#[test]
+fn version_is_read_again_after_the_check_interval() {
+    let now = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+    let checked = |check_time: Option<&str>| TokenStatus {
+        vault_version: "v1".to_string(),
+        rotation_time: None,
+        check_time: check_time.map(String::from),
+    };
+
+    assert!(version_check_due(None, now));
+    assert!(version_check_due(Some(&checked(None)), now));
+    assert!(version_check_due(Some(&checked(Some("garbage"))), now));
+    assert!(!version_check_due(
+        Some(&checked(Some("2024-03-01T11:58:00Z"))),
+        now
+    ));
+    assert!(version_check_due(
+        Some(&checked(Some("2024-03-01T11:55:00Z"))),
+        now
+    ));
+    assert!(version_check_due(
+        Some(&checked(Some("2024-03-01T11:58:00Z"))),
+        now + Duration::minutes(3)
+    ));
+}